@@ -1,4 +1,5 @@
 use crate::memory::ApuMemory;
+use crate::state::{StateError, StateReader, StateWriter};
 
 const RATE_VALUE_LOOKUP_TABLE: [u16; 16] = [
     428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
@@ -28,6 +29,8 @@ pub(crate) struct Dmc {
     pub interrupt_flag: bool,
 
     pub restart_flag: bool,
+    /// A sample byte was requested and the CPU is being stalled to fetch it
+    fetching: bool,
 }
 
 impl Dmc {
@@ -55,6 +58,7 @@ impl Dmc {
             interrupt_flag: false,
 
             restart_flag: false,
+            fetching: false,
         }
     }
 
@@ -68,6 +72,48 @@ impl Dmc {
         }
     }
 
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.irq_enabled_flag);
+        writer.write_bool(self.loop_flag);
+        writer.write_u16(self.rate_value);
+        writer.write_u16(self.sample_address);
+        writer.write_u16(self.sample_length);
+        writer.write_u16(self.current_address);
+        writer.write_u16(self.sample_bytes_remaining);
+        writer.write_u8(self.sample_buffer);
+        writer.write_bool(self.sample_buffer_empty);
+        writer.write_u8(self.output_bits_remaining);
+        writer.write_u8(self.shift_register);
+        writer.write_bool(self.silence_flag);
+        writer.write_u16(self.cycle);
+        writer.write_u8(self.out_value);
+        writer.write_bool(self.interrupt_flag);
+        writer.write_bool(self.restart_flag);
+        writer.write_bool(self.fetching);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.irq_enabled_flag = reader.read_bool()?;
+        self.loop_flag = reader.read_bool()?;
+        self.rate_value = reader.read_u16()?;
+        self.sample_address = reader.read_u16()?;
+        self.sample_length = reader.read_u16()?;
+        self.current_address = reader.read_u16()?;
+        self.sample_bytes_remaining = reader.read_u16()?;
+        self.sample_buffer = reader.read_u8()?;
+        self.sample_buffer_empty = reader.read_bool()?;
+        self.output_bits_remaining = reader.read_u8()?;
+        self.shift_register = reader.read_u8()?;
+        self.silence_flag = reader.read_bool()?;
+        self.cycle = reader.read_u16()?;
+        self.out_value = reader.read_u8()?;
+        self.interrupt_flag = reader.read_bool()?;
+        self.restart_flag = reader.read_bool()?;
+        // version 1 read samples directly, without a pending fetch
+        self.fetching = reader.version() >= 2 && reader.read_bool()?;
+        Ok(())
+    }
+
     fn tick_memory_unit(&mut self, memory: &mut ApuMemory) {
         if self.restart_flag {
             if self.output_bits_remaining == 8 {
@@ -79,12 +125,30 @@ impl Dmc {
             }
         }
 
-        if self.sample_buffer_empty && self.sample_bytes_remaining != 0 {
-            self.sample_buffer = memory.read_u8(self.current_address);
+        if let Some(value) = memory.take_dmc_sample() {
+            self.fetching = false;
+            self.sample_buffer = value;
             let (new_addr, overflow) = self.current_address.overflowing_add(1);
             self.current_address = if overflow { 0x8000 } else { new_addr };
-            self.sample_bytes_remaining -= 1;
             self.sample_buffer_empty = false;
+
+            // the channel could have been disabled while the byte was being fetched
+            if self.sample_bytes_remaining > 0 {
+                self.sample_bytes_remaining -= 1;
+                if self.sample_bytes_remaining == 0 {
+                    if self.loop_flag {
+                        self.current_address = self.sample_address;
+                        self.sample_bytes_remaining = self.sample_length;
+                    } else if self.irq_enabled_flag {
+                        self.interrupt_flag = true;
+                    }
+                }
+            }
+        }
+
+        if self.sample_buffer_empty && self.sample_bytes_remaining != 0 && !self.fetching {
+            memory.request_dmc_fetch(self.current_address);
+            self.fetching = true;
         }
     }
 
@@ -131,6 +195,9 @@ impl Dmc {
 
     pub(crate) fn write0(&mut self, value: u8) {
         self.irq_enabled_flag = (value & 0b10000000) != 0;
+        if !self.irq_enabled_flag {
+            self.interrupt_flag = false;
+        }
         self.loop_flag = (value & 0b01000000) != 0;
         let rate_index = value & 0b0000_1111;
         self.rate_value = RATE_VALUE_LOOKUP_TABLE[rate_index as usize];
@@ -1,3 +1,5 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
 pub(crate) struct Envelope {
     pub start_flag: bool,
     pub loop_flag: bool,
@@ -40,6 +42,23 @@ impl Envelope {
         }
     }
 
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.start_flag);
+        writer.write_bool(self.loop_flag);
+        writer.write_u8(self.start_parameter);
+        writer.write_u8(self.divider);
+        writer.write_u8(self.decay_level);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.start_flag = reader.read_bool()?;
+        self.loop_flag = reader.read_bool()?;
+        self.start_parameter = reader.read_u8()?;
+        self.divider = reader.read_u8()?;
+        self.decay_level = reader.read_u8()?;
+        Ok(())
+    }
+
     pub(crate) fn get_volume(&self) -> u8 {
         self.decay_level
     }
@@ -14,6 +14,7 @@ use std::{borrow::BorrowMut, collections::VecDeque};
 use sdl2::audio::{AudioCallback, AudioDeviceLockGuard, AudioSpec};
 
 use crate::memory::{ApuMemory, BusAction};
+use crate::state::{StateError, StateReader, StateWriter};
 
 const APU_FREQ: u32 = 1789773;
 
@@ -60,8 +61,12 @@ pub struct Apu {
     out_cycle: f64,
 
     frame_counter_mode: u8,
-    frame_counter_cycle: u8,
+    /// CPU cycles since the start of the frame counter sequence
+    frame_counter_cycle: u32,
+    /// CPU cycles left before a $4017 write resets the sequence, 0 if none is pending
+    frame_counter_reset_delay: u8,
     irq_inhibited: bool,
+    frame_interrupt: bool,
 
     pulse1_silenced: bool,
     pulse2_silenced: bool,
@@ -93,7 +98,9 @@ impl Apu {
 
             frame_counter_mode: 0,
             frame_counter_cycle: 0,
+            frame_counter_reset_delay: 0,
             irq_inhibited: false,
+            frame_interrupt: false,
 
             pulse1_silenced: false,
             pulse2_silenced: false,
@@ -104,26 +111,28 @@ impl Apu {
 
     pub fn tick(&mut self, bus_action: BusAction) {
         let frame_counter = self.frame_counter_action();
-        if self.cycle % 2 == 0 {
-            self.pulse1.tick(
-                frame_counter.length_counter_and_sweep,
-                frame_counter.envelope_and_triangle_linear_counter,
-                true,
-            );
-            self.pulse2.tick(
-                frame_counter.length_counter_and_sweep,
-                frame_counter.envelope_and_triangle_linear_counter,
-                false,
-            );
-            self.noise.tick(
-                frame_counter.envelope_and_triangle_linear_counter,
-                frame_counter.length_counter_and_sweep,
-            );
-        }
+        let timer_tick = self.cycle & 1 == 0;
+        self.pulse1.tick(
+            timer_tick,
+            frame_counter.length_counter_and_sweep,
+            frame_counter.envelope_and_triangle_linear_counter,
+            true,
+        );
+        self.pulse2.tick(
+            timer_tick,
+            frame_counter.length_counter_and_sweep,
+            frame_counter.envelope_and_triangle_linear_counter,
+            false,
+        );
+        self.noise.tick(
+            timer_tick,
+            frame_counter.envelope_and_triangle_linear_counter,
+            frame_counter.length_counter_and_sweep,
+        );
 
         self.triangle.tick(
-            frame_counter.length_counter_and_sweep,
             frame_counter.envelope_and_triangle_linear_counter,
+            frame_counter.length_counter_and_sweep,
         );
         self.dmc.tick(&mut self.memory);
 
@@ -170,7 +179,7 @@ impl Apu {
                         self.triangle_silenced = false;
                     }
 
-                    if value & 0b0000_0100 == 0 {
+                    if value & 0b0000_1000 == 0 {
                         self.noise_silenced = true;
                         self.noise.length_counter = 0;
                     } else {
@@ -188,9 +197,17 @@ impl Apu {
                 0x4017 => {
                     self.frame_counter_mode = (value & 0b1000_0000) >> 7;
                     self.irq_inhibited = value & 0b0100_0000 != 0;
+                    if self.irq_inhibited {
+                        self.frame_interrupt = false;
+                    }
+                    // the sequence restarts 3 or 4 CPU cycles later, depending on
+                    // whether the write lands on an APU cycle or between two
+                    self.frame_counter_reset_delay = if self.cycle & 1 == 0 { 3 } else { 4 };
                 }
                 _ => {}
             }
+        } else if let BusAction::ApuStatusRead = bus_action {
+            self.frame_interrupt = false;
         }
 
         if self.is_output_cycle() {
@@ -221,7 +238,7 @@ impl Apu {
 
             let tnd = (t + n + d) as usize;
 
-            let out = PULSE_TABLE[p] + TND_TABLE[tnd];
+            let out = PULSE_TABLE[p] + TND_TABLE[tnd] + self.memory.expansion_audio();
 
             //let out = PULSE_TABLE[p];
             //let out = TND_TABLE[n as usize];
@@ -231,6 +248,110 @@ impl Apu {
 
         self.cycle = self.cycle.wrapping_add(1);
         //self.out_cycle += 1.;
+
+        let status = self.status();
+        self.memory.set_status(status);
+    }
+
+    /// Value read from $4015
+    fn status(&self) -> u8 {
+        let mut status = 0;
+        if self.pulse1.length_counter > 0 {
+            status |= 0b0000_0001;
+        }
+        if self.pulse2.length_counter > 0 {
+            status |= 0b0000_0010;
+        }
+        if self.triangle.length_counter > 0 {
+            status |= 0b0000_0100;
+        }
+        if self.noise.length_counter > 0 {
+            status |= 0b0000_1000;
+        }
+        if self.dmc.sample_bytes_remaining > 0 {
+            status |= 0b0001_0000;
+        }
+        if self.frame_interrupt {
+            status |= 0b0100_0000;
+        }
+        if self.dmc.interrupt_flag {
+            status |= 0b1000_0000;
+        }
+        status
+    }
+
+    pub(crate) fn frame_irq(&self) -> bool {
+        self.frame_interrupt
+    }
+
+    pub(crate) fn dmc_irq(&self) -> bool {
+        self.dmc.interrupt_flag
+    }
+
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        self.pulse1.save_state(writer);
+        self.pulse2.save_state(writer);
+        self.triangle.save_state(writer);
+        self.noise.save_state(writer);
+        self.dmc.save_state(writer);
+
+        writer.write_u32(self.cycle);
+        writer.write_f64(self.out_cycle);
+
+        writer.write_u8(self.frame_counter_mode);
+        writer.write_u32(self.frame_counter_cycle);
+        writer.write_u8(self.frame_counter_reset_delay);
+        writer.write_bool(self.irq_inhibited);
+        writer.write_bool(self.frame_interrupt);
+
+        writer.write_bool(self.pulse1_silenced);
+        writer.write_bool(self.pulse2_silenced);
+        writer.write_bool(self.triangle_silenced);
+        writer.write_bool(self.noise_silenced);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.pulse1.load_state(reader)?;
+        self.pulse2.load_state(reader)?;
+        self.triangle.load_state(reader)?;
+        self.noise.load_state(reader)?;
+        self.dmc.load_state(reader)?;
+
+        self.cycle = reader.read_u32()?;
+        self.out_cycle = reader.read_f64()?;
+
+        self.frame_counter_mode = reader.read_u8()?;
+        if reader.version() >= 2 {
+            self.frame_counter_cycle = reader.read_u32()?;
+            self.frame_counter_reset_delay = reader.read_u8()?;
+        } else {
+            // version 1 saved the index of the next step, which fired every 7458 cycles
+            let step = reader.read_u8()? as usize;
+            let steps = [7457, 14913, 22371, 29829, 37281];
+            if self.frame_counter_mode > 1 || step >= 4 + self.frame_counter_mode as usize {
+                return Err(StateError::InvalidValue("APU frame counter"));
+            }
+            let until_step = (7458 - self.cycle % 7458) % 7458;
+            self.frame_counter_cycle = steps[step] - until_step;
+            self.frame_counter_reset_delay = 0;
+        }
+        if self.frame_counter_mode > 1
+            || self.frame_counter_cycle >= 37282
+            || self.frame_counter_reset_delay > 4
+        {
+            return Err(StateError::InvalidValue("APU frame counter"));
+        }
+        self.irq_inhibited = reader.read_bool()?;
+        self.frame_interrupt = reader.version() >= 2 && reader.read_bool()?;
+
+        self.pulse1_silenced = reader.read_bool()?;
+        self.pulse2_silenced = reader.read_bool()?;
+        self.triangle_silenced = reader.read_bool()?;
+        self.noise_silenced = reader.read_bool()?;
+
+        // Samples generated before the state was loaded don't belong to the restored timeline
+        self.output.clear();
+        Ok(())
     }
 
     fn is_output_cycle(&mut self) -> bool {
@@ -239,77 +360,66 @@ impl Apu {
     }
 
     fn frame_counter_action(&mut self) -> FrameCounterAction {
-        if self.cycle % 7458 == 0 {
-            if self.frame_counter_mode == 0 {
-                let v = match self.frame_counter_cycle {
-                    0 => FrameCounterAction {
-                        irc: false,
-                        length_counter_and_sweep: false,
-                        envelope_and_triangle_linear_counter: true,
-                    },
-                    1 => FrameCounterAction {
-                        irc: false,
-                        length_counter_and_sweep: true,
-                        envelope_and_triangle_linear_counter: true,
-                    },
-                    2 => FrameCounterAction {
-                        irc: false,
-                        length_counter_and_sweep: false,
-                        envelope_and_triangle_linear_counter: true,
-                    },
-                    3 => FrameCounterAction {
-                        irc: !self.irq_inhibited,
-                        length_counter_and_sweep: true,
-                        envelope_and_triangle_linear_counter: true,
-                    },
-                    _ => unreachable!(),
-                };
-                self.frame_counter_cycle = (self.frame_counter_cycle + 1) % 4;
-                v
-            } else {
-                let v = match self.frame_counter_cycle {
-                    0 => FrameCounterAction {
-                        irc: false,
-                        length_counter_and_sweep: false,
-                        envelope_and_triangle_linear_counter: true,
-                    },
-                    1 => FrameCounterAction {
-                        irc: false,
-                        length_counter_and_sweep: true,
-                        envelope_and_triangle_linear_counter: true,
-                    },
-                    2 => FrameCounterAction {
-                        irc: false,
-                        length_counter_and_sweep: false,
-                        envelope_and_triangle_linear_counter: true,
-                    },
-                    3 => FrameCounterAction {
-                        irc: false,
-                        length_counter_and_sweep: false,
-                        envelope_and_triangle_linear_counter: false,
-                    },
-                    4 => FrameCounterAction {
-                        irc: false,
-                        length_counter_and_sweep: true,
-                        envelope_and_triangle_linear_counter: true,
-                    },
-                    _ => unreachable!(),
-                };
-                self.frame_counter_cycle = (self.frame_counter_cycle + 1) % 5;
-                v
+        let mut action = FrameCounterAction {
+            irc: false,
+            length_counter_and_sweep: false,
+            envelope_and_triangle_linear_counter: false,
+        };
+
+        if self.frame_counter_reset_delay > 0 {
+            self.frame_counter_reset_delay -= 1;
+            if self.frame_counter_reset_delay == 0 {
+                self.frame_counter_cycle = 0;
+                if self.frame_counter_mode == 1 {
+                    // entering 5-step mode clocks all the units immediately
+                    action.length_counter_and_sweep = true;
+                    action.envelope_and_triangle_linear_counter = true;
+                }
             }
-        } else {
-            FrameCounterAction {
-                irc: false,
-                length_counter_and_sweep: false,
-                envelope_and_triangle_linear_counter: false,
+        }
+
+        match (self.frame_counter_mode, self.frame_counter_cycle) {
+            (_, 7457) | (_, 22371) => {
+                action.envelope_and_triangle_linear_counter = true;
+            }
+            (_, 14913) | (1, 37281) => {
+                action.length_counter_and_sweep = true;
+                action.envelope_and_triangle_linear_counter = true;
+            }
+            (0, 29828) | (0, 29830) => {
+                action.irc = true;
             }
+            (0, 29829) => {
+                action.irc = true;
+                action.length_counter_and_sweep = true;
+                action.envelope_and_triangle_linear_counter = true;
+            }
+            _ => {}
+        }
+
+        if action.irc && !self.irq_inhibited {
+            self.frame_interrupt = true;
+        }
+
+        self.frame_counter_cycle += 1;
+        if self.frame_counter_mode == 0 && self.frame_counter_cycle > 29830 {
+            // the last cycle of the 4-step sequence is also the first of the next one
+            self.frame_counter_cycle = 1;
+        } else if self.frame_counter_mode == 1 && self.frame_counter_cycle >= 37282 {
+            self.frame_counter_cycle = 0;
         }
+
+        action
     }
 
     pub fn update_audio_generator(&mut self, mut generator: AudioDeviceLockGuard<AudioGenerator>) {
         generator.values.append(&mut self.output);
     }
+
+    /// Samples generated since the last call, for runs without an audio device
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.output.drain(..).collect()
+    }
 }
 
 pub struct AudioGenerator {
@@ -394,3 +504,132 @@ impl AudioCallback for AudioGenerator {
 //*x = (input[j] + input[k]) / 2.;
 //}
 //}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory;
+
+    fn apu() -> Apu {
+        let (_, apu_memory, _, _) = memory::create_memory();
+        Apu::new(apu_memory)
+    }
+
+    fn run(apu: &mut Apu, cycles: u32) {
+        for _ in 0..cycles {
+            apu.tick(BusAction::None);
+        }
+    }
+
+    #[test]
+    fn four_step_mode_raises_frame_irq() {
+        let mut apu = apu();
+        apu.tick(BusAction::ApuWrite((0x4017, 0x00)));
+        run(&mut apu, 29828);
+        assert!(!apu.frame_irq());
+        run(&mut apu, 4);
+        assert!(apu.frame_irq());
+        assert_eq!(apu.status() & 0x40, 0x40);
+
+        // reading $4015 acknowledges the interrupt
+        apu.tick(BusAction::ApuStatusRead);
+        assert!(!apu.frame_irq());
+        run(&mut apu, 29830 - 5);
+        assert!(!apu.frame_irq());
+        run(&mut apu, 10);
+        assert!(apu.frame_irq());
+    }
+
+    #[test]
+    fn version_1_frame_counter_is_converted() {
+        let mut apu = apu();
+        let mut writer = StateWriter::new();
+        apu.save_state(&mut writer);
+        let state = writer.into_inner();
+
+        // version 1 had no DMC fetch flag and saved the frame counter as the next step,
+        // here the IRQ step 100 cycles away
+        let mut v1 = state[..state.len() - 25].to_vec();
+        let mut writer = StateWriter::new();
+        writer.write_u32(7458 * 3 - 100);
+        writer.write_f64(0.);
+        writer.write_u8(0);
+        writer.write_u8(3);
+        for _ in 0..5 {
+            writer.write_bool(false);
+        }
+        v1.extend_from_slice(&writer.into_inner());
+
+        let mut reader = StateReader::with_version(&v1, 1);
+        apu.load_state(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        run(&mut apu, 99);
+        assert!(!apu.frame_irq());
+        run(&mut apu, 1);
+        assert!(apu.frame_irq());
+    }
+
+    #[test]
+    fn samples_are_drained() {
+        let mut apu = apu();
+        run(&mut apu, 10000);
+        assert!(!apu.take_samples().is_empty());
+        assert!(apu.take_samples().is_empty());
+    }
+
+    #[test]
+    fn inhibit_and_five_step_mode_have_no_irq() {
+        let mut apu = apu();
+        apu.tick(BusAction::ApuWrite((0x4017, 0x00)));
+        run(&mut apu, 30000);
+        assert!(apu.frame_irq());
+
+        // setting the inhibit flag clears the pending interrupt
+        apu.tick(BusAction::ApuWrite((0x4017, 0x40)));
+        assert!(!apu.frame_irq());
+        run(&mut apu, 60000);
+        assert!(!apu.frame_irq());
+
+        apu.tick(BusAction::ApuWrite((0x4017, 0x80)));
+        run(&mut apu, 80000);
+        assert!(!apu.frame_irq());
+    }
+
+    #[test]
+    fn dmc_fetch_stalls_cpu() {
+        let (_, apu_memory, mut cpu_memory, _) = memory::create_memory();
+        let mut apu = Apu::new(apu_memory);
+        // IRQ enabled, one byte sample at $C000
+        apu.tick(BusAction::ApuWrite((0x4010, 0x8F)));
+        apu.tick(BusAction::ApuWrite((0x4012, 0x00)));
+        apu.tick(BusAction::ApuWrite((0x4013, 0x00)));
+        apu.tick(BusAction::ApuWrite((0x4015, 0x10)));
+
+        let mut stalls = vec![];
+        for _ in 0..200 {
+            if cpu_memory.try_dma() {
+                stalls.push(apu.cycle);
+            } else {
+                cpu_memory.read_u8(0x0000);
+            }
+            apu.tick(BusAction::None);
+        }
+        assert_eq!(stalls.len(), 4);
+        assert_eq!(stalls[3] - stalls[0], 3);
+        assert!(apu.dmc_irq());
+        assert_eq!(apu.status() & 0x90, 0x80);
+    }
+
+    #[test]
+    fn status_reports_length_counters() {
+        let mut apu = apu();
+        apu.tick(BusAction::ApuWrite((0x4015, 0x0F)));
+        // length index 1 -> 254
+        apu.tick(BusAction::ApuWrite((0x4003, 0x08)));
+        apu.tick(BusAction::ApuWrite((0x400F, 0x08)));
+        assert_eq!(apu.status() & 0x0F, 0b1001);
+
+        apu.tick(BusAction::ApuWrite((0x4015, 0x01)));
+        assert_eq!(apu.status() & 0x0F, 0b0001);
+    }
+}
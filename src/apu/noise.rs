@@ -1,6 +1,7 @@
 use std::ops::BitXor;
 
 use super::envelope::Envelope;
+use crate::state::{StateError, StateReader, StateWriter};
 
 const PERIOD_LOOKUP_TABLE: [u16; 16] = [
     4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
@@ -35,12 +36,14 @@ impl Noise {
         }
     }
 
-    pub(crate) fn tick(&mut self, envelope_tick: bool, length_counter_tick: bool) {
-        if self.cycle == 0 {
-            self.tick_shift_register();
-            self.cycle = self.period;
-        } else {
-            self.cycle -= 1;
+    pub(crate) fn tick(&mut self, timer_tick: bool, envelope_tick: bool, length_counter_tick: bool) {
+        if timer_tick {
+            if self.cycle == 0 {
+                self.tick_shift_register();
+                self.cycle = self.period;
+            } else {
+                self.cycle -= 1;
+            }
         }
 
         if length_counter_tick {
@@ -56,6 +59,31 @@ impl Noise {
         }
     }
 
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.length_counter_halt);
+        writer.write_bool(self.constant_volume);
+        writer.write_u8(self.volume);
+        writer.write_bool(self.mode_flag);
+        writer.write_u16(self.period);
+        writer.write_u16(self.cycle);
+        writer.write_u16(self.shift_register);
+        self.envelope.save_state(writer);
+        writer.write_u8(self.length_counter);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.length_counter_halt = reader.read_bool()?;
+        self.constant_volume = reader.read_bool()?;
+        self.volume = reader.read_u8()?;
+        self.mode_flag = reader.read_bool()?;
+        self.period = reader.read_u16()?;
+        self.cycle = reader.read_u16()?;
+        self.shift_register = reader.read_u16()?;
+        self.envelope.load_state(reader)?;
+        self.length_counter = reader.read_u8()?;
+        Ok(())
+    }
+
     fn tick_shift_register(&mut self) {
         let bit0 = self.shift_register & 1 != 0;
         let second_bit = if self.mode_flag {
@@ -110,7 +138,8 @@ impl Noise {
     }
 
     pub(crate) fn write3(&mut self, value: u8) {
-        self.length_counter = (value & 0b1111_1000) >> 3;
+        let length_counter_load = (value & 0b1111_1000) >> 3;
+        self.length_counter = super::LENGTH_COUNTER_TABLE[length_counter_load as usize];
         self.envelope.start_flag = true;
     }
 }
@@ -1,4 +1,5 @@
 use super::envelope::Envelope;
+use crate::state::{StateError, StateReader, StateWriter};
 
 const CPU_FREQ: u32 = 1789773;
 
@@ -31,12 +32,15 @@ impl Pulse {
         }
     }
 
-    pub(crate) fn tick(&mut self, sweep_tick: bool, envelope_tick: bool, is_1: bool) {
-        if self.t == 0 {
-            self.t = self.settings.timer;
-            self.cycle = (self.cycle + 1) % 8;
-        } else {
-            self.t -= 1;
+    /// `timer_tick` is set every other CPU cycle, the frame counter clocks can come on any cycle
+    pub(crate) fn tick(&mut self, timer_tick: bool, sweep_tick: bool, envelope_tick: bool, is_1: bool) {
+        if timer_tick {
+            if self.t == 0 {
+                self.t = self.settings.timer;
+                self.cycle = (self.cycle + 1) % 8;
+            } else {
+                self.t -= 1;
+            }
         }
 
         let change_amount = self.settings.timer >> self.settings.sweep_shift;
@@ -76,6 +80,48 @@ impl Pulse {
         }
     }
 
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.settings.duty);
+        writer.write_bool(self.settings.constant_vol);
+        writer.write_u8(self.settings.volume);
+        writer.write_bool(self.settings.length_counter_halt);
+        writer.write_bool(self.settings.sweep_enable);
+        writer.write_u8(self.settings.sweep_period);
+        writer.write_bool(self.settings.sweep_negate);
+        writer.write_u8(self.settings.sweep_shift);
+        writer.write_u16(self.settings.timer);
+        writer.write_u16(self.t);
+        writer.write_u8(self.cycle);
+        writer.write_bool(self.sweeper_reload_flag);
+        writer.write_u8(self.sweeper_divider_counter);
+        writer.write_bool(self.mute);
+        self.envelope.save_state(writer);
+        writer.write_u8(self.length_counter);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.settings.duty = reader.read_u8()?;
+        self.settings.constant_vol = reader.read_bool()?;
+        self.settings.volume = reader.read_u8()?;
+        self.settings.length_counter_halt = reader.read_bool()?;
+        self.settings.sweep_enable = reader.read_bool()?;
+        self.settings.sweep_period = reader.read_u8()?;
+        self.settings.sweep_negate = reader.read_bool()?;
+        self.settings.sweep_shift = reader.read_u8()?;
+        self.settings.timer = reader.read_u16()?;
+        self.t = reader.read_u16()?;
+        self.cycle = reader.read_u8()?;
+        if self.settings.duty > 3 || self.cycle > 7 {
+            return Err(StateError::InvalidValue("pulse duty"));
+        }
+        self.sweeper_reload_flag = reader.read_bool()?;
+        self.sweeper_divider_counter = reader.read_u8()?;
+        self.mute = reader.read_bool()?;
+        self.envelope.load_state(reader)?;
+        self.length_counter = reader.read_u8()?;
+        Ok(())
+    }
+
     pub(crate) fn next_value(&mut self) -> u8 {
         match self.cycle {
             0..=3 => {
@@ -1,3 +1,5 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
 pub(crate) struct Triangle {
     control_flag: bool,
     linear_counter_reload_value: u8,
@@ -55,6 +57,33 @@ impl Triangle {
         }
     }
 
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.control_flag);
+        writer.write_u8(self.linear_counter_reload_value);
+        writer.write_bool(self.linear_counter_reload_flag);
+        writer.write_u16(self.timer);
+        writer.write_u8(self.length_counter_load);
+        writer.write_u16(self.cycle);
+        writer.write_u8(self.out_value);
+        writer.write_bool(self.crescent);
+        writer.write_u8(self.length_counter);
+        writer.write_u8(self.linear_counter);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.control_flag = reader.read_bool()?;
+        self.linear_counter_reload_value = reader.read_u8()?;
+        self.linear_counter_reload_flag = reader.read_bool()?;
+        self.timer = reader.read_u16()?;
+        self.length_counter_load = reader.read_u8()?;
+        self.cycle = reader.read_u16()?;
+        self.out_value = reader.read_u8()?;
+        self.crescent = reader.read_bool()?;
+        self.length_counter = reader.read_u8()?;
+        self.linear_counter = reader.read_u8()?;
+        Ok(())
+    }
+
     fn advance_out_value(&mut self) {
         if self.crescent {
             if self.out_value == 15 {
@@ -0,0 +1,106 @@
+//! Dumps the disassembly of the PRG ROM banks of a ROM.
+//!
+//! Every 16K bank is shown at $8000, except for the last one which is shown at $C000
+//! where it is usually fixed, `--origin` overrides the address.
+use std::process;
+
+use rnes::cpu::disassembler;
+use rnes::roms;
+
+const USAGE: &str = "usage: rnes-disasm <rom> [--bank N] [--origin ADDR]";
+
+const BANK_SIZE: usize = 0x4000;
+
+struct Options {
+    rom: String,
+    bank: Option<usize>,
+    origin: Option<u16>,
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(&options) {
+        eprintln!("{}", e);
+        process::exit(2);
+    }
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let cartridge = roms::read_rom(&options.rom)?;
+    let banks: Vec<&[u8]> = cartridge.prg_rom().chunks(BANK_SIZE).collect();
+    let selected: Vec<usize> = match options.bank {
+        Some(bank) if bank >= banks.len() => {
+            return Err(format!(
+                "bank {} doesn't exist, the ROM has {} banks",
+                bank,
+                banks.len()
+            ))
+        }
+        Some(bank) => vec![bank],
+        None => (0..banks.len()).collect(),
+    };
+
+    for bank in selected {
+        let origin = options.origin.unwrap_or(if bank == banks.len() - 1 {
+            0xC000
+        } else {
+            0x8000
+        });
+        println!("; bank {} at ${:04X}", bank, origin);
+        for instruction in disassembler::disassemble_block(banks[bank], origin) {
+            println!(
+                "{:04X}  {:<8}  {}",
+                instruction.address,
+                instruction.hex_bytes(),
+                instruction
+            );
+        }
+    }
+    Ok(())
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        rom: String::new(),
+        bank: None,
+        origin: None,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| format!("missing value for {}", name))
+        };
+        match arg.as_str() {
+            "--bank" => {
+                let bank = value("--bank")?;
+                options.bank = Some(
+                    bank.parse()
+                        .map_err(|_| format!("invalid bank: {}", bank))?,
+                );
+            }
+            "--origin" => {
+                let origin = value("--origin")?;
+                let hex = origin.trim_start_matches('$').trim_start_matches("0x");
+                options.origin = Some(
+                    u16::from_str_radix(hex, 16)
+                        .map_err(|_| format!("invalid origin: {}", origin))?,
+                );
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option: {}", arg)),
+            _ if options.rom.is_empty() => options.rom = arg,
+            _ => return Err(format!("unexpected argument: {}", arg)),
+        }
+    }
+
+    if options.rom.is_empty() {
+        return Err("missing rom path".to_string());
+    }
+    Ok(options)
+}
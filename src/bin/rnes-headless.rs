@@ -0,0 +1,208 @@
+//! Runs a ROM without a window or an audio device, meant for CI.
+//!
+//! Exit codes: 0 when the run finished (or the `--until` condition was met, or the test ROM passed),
+//! 1 when the `--until` condition was never met, the test ROM didn't pass
+//! or the trace diverged from the `--nestest` log,
+//! 2 on bad arguments or a ROM that can't be loaded.
+//!
+//! With `--gdb PORT` it waits for a GDB connection on localhost instead, and exits
+//! when the debugger detaches.
+use std::process;
+
+use rnes::roms;
+use rnes::{gdb, Nes};
+
+const USAGE: &str = "usage: rnes-headless <rom> [--frames N] [--until ADDR=VALUE] [--test-rom] \
+                     [--nestest LOG] [--gdb PORT] [--load-state FILE] [--save-state FILE]";
+
+const EXIT_OK: i32 = 0;
+const EXIT_CONDITION_FAILED: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+
+struct Options {
+    rom: String,
+    frames: usize,
+    until: Option<(u16, u8)>,
+    test_rom: bool,
+    nestest: Option<String>,
+    gdb_port: Option<u16>,
+    load_state: Option<String>,
+    save_state: Option<String>,
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(EXIT_ERROR);
+        }
+    };
+    process::exit(run(&options));
+}
+
+fn run(options: &Options) -> i32 {
+    let cartridge = match roms::read_rom(&options.rom) {
+        Ok(cartridge) => cartridge,
+        Err(e) => {
+            eprintln!("can't load {}: {}", options.rom, e);
+            return EXIT_ERROR;
+        }
+    };
+    let mut nes = Nes::with_cartridge(cartridge);
+
+    if let Some(path) = &options.load_state {
+        if let Err(e) = nes.load_state_file(path) {
+            eprintln!("can't load state {}: {}", path, e);
+            return EXIT_ERROR;
+        }
+    }
+
+    if let Some(path) = &options.nestest {
+        let golden_log = match std::fs::read_to_string(path) {
+            Ok(log) => log,
+            Err(e) => {
+                eprintln!("can't read {}: {}", path, e);
+                return EXIT_ERROR;
+            }
+        };
+        return match nes.run_nestest(&golden_log) {
+            Ok(lines) => {
+                eprintln!("{} lines match", lines);
+                EXIT_OK
+            }
+            Err(mismatch) => {
+                eprintln!("{}", mismatch);
+                EXIT_CONDITION_FAILED
+            }
+        };
+    }
+
+    if let Some(port) = options.gdb_port {
+        eprintln!("waiting for gdb on 127.0.0.1:{}", port);
+        return match gdb::serve(&mut nes, ("127.0.0.1", port)) {
+            Ok(()) => EXIT_OK,
+            Err(e) => {
+                eprintln!("gdb session failed: {}", e);
+                EXIT_ERROR
+            }
+        };
+    }
+
+    if options.test_rom {
+        // results are reported through $6000, see `Nes::run_test_rom`
+        let result = nes.run_test_rom(options.frames);
+        eprintln!("{}", result);
+        return if result.passed() {
+            EXIT_OK
+        } else {
+            EXIT_CONDITION_FAILED
+        };
+    }
+
+    let mut frame = 0;
+    let mut condition_met = false;
+    while frame < options.frames {
+        nes.run_until_frame();
+        // there is no audio device to play them
+        nes.take_audio_samples();
+        frame += 1;
+        if let Some((address, value)) = options.until {
+            if nes.peek(address) == value {
+                condition_met = true;
+                break;
+            }
+        }
+    }
+
+    if let Some(path) = &options.save_state {
+        if let Err(e) = nes.save_state_file(path) {
+            eprintln!("can't save state {}: {}", path, e);
+            return EXIT_ERROR;
+        }
+    }
+
+    match options.until {
+        Some((address, value)) if !condition_met => {
+            eprintln!(
+                "${:04X} != ${:02X} after {} frames (found ${:02X})",
+                address,
+                value,
+                frame,
+                nes.peek(address)
+            );
+            EXIT_CONDITION_FAILED
+        }
+        Some((address, value)) => {
+            eprintln!("${:04X} == ${:02X} after {} frames", address, value, frame);
+            EXIT_OK
+        }
+        None => {
+            eprintln!("ran {} frames, {} cpu cycles", frame, nes.cycles());
+            EXIT_OK
+        }
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        rom: String::new(),
+        frames: 600,
+        until: None,
+        test_rom: false,
+        nestest: None,
+        gdb_port: None,
+        load_state: None,
+        save_state: None,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| format!("missing value for {}", name))
+        };
+        match arg.as_str() {
+            "--frames" => {
+                let frames = value("--frames")?;
+                options.frames = frames
+                    .parse()
+                    .map_err(|_| format!("invalid frame count: {}", frames))?;
+            }
+            "--until" => options.until = Some(parse_condition(&value("--until")?)?),
+            "--test-rom" => options.test_rom = true,
+            "--nestest" => options.nestest = Some(value("--nestest")?),
+            "--gdb" => {
+                let port = value("--gdb")?;
+                options.gdb_port = Some(
+                    port.parse()
+                        .map_err(|_| format!("invalid port: {}", port))?,
+                );
+            }
+            "--load-state" => options.load_state = Some(value("--load-state")?),
+            "--save-state" => options.save_state = Some(value("--save-state")?),
+            _ if arg.starts_with("--") => return Err(format!("unknown option: {}", arg)),
+            _ if options.rom.is_empty() => options.rom = arg,
+            _ => return Err(format!("unexpected argument: {}", arg)),
+        }
+    }
+
+    if options.rom.is_empty() {
+        return Err("missing rom path".to_string());
+    }
+    Ok(options)
+}
+
+/// Parses `ADDR=VALUE`, both in hex with an optional `$` or `0x` prefix
+fn parse_condition(condition: &str) -> Result<(u16, u8), String> {
+    let invalid = || format!("invalid condition: {}", condition);
+    let mut parts = condition.splitn(2, '=');
+    let address = parts.next().ok_or_else(invalid)?;
+    let value = parts.next().ok_or_else(invalid)?;
+    let address = u16::from_str_radix(strip_hex_prefix(address), 16).map_err(|_| invalid())?;
+    let value = u8::from_str_radix(strip_hex_prefix(value), 16).map_err(|_| invalid())?;
+    Ok((address, value))
+}
+
+fn strip_hex_prefix(s: &str) -> &str {
+    s.trim_start_matches('$').trim_start_matches("0x")
+}
@@ -2,6 +2,7 @@
 pub enum BusAction {
     PpuAction(PpuAction),
     ApuWrite((u16, u8)),
+    ApuStatusRead,
     None
 }
 
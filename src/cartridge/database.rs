@@ -0,0 +1,170 @@
+//! Game database, fixes the header fields bad dumps get wrong. The games come from a database
+//! such as a NesCartDB export added with `load`, `games.txt` only holds a sample entry that
+//! documents the format.
+
+use super::RomHeader;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+const GAMES: &str = include_str!("games.txt");
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GameInfo {
+    /// CRC-32 of the PRG and CHR ROM, without header and trainer
+    pub crc: u32,
+    pub title: String,
+    pub board: String,
+    pub mapper: u16,
+    /// `None` when the mapper switches the mirroring
+    pub vertical_mirroring: Option<bool>,
+    pub battery: bool,
+}
+
+impl GameInfo {
+    /// Parses a `crc;mapper;mirroring;battery;board;title` line of the database
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(6, ';');
+        let crc = u32::from_str_radix(fields.next()?, 16).ok()?;
+        let mapper = fields.next()?.parse().ok()?;
+        let vertical_mirroring = match fields.next()? {
+            "V" => Some(true),
+            "H" => Some(false),
+            "-" => None,
+            _ => return None,
+        };
+        let battery = match fields.next()? {
+            "1" => true,
+            "0" => false,
+            _ => return None,
+        };
+        Some(Self {
+            crc,
+            mapper,
+            vertical_mirroring,
+            battery,
+            board: fields.next()?.to_string(),
+            title: fields.next()?.to_string(),
+        })
+    }
+
+    /// Overrides the fields of `header` the database knows better
+    pub fn fix_header(&self, header: &mut RomHeader) {
+        header.mapper = self.mapper;
+        if let Some(vertical) = self.vertical_mirroring {
+            header.vertical_mirroring = vertical;
+        }
+        if self.battery != header.battery {
+            header.battery = self.battery;
+            if self.battery {
+                header.prg_nvram_size += header.prg_ram_size;
+                header.prg_ram_size = 0;
+            } else {
+                header.prg_ram_size += header.prg_nvram_size;
+                header.prg_nvram_size = 0;
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref DATABASE: HashMap<u32, GameInfo> = entries(GAMES)
+        .map(|(line, game)| (line, game.unwrap_or_else(|| panic!("bad game entry {}", line))))
+        .map(|(_, game)| (game.crc, game))
+        .collect();
+    /// Entries added with `load`, they live for the rest of the process
+    static ref LOADED: RwLock<HashMap<u32, &'static GameInfo>> = RwLock::new(HashMap::new());
+}
+
+/// Parsed entries of a database in the `games.txt` format, comments and blank lines skipped
+fn entries(text: &str) -> impl Iterator<Item = (&str, Option<GameInfo>)> {
+    text.lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| (line, GameInfo::parse(line)))
+}
+
+/// Adds the games of a database in the `games.txt` format, they take precedence over the
+/// sample entries. Returns the number of games added.
+pub fn load(text: &str) -> Result<usize, String> {
+    let games = entries(text)
+        .map(|(line, game)| game.ok_or_else(|| format!("bad game entry {}", line)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let count = games.len();
+    let mut loaded = LOADED.write().unwrap();
+    for game in games {
+        loaded.insert(game.crc, Box::leak(Box::new(game)));
+    }
+    Ok(count)
+}
+
+/// Game whose PRG and CHR ROM have CRC-32 `crc`
+pub fn lookup(crc: u32) -> Option<&'static GameInfo> {
+    let loaded = LOADED.read().unwrap().get(&crc).copied();
+    loaded.or_else(|| DATABASE.get(&crc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cartridge;
+
+    #[test]
+    fn database_parses() {
+        assert!(!DATABASE.is_empty());
+        let game = lookup(0x3337EC46).unwrap();
+        assert_eq!(game.title, "Super Mario Bros.");
+        assert_eq!(game.board, "NES-NROM-256");
+        assert_eq!(game.vertical_mirroring, Some(true));
+        assert_eq!(lookup(0), None);
+    }
+
+    #[test]
+    fn loaded_games() {
+        assert_eq!(
+            load("# comment\n\nC0FFEE00;2;V;0;NES-UNROM;Loaded game\n"),
+            Ok(1)
+        );
+        assert_eq!(lookup(0xC0FFEE00).unwrap().board, "NES-UNROM");
+        assert_eq!(
+            load("C0FFEE01;2;V;0;NES-UNROM;Other\nC0FFEE02;2;X;0;NES-UNROM;Bad"),
+            Err("bad game entry C0FFEE02;2;X;0;NES-UNROM;Bad".to_string())
+        );
+        // nothing is added from a bad database
+        assert_eq!(lookup(0xC0FFEE01), None);
+    }
+
+    #[test]
+    fn fixes_headers() {
+        let mut bytes = [0u8; 16];
+        bytes[..4].copy_from_slice(b"NES\x1A");
+        bytes[4] = 8;
+        bytes[6] = 0x31;
+        let mut header = RomHeader::parse(&bytes).unwrap();
+
+        let game = GameInfo::parse("12345678;1;-;1;NES-SNROM;Some RPG").unwrap();
+        game.fix_header(&mut header);
+        assert_eq!(header.mapper, 1);
+        assert!(header.vertical_mirroring);
+        assert!(header.battery);
+        assert_eq!(header.prg_ram_size, 0);
+        assert_eq!(header.prg_nvram_size, 0x2000);
+
+        let game = GameInfo::parse("12345678;4;H;0;NES-TLROM;Some platformer").unwrap();
+        game.fix_header(&mut header);
+        assert_eq!(header.mapper, 4);
+        assert!(!header.vertical_mirroring);
+        assert!(!header.battery);
+        assert_eq!(header.prg_ram_size, 0x2000);
+    }
+
+    #[test]
+    fn unknown_games() {
+        let mut header = [0u8; 16];
+        header[..4].copy_from_slice(b"NES\x1A");
+        header[4] = 1;
+        header[5] = 1;
+        let cartridge = Cartridge::new(header, vec![0; 0x6000]).unwrap();
+        assert_eq!(cartridge.game(), None);
+        assert_eq!(cartridge.header().unwrap().mapper, 0);
+    }
+}
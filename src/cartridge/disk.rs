@@ -0,0 +1,227 @@
+//! Famicom Disk System images. `.fds` files hold the blocks of each disk side back to
+//! back, the drive also sees the gaps, block start marks and CRCs between them.
+
+use super::RomError;
+
+/// Bytes per side in an `.fds` file
+pub const SIDE_SIZE: usize = 65500;
+/// disksys.rom, mapped at $E000
+pub const BIOS_SIZE: usize = 0x2000;
+
+/// fwNES header, only its side count is used
+const FWNES_MAGIC: &[u8] = b"FDS\x1A";
+const FWNES_HEADER_SIZE: usize = 16;
+/// First bytes of the disk info block starting every side
+const DISK_INFO: &[u8] = b"\x01*NINTENDO-HVC*";
+
+/// Gap before the first block, 28300 bits
+const LEAD_IN: usize = 28300 / 8;
+/// Gap after every block, 976 bits
+const BLOCK_GAP: usize = 976 / 8;
+const BLOCK_START: u8 = 0x80;
+
+pub fn is_disk_image(data: &[u8]) -> bool {
+    data.starts_with(FWNES_MAGIC) || data.starts_with(DISK_INFO)
+}
+
+/// Disk sides of an `.fds` image, with or without the fwNES header
+pub(crate) fn sides(image: &[u8]) -> Result<Vec<&[u8]>, RomError> {
+    let (data, count) = if image.starts_with(FWNES_MAGIC) {
+        let count = *image.get(4).ok_or(RomError::TooShort(image.len()))? as usize;
+        (image.get(FWNES_HEADER_SIZE..).unwrap_or(&[]), count)
+    } else {
+        (image, image.len() / SIDE_SIZE)
+    };
+    if count == 0 {
+        return Err(invalid("no disk side in the image"));
+    }
+    if data.len() < count * SIDE_SIZE {
+        return Err(RomError::Truncated {
+            expected: count * SIDE_SIZE,
+            found: data.len(),
+        });
+    }
+
+    let sides: Vec<&[u8]> = data.chunks_exact(SIDE_SIZE).take(count).collect();
+    if let Some(side) = sides.iter().position(|s| !s.starts_with(DISK_INFO)) {
+        return Err(invalid(&format!("side {} has no disk info block", side)));
+    }
+    Ok(sides)
+}
+
+/// Lays out a side the way the drive reads it, the blocks surrounded by gaps
+pub(crate) fn add_gaps(side: &[u8]) -> Vec<u8> {
+    let mut track = vec![0; LEAD_IN];
+    let mut position = 0;
+    let mut file_size = 0;
+    while let Some(size) = side
+        .get(position)
+        .and_then(|block_type| block_size(*block_type, file_size))
+    {
+        let block = match side.get(position..position + size) {
+            Some(block) => block,
+            None => break,
+        };
+        if block[0] == 3 {
+            file_size = u16::from_le_bytes([block[13], block[14]]) as usize;
+        }
+        let start = track.len();
+        track.push(BLOCK_START);
+        track.extend_from_slice(block);
+        let crc = block_crc(&track[start..]);
+        track.extend_from_slice(&crc.to_le_bytes());
+        track.extend(std::iter::repeat_n(0, BLOCK_GAP));
+        position += size;
+    }
+    if track.len() < SIDE_SIZE {
+        track.resize(SIDE_SIZE, 0);
+    }
+    track
+}
+
+/// Extracts the blocks of a side read by the drive, for writing it back to an `.fds` file
+pub(crate) fn strip_gaps(track: &[u8]) -> Vec<u8> {
+    let mut side = Vec::with_capacity(SIDE_SIZE);
+    let mut position = 0;
+    let mut file_size = 0;
+    loop {
+        while track.get(position) == Some(&0) {
+            position += 1;
+        }
+        if track.get(position) != Some(&BLOCK_START) {
+            break;
+        }
+        position += 1;
+        let block = match track
+            .get(position)
+            .and_then(|block_type| block_size(*block_type, file_size))
+            .and_then(|size| track.get(position..position + size))
+        {
+            Some(block) => block,
+            None => break,
+        };
+        if block[0] == 3 {
+            file_size = u16::from_le_bytes([block[13], block[14]]) as usize;
+        }
+        side.extend_from_slice(block);
+        // the CRC isn't stored in the image
+        position += block.len() + 2;
+    }
+    side.resize(SIDE_SIZE, 0);
+    side
+}
+
+/// Size of a block, type included. File data blocks are as long as the file header
+/// before them says.
+fn block_size(block_type: u8, file_size: usize) -> Option<usize> {
+    match block_type {
+        // disk info
+        1 => Some(56),
+        // file amount
+        2 => Some(2),
+        // file header
+        3 => Some(16),
+        // file data
+        4 => Some(1 + file_size),
+        _ => None,
+    }
+}
+
+/// Adds a byte to the CRC the RAM adapter computes while reading or writing
+pub(crate) fn update_crc(crc: u16, value: u8) -> u16 {
+    let mut crc = crc;
+    for bit in 0..8 {
+        let carry = crc & 1 != 0;
+        crc >>= 1;
+        if carry {
+            crc ^= 0x8408;
+        }
+        if value & (1 << bit) != 0 {
+            crc ^= 0x8000;
+        }
+    }
+    crc
+}
+
+/// CRC following a block, start mark included. Reading the block and its CRC brings the
+/// CRC back to 0.
+fn block_crc(block: &[u8]) -> u16 {
+    let crc = block.iter().fold(0, |crc, b| update_crc(crc, *b));
+    update_crc(update_crc(crc, 0), 0)
+}
+
+fn invalid(message: &str) -> RomError {
+    RomError::InvalidDisk(message.to_string())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A side holding one 4 byte file named `FILE`
+    pub(crate) fn side() -> Vec<u8> {
+        let mut side = DISK_INFO.to_vec();
+        side.resize(56, 0);
+        side.extend_from_slice(&[2, 1]);
+        let mut header = vec![3, 0, 0];
+        header.extend_from_slice(b"FILE\0\0\0\0");
+        header.extend_from_slice(&[0x00, 0x60, 4, 0, 0]);
+        side.extend_from_slice(&header);
+        side.extend_from_slice(&[4, 0xDE, 0xAD, 0xBE, 0xEF]);
+        side.resize(SIDE_SIZE, 0);
+        side
+    }
+
+    #[test]
+    fn images() {
+        let raw = [side(), side()].concat();
+        assert!(is_disk_image(&raw));
+        assert_eq!(sides(&raw).unwrap().len(), 2);
+
+        let mut fwnes = b"FDS\x1A\x01".to_vec();
+        fwnes.resize(FWNES_HEADER_SIZE, 0);
+        fwnes.extend_from_slice(&raw);
+        assert!(is_disk_image(&fwnes));
+        assert_eq!(sides(&fwnes).unwrap(), vec![&side()[..]]);
+
+        fwnes[4] = 3;
+        assert_eq!(
+            sides(&fwnes),
+            Err(RomError::Truncated {
+                expected: 3 * SIDE_SIZE,
+                found: 2 * SIDE_SIZE,
+            })
+        );
+        assert_eq!(
+            sides(&[side(), vec![0; SIDE_SIZE]].concat()),
+            Err(invalid("side 1 has no disk info block"))
+        );
+        assert_eq!(
+            sides(&side()[..100]),
+            Err(invalid("no disk side in the image"))
+        );
+    }
+
+    #[test]
+    fn gaps() {
+        let track = add_gaps(&side());
+        assert!(track[..LEAD_IN].iter().all(|b| *b == 0));
+        assert_eq!(track[LEAD_IN], BLOCK_START);
+        assert_eq!(&track[LEAD_IN + 1..LEAD_IN + 16], DISK_INFO);
+
+        // the file data block is found from the header before it
+        let data = LEAD_IN + 3 * 3 + 56 + 2 + 16 + 3 * BLOCK_GAP;
+        assert_eq!(track[data], BLOCK_START);
+        assert_eq!(&track[data + 1..data + 6], &[4, 0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(
+            block_crc(&track[data..data + 6]).to_le_bytes(),
+            track[data + 6..data + 8]
+        );
+        let crc = track[data..data + 8]
+            .iter()
+            .fold(0, |crc, b| update_crc(crc, *b));
+        assert_eq!(crc, 0);
+
+        assert_eq!(strip_gaps(&track), side());
+    }
+}
@@ -0,0 +1,410 @@
+//! iNES and NES 2.0 file headers
+
+pub const HEADER_SIZE: usize = 16;
+pub const TRAINER_SIZE: usize = 512;
+
+const MAGIC: [u8; 4] = *b"NES\x1A";
+
+/// Errors returned when loading a ROM image
+#[derive(Debug, Clone, PartialEq)]
+pub enum RomError {
+    /// The file is shorter than a header
+    TooShort(usize),
+    /// The data doesn't start with the iNES or UNIF magic bytes
+    BadMagic,
+    /// The header announces more data than the file holds
+    Truncated {
+        expected: usize,
+        found: usize,
+    },
+    /// The ROM sizes in the header add up to more than can be addressed
+    SizeOverflow,
+    /// PRG ROM isn't a non-zero multiple of 16K
+    InvalidPrgSize(usize),
+    /// CHR ROM isn't a multiple of 8K
+    InvalidChrSize(usize),
+    /// The board has no CHR RAM and the file no CHR ROM
+    MissingChrRom,
+    UnsupportedMapper(u16),
+    /// The UNIF board has no matching mapper
+    UnsupportedBoard(String),
+    InvalidUnif(String),
+    InvalidDisk(String),
+    /// The FDS BIOS isn't 8K long
+    InvalidBios(usize),
+    /// The zip archive can't be read or doesn't hold exactly one ROM
+    Archive(String),
+    InvalidPatch(String),
+    /// The patch was made for a different ROM, CRC-32s of the whole files
+    PatchSourceMismatch {
+        expected: u32,
+        found: u32,
+    },
+}
+
+impl std::fmt::Display for RomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RomError::TooShort(len) => write!(f, "{} bytes are too short for a ROM header", len),
+            RomError::BadMagic => write!(f, "not an iNES or UNIF ROM"),
+            RomError::Truncated { expected, found } => write!(
+                f,
+                "truncated ROM, expected {} bytes after the header, found {}",
+                expected, found
+            ),
+            RomError::SizeOverflow => write!(f, "the ROM sizes in the header are too large"),
+            RomError::InvalidPrgSize(size) => {
+                write!(f, "{} bytes of PRG ROM isn't a multiple of 16K", size)
+            }
+            RomError::InvalidChrSize(size) => {
+                write!(f, "{} bytes of CHR ROM isn't a multiple of 8K", size)
+            }
+            RomError::MissingChrRom => write!(f, "the board needs CHR ROM, the ROM has none"),
+            RomError::UnsupportedMapper(mapper) => write!(f, "unsupported mapper {}", mapper),
+            RomError::UnsupportedBoard(board) => write!(f, "unsupported board {}", board),
+            RomError::InvalidUnif(e) => write!(f, "invalid UNIF file, {}", e),
+            RomError::InvalidDisk(e) => write!(f, "invalid FDS image, {}", e),
+            RomError::InvalidBios(len) => {
+                write!(f, "the FDS BIOS must be 8192 bytes long, found {}", len)
+            }
+            RomError::Archive(e) => write!(f, "{}", e),
+            RomError::InvalidPatch(e) => write!(f, "{}", e),
+            RomError::PatchSourceMismatch { expected, found } => write!(
+                f,
+                "the patch is for ROM {:08X}, this ROM is {:08X}",
+                expected, found
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderFormat {
+    INes,
+    /// iNES header with garbage in bytes 7-15, only the low mapper nibble is trusted
+    ArchaicINes,
+    Nes20,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    /// Runs on both
+    MultiRegion,
+    Dendy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem {
+        ppu: u8,
+        hardware: u8,
+    },
+    Playchoice10,
+    /// NES 2.0 extended console type, e.g. Famiclones with extra opcodes
+    Extended(u8),
+}
+
+/// Parsed iNES or NES 2.0 header. Sizes are in bytes, iNES headers get the sizes NES 2.0
+/// would spell out for them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomHeader {
+    pub format: HeaderFormat,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    /// Battery backed PRG RAM
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub vertical_mirroring: bool,
+    pub four_screen: bool,
+    pub battery: bool,
+    /// 512 bytes between the header and the PRG ROM, loaded at $7000
+    pub trainer: bool,
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+    pub misc_roms: u8,
+    pub expansion_device: u8,
+}
+
+impl RomHeader {
+    pub fn parse(data: &[u8]) -> Result<Self, RomError> {
+        if data.len() < HEADER_SIZE {
+            return Err(RomError::TooShort(data.len()));
+        }
+        let h = &data[..HEADER_SIZE];
+        if h[..4] != MAGIC {
+            return Err(RomError::BadMagic);
+        }
+
+        let format = if h[7] & 0x0C == 0x08 {
+            HeaderFormat::Nes20
+        } else if h[7] & 0x0C == 0 && h[12..].iter().all(|b| *b == 0) {
+            HeaderFormat::INes
+        } else {
+            HeaderFormat::ArchaicINes
+        };
+
+        let battery = h[6] & 0x02 != 0;
+        let mut header = Self {
+            format,
+            mapper: (h[6] >> 4) as u16,
+            submapper: 0,
+            prg_rom_size: h[4] as usize * 0x4000,
+            chr_rom_size: h[5] as usize * 0x2000,
+            prg_ram_size: 0,
+            prg_nvram_size: 0,
+            chr_ram_size: 0,
+            chr_nvram_size: 0,
+            vertical_mirroring: h[6] & 0x01 != 0,
+            four_screen: h[6] & 0x08 != 0,
+            battery,
+            trainer: h[6] & 0x04 != 0,
+            timing: Timing::Ntsc,
+            console_type: ConsoleType::Nes,
+            misc_roms: 0,
+            expansion_device: 0,
+        };
+
+        match format {
+            HeaderFormat::Nes20 => {
+                header.mapper |= (h[7] & 0xF0) as u16 | ((h[8] & 0x0F) as u16) << 8;
+                header.submapper = h[8] >> 4;
+                header.prg_rom_size = rom_size(h[4], h[9] & 0x0F, 0x4000);
+                header.chr_rom_size = rom_size(h[5], h[9] >> 4, 0x2000);
+                header.prg_ram_size = ram_size(h[10] & 0x0F);
+                header.prg_nvram_size = ram_size(h[10] >> 4);
+                header.chr_ram_size = ram_size(h[11] & 0x0F);
+                header.chr_nvram_size = ram_size(h[11] >> 4);
+                header.timing = match h[12] & 0x03 {
+                    0 => Timing::Ntsc,
+                    1 => Timing::Pal,
+                    2 => Timing::MultiRegion,
+                    _ => Timing::Dendy,
+                };
+                header.console_type = match h[7] & 0x03 {
+                    0 => ConsoleType::Nes,
+                    1 => ConsoleType::VsSystem {
+                        ppu: h[13] & 0x0F,
+                        hardware: h[13] >> 4,
+                    },
+                    2 => ConsoleType::Playchoice10,
+                    _ => ConsoleType::Extended(h[13] & 0x0F),
+                };
+                header.misc_roms = h[14] & 0x03;
+                header.expansion_device = h[15] & 0x3F;
+            }
+            HeaderFormat::INes | HeaderFormat::ArchaicINes => {
+                if format == HeaderFormat::INes {
+                    header.mapper |= (h[7] & 0xF0) as u16;
+                    if h[9] & 0x01 != 0 {
+                        header.timing = Timing::Pal;
+                    }
+                }
+                // 0 means 8K for compatibility
+                let prg_ram_size = (h[8].max(1) as usize) * 0x2000;
+                if battery {
+                    header.prg_nvram_size = prg_ram_size;
+                } else {
+                    header.prg_ram_size = prg_ram_size;
+                }
+                if header.chr_rom_size == 0 {
+                    header.chr_ram_size = 0x2000;
+                }
+                header.console_type = match h[7] & 0x03 {
+                    0 => ConsoleType::Nes,
+                    1 => ConsoleType::VsSystem {
+                        ppu: 0,
+                        hardware: 0,
+                    },
+                    _ => ConsoleType::Playchoice10,
+                };
+            }
+        }
+
+        // exponent sizes go up to 2^63 * 7
+        TRAINER_SIZE
+            .checked_add(header.prg_rom_size)
+            .and_then(|size| size.checked_add(header.chr_rom_size))
+            .ok_or(RomError::SizeOverflow)?;
+        Ok(header)
+    }
+
+    /// Bytes expected after the header: trainer, PRG ROM and CHR ROM
+    pub fn data_size(&self) -> usize {
+        let trainer = if self.trainer { TRAINER_SIZE } else { 0 };
+        trainer
+            .saturating_add(self.prg_rom_size)
+            .saturating_add(self.chr_rom_size)
+    }
+}
+
+/// NES 2.0 ROM size, either a count of `unit` or `2^E * (MM * 2 + 1)` bytes when the
+/// high nibble is $F
+fn rom_size(lsb: u8, msb: u8, unit: usize) -> usize {
+    if msb == 0x0F {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0x03) as usize * 2 + 1;
+        2usize.saturating_pow(exponent).saturating_mul(multiplier)
+    } else {
+        ((msb as usize) << 8 | lsb as usize) * unit
+    }
+}
+
+/// NES 2.0 RAM size, `64 << shift` bytes or none
+fn ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cartridge;
+
+    fn header(bytes: &[u8]) -> [u8; HEADER_SIZE] {
+        let mut header = [0u8; HEADER_SIZE];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4..4 + bytes.len()].copy_from_slice(bytes);
+        header
+    }
+
+    #[test]
+    fn ines() {
+        // MMC3, 256K PRG, 128K CHR, vertical, battery and trainer
+        let parsed = RomHeader::parse(&header(&[16, 16, 0x47, 0x00])).unwrap();
+        assert_eq!(
+            parsed,
+            RomHeader {
+                format: HeaderFormat::INes,
+                mapper: 4,
+                submapper: 0,
+                prg_rom_size: 0x40000,
+                chr_rom_size: 0x20000,
+                prg_ram_size: 0,
+                prg_nvram_size: 0x2000,
+                chr_ram_size: 0,
+                chr_nvram_size: 0,
+                vertical_mirroring: true,
+                four_screen: false,
+                battery: true,
+                trainer: true,
+                timing: Timing::Ntsc,
+                console_type: ConsoleType::Nes,
+                misc_roms: 0,
+                expansion_device: 0,
+            }
+        );
+        assert_eq!(parsed.data_size(), 512 + 0x40000 + 0x20000);
+
+        // both mapper nibbles, CHR RAM, 32K PRG RAM, PAL
+        let parsed = RomHeader::parse(&header(&[8, 0, 0x10, 0x40, 4, 1])).unwrap();
+        assert_eq!(parsed.mapper, 0x41);
+        assert_eq!(parsed.chr_ram_size, 0x2000);
+        assert_eq!(parsed.prg_ram_size, 0x8000);
+        assert_eq!(parsed.timing, Timing::Pal);
+    }
+
+    #[test]
+    fn archaic_ines() {
+        let mut bytes = header(&[2, 1, 0x10, 0x40]);
+        bytes[7..].copy_from_slice(b"DiskDude!");
+        let parsed = RomHeader::parse(&bytes).unwrap();
+        assert_eq!(parsed.format, HeaderFormat::ArchaicINes);
+        assert_eq!(parsed.mapper, 1);
+    }
+
+    #[test]
+    fn nes20() {
+        let parsed = RomHeader::parse(&header(&[
+            0x02, 0x01, 0x12, 0x29, 0x35, 0x10, 0x70, 0x07, 0x03, 0x21, 0x01, 0x05,
+        ]))
+        .unwrap();
+        assert_eq!(
+            parsed,
+            RomHeader {
+                format: HeaderFormat::Nes20,
+                mapper: 0x521,
+                submapper: 3,
+                prg_rom_size: 0x2 * 0x4000,
+                chr_rom_size: 0x101 * 0x2000,
+                prg_ram_size: 0,
+                prg_nvram_size: 0x2000,
+                chr_ram_size: 0x2000,
+                chr_nvram_size: 0,
+                vertical_mirroring: false,
+                four_screen: false,
+                battery: true,
+                trainer: false,
+                timing: Timing::Dendy,
+                console_type: ConsoleType::VsSystem {
+                    ppu: 1,
+                    hardware: 2,
+                },
+                misc_roms: 1,
+                expansion_device: 5,
+            }
+        );
+    }
+
+    #[test]
+    fn nes20_exponent_sizes() {
+        // PRG 2^4 * 3, CHR 2^10 * 1
+        let parsed = RomHeader::parse(&header(&[0x11, 0x28, 0x00, 0x08, 0x00, 0xFF])).unwrap();
+        assert_eq!(parsed.prg_rom_size, 48);
+        assert_eq!(parsed.chr_rom_size, 1024);
+        assert_eq!(parsed.chr_ram_size, 0);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(RomHeader::parse(b"NES\x1A\x01"), Err(RomError::TooShort(5)));
+        let mut bytes = header(&[1, 1]);
+        bytes[3] = 0;
+        assert_eq!(RomHeader::parse(&bytes), Err(RomError::BadMagic));
+
+        assert_eq!(
+            RomHeader::parse(&header(&[0xFF, 0xFF, 0x00, 0x08, 0x00, 0xFF])),
+            Err(RomError::SizeOverflow)
+        );
+        assert_eq!(
+            Cartridge::new(header(&[0, 1]), vec![0; 0x2000]).err(),
+            Some(RomError::InvalidPrgSize(0))
+        );
+        assert_eq!(
+            Cartridge::new(header(&[0x11, 0, 0, 0x08, 0, 0x0F]), vec![0; 48]).err(),
+            Some(RomError::InvalidPrgSize(48))
+        );
+        // 12K of CHR
+        assert_eq!(
+            Cartridge::new(header(&[1, 0x31, 0x30, 0x08, 0, 0xF0]), vec![0; 0x7000]).err(),
+            Some(RomError::InvalidChrSize(0x3000))
+        );
+        // CNROM has no CHR RAM
+        assert_eq!(
+            Cartridge::new(header(&[1, 0, 0x30]), vec![0; 0x4000]).err(),
+            Some(RomError::MissingChrRom)
+        );
+        assert_eq!(
+            Cartridge::new(header(&[2, 1]), vec![0; 100]).err(),
+            Some(RomError::Truncated {
+                expected: 0xA000,
+                found: 100,
+            })
+        );
+        assert_eq!(
+            Cartridge::new(header(&[1, 1, 0xF0, 0xF0]), vec![0; 0x6000]).err(),
+            Some(RomError::UnsupportedMapper(0xFF))
+        );
+    }
+}
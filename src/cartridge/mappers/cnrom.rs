@@ -0,0 +1,194 @@
+use super::{
+    super::{BANK_1_OFFSET, BANK_2_OFFSET},
+    prg_ram_offset, Mapper, Mirroring,
+};
+use crate::cpu::addresses::SAVE_RAM;
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Mapper 3: fixed 16K or 32K of PRG ROM and switchable 8K CHR ROM banks
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) struct CNROM {
+    prg_banks: Vec<[u8; 0x4000]>,
+    chr_banks: Vec<[u8; 0x2000]>,
+    selected_chr: usize,
+    // the boards have none, only there for the header's PRG RAM or the trainer
+    prg_ram: Vec<u8>,
+    mirroring: Mirroring,
+}
+
+impl Mapper for CNROM {
+    fn read(&self, address: u16) -> u8 {
+        if address >= BANK_2_OFFSET {
+            self.prg_banks[self.prg_banks.len() - 1][(address - BANK_2_OFFSET) as usize]
+        } else if address >= BANK_1_OFFSET {
+            self.prg_banks[0][(address - BANK_1_OFFSET) as usize]
+        } else if address >= SAVE_RAM {
+            prg_ram_offset(address, self.prg_ram.len()).map_or(0, |i| self.prg_ram[i])
+        } else {
+            0
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if address >= BANK_1_OFFSET {
+            // bus conflict: the ROM drives the bus at the same time as the CPU
+            let value = value & self.read(address);
+            self.selected_chr = value as usize % self.chr_banks.len();
+        } else if address >= SAVE_RAM {
+            if let Some(i) = prg_ram_offset(address, self.prg_ram.len()) {
+                self.prg_ram[i] = value;
+            }
+        }
+    }
+
+    fn tick(&mut self) {}
+
+    fn ppu_read(&self, address: u16, internal_vram: &[u8; 0x800]) -> u8 {
+        if address < 0x2000 {
+            self.chr_banks[self.selected_chr][address as usize]
+        } else if address < 0x3F00 {
+            internal_vram[self.mirroring.map_nametable_address(address)]
+        } else {
+            unreachable!();
+        }
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8, internal_vram: &mut [u8; 0x800]) {
+        if address < 0x2000 {
+            // CHR ROM, writes are ignored
+        } else if address < 0x3F00 {
+            internal_vram[self.mirroring.map_nametable_address(address)] = value;
+        } else {
+            unreachable!();
+        }
+    }
+
+    fn cpu_byte_mut(&mut self, address: u16) -> Option<&mut u8> {
+        if address >= BANK_2_OFFSET {
+            let last = self.prg_banks.len() - 1;
+            Some(&mut self.prg_banks[last][(address - BANK_2_OFFSET) as usize])
+        } else if address >= BANK_1_OFFSET {
+            Some(&mut self.prg_banks[0][(address - BANK_1_OFFSET) as usize])
+        } else if address >= SAVE_RAM {
+            let i = prg_ram_offset(address, self.prg_ram.len())?;
+            Some(&mut self.prg_ram[i])
+        } else {
+            None
+        }
+    }
+
+    fn prg_banks(&self) -> Vec<&[u8]> {
+        self.prg_banks.iter().map(|b| &b[..]).collect()
+    }
+
+    fn prg_banks_mut(&mut self) -> Vec<&mut [u8]> {
+        self.prg_banks.iter_mut().map(|b| &mut b[..]).collect()
+    }
+
+    fn chr_banks(&self) -> Vec<&[u8]> {
+        self.chr_banks.iter().map(|b| &b[..]).collect()
+    }
+
+    fn chr_banks_mut(&mut self) -> Vec<&mut [u8]> {
+        self.chr_banks.iter_mut().map(|b| &mut b[..]).collect()
+    }
+
+    fn get_save_ram(&self) -> Vec<u8> {
+        self.prg_ram.to_vec()
+    }
+
+    fn set_save_ram(&mut self, data: Vec<u8>) {
+        for (i, x) in data.into_iter().take(self.prg_ram.len()).enumerate() {
+            self.prg_ram[i] = x;
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_usize(self.selected_chr);
+        writer.write_bytes(&self.prg_ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        let selected_chr = reader.read_usize()?;
+        if selected_chr >= self.chr_banks.len() {
+            return Err(StateError::InvalidValue("CNROM CHR bank"));
+        }
+        self.selected_chr = selected_chr;
+        // version 2 had no PRG RAM, keep the current one
+        if reader.version() >= 3 {
+            reader.read_bytes_into(&mut self.prg_ram)?;
+        }
+        Ok(())
+    }
+}
+
+impl CNROM {
+    pub(crate) fn new(
+        prg_banks: Vec<[u8; 0x4000]>,
+        chr_banks: Vec<[u8; 0x2000]>,
+        prg_ram_size: usize,
+        mirroring: Mirroring,
+    ) -> Self {
+        Self {
+            prg_banks,
+            chr_banks,
+            selected_chr: 0,
+            prg_ram: vec![0; prg_ram_size],
+            mirroring,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapper(prg_banks: usize) -> CNROM {
+        let prg = (0..prg_banks).map(|i| [0xFF - i as u8; 0x4000]).collect();
+        let chr = (0..4).map(|i| [i as u8; 0x2000]).collect();
+        CNROM::new(prg, chr, 0, Mirroring::Horizontal)
+    }
+
+    #[test]
+    fn chr_bank_switching() {
+        let mut mapper = mapper(2);
+        let vram = [0u8; 0x800];
+        assert_eq!(mapper.ppu_read(0x0000, &vram), 0);
+
+        mapper.write(0x8000, 2);
+        assert_eq!(mapper.ppu_read(0x0000, &vram), 2);
+        assert_eq!(mapper.ppu_read(0x1FFF, &vram), 2);
+
+        // bank numbers wrap around the number of banks
+        mapper.write(0xBFFF, 7);
+        assert_eq!(mapper.ppu_read(0x1000, &vram), 3);
+
+        // bus conflict with the $FE bytes of the second bank
+        mapper.write(0xC000, 3);
+        assert_eq!(mapper.ppu_read(0x1000, &vram), 2);
+    }
+
+    #[test]
+    fn fixed_prg() {
+        let mapper = mapper(1);
+        assert_eq!(mapper.read(0x8000), 0xFF);
+        assert_eq!(mapper.read(0xC000), 0xFF);
+
+        let mut mapper = self::mapper(2);
+        mapper.write(0x8000, 1);
+        assert_eq!(mapper.read(0x8000), 0xFF);
+        assert_eq!(mapper.read(0xC000), 0xFE);
+    }
+
+    #[test]
+    fn chr_rom_is_read_only() {
+        let mut mapper = mapper(1);
+        let mut vram = [0u8; 0x800];
+        mapper.ppu_write(0x0010, 0x42, &mut vram);
+        assert_eq!(mapper.ppu_read(0x0010, &vram), 0);
+
+        mapper.ppu_write(0x2800, 0x24, &mut vram);
+        assert_eq!(mapper.ppu_read(0x2C00, &vram), 0x24);
+        assert_eq!(mapper.ppu_read(0x2000, &vram), 0);
+    }
+}
@@ -0,0 +1,638 @@
+use super::{fds_audio::FdsAudio, Mapper, Mirroring};
+use crate::cartridge::disk;
+use crate::state::{StateError, StateReader, StateWriter};
+
+const RAM_START: u16 = 0x6000;
+const BIOS_START: u16 = 0xE000;
+const RAM_SIZE: usize = 0x8000;
+const CHR_RAM_SIZE: usize = 0x2000;
+/// CPU cycles between two bytes under the head
+const BYTE_CYCLES: u32 = 150;
+/// CPU cycles the head takes to go back to the start of the disk
+const REWIND_CYCLES: u32 = 50000;
+
+/// Famicom Disk System RAM adapter: 32K of PRG RAM at $6000, the BIOS at $E000, 8K of
+/// CHR RAM, the disk drive controller and timer IRQ at $4020-$4033 and the wavetable
+/// channel at $4040-$4092.
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) struct FDS {
+    bios: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+    mirroring: Mirroring,
+    /// Disk sides laid out with their gaps, as the head reads them
+    sides: Vec<Vec<u8>>,
+    side: Option<usize>,
+
+    disk_registers_enabled: bool,
+    sound_registers_enabled: bool,
+
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_repeat: bool,
+    timer_enabled: bool,
+    timer_irq: bool,
+
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    crc_control: bool,
+    previous_crc_control: bool,
+    /// Set by the BIOS once the head is in the gap before the block to transfer
+    transfer_started: bool,
+    transfer_irq_enabled: bool,
+    transfer_irq: bool,
+    /// A byte was read or is needed for the write
+    transfer_complete: bool,
+    read_data: u8,
+    write_data: u8,
+    /// $4026 output, read back through $4033
+    external: u8,
+
+    position: usize,
+    delay: u32,
+    /// The head has to go back to the start of the disk
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    crc: u16,
+
+    audio: FdsAudio,
+}
+
+impl Mapper for FDS {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x4030..=0x4033 if self.disk_registers_enabled => self.read_register(address),
+            0x4040..=0x4092 if self.sound_registers_enabled => {
+                self.audio.read(address).unwrap_or(0)
+            }
+            RAM_START..=0xDFFF => self.prg_ram[(address - RAM_START) as usize],
+            BIOS_START..=0xFFFF => self.bios[(address - BIOS_START) as usize],
+            _ => 0,
+        }
+    }
+
+    fn bus_read(&mut self, address: u16) -> u8 {
+        let value = self.read(address);
+        if self.disk_registers_enabled {
+            match address {
+                // acknowledges both interrupts
+                0x4030 => {
+                    self.transfer_complete = false;
+                    self.transfer_irq = false;
+                    self.timer_irq = false;
+                }
+                0x4031 => {
+                    self.transfer_complete = false;
+                    self.transfer_irq = false;
+                }
+                _ => {}
+            }
+        }
+        value
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x4020..=0x4026 => self.write_register(address, value),
+            0x4040..=0x408A if self.sound_registers_enabled => self.audio.write(address, value),
+            RAM_START..=0xDFFF => self.prg_ram[(address - RAM_START) as usize] = value,
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self) {
+        self.tick_timer();
+        self.tick_drive();
+        self.audio.tick();
+    }
+
+    fn ppu_read(&self, address: u16, internal_vram: &[u8; 0x800]) -> u8 {
+        if address < 0x2000 {
+            self.chr_ram[address as usize]
+        } else if address < 0x3F00 {
+            internal_vram[self.mirroring.map_nametable_address(address)]
+        } else {
+            unreachable!();
+        }
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8, internal_vram: &mut [u8; 0x800]) {
+        if address < 0x2000 {
+            self.chr_ram[address as usize] = value;
+        } else if address < 0x3F00 {
+            internal_vram[self.mirroring.map_nametable_address(address)] = value;
+        } else {
+            unreachable!();
+        }
+    }
+
+    fn cpu_byte_mut(&mut self, address: u16) -> Option<&mut u8> {
+        match address {
+            RAM_START..=0xDFFF => Some(&mut self.prg_ram[(address - RAM_START) as usize]),
+            BIOS_START..=0xFFFF => Some(&mut self.bios[(address - BIOS_START) as usize]),
+            _ => None,
+        }
+    }
+
+    fn prg_banks(&self) -> Vec<&[u8]> {
+        vec![&self.bios]
+    }
+
+    fn prg_banks_mut(&mut self) -> Vec<&mut [u8]> {
+        vec![&mut self.bios]
+    }
+
+    fn chr_banks(&self) -> Vec<&[u8]> {
+        vec![&self.chr_ram]
+    }
+
+    fn chr_banks_mut(&mut self) -> Vec<&mut [u8]> {
+        vec![&mut self.chr_ram]
+    }
+
+    fn get_save_ram(&self) -> Vec<u8> {
+        self.prg_ram.clone()
+    }
+
+    fn set_save_ram(&mut self, data: Vec<u8>) {
+        if data.len() == RAM_SIZE {
+            self.prg_ram = data;
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.prg_ram);
+        writer.write_bytes(&self.chr_ram);
+        writer.write_u8(self.mirroring.to_u8());
+        writer.write_usize(self.sides.len());
+        for side in &self.sides {
+            writer.write_bytes(side);
+        }
+        writer.write_bool(self.side.is_some());
+        writer.write_usize(self.side.unwrap_or(0));
+
+        writer.write_bool(self.disk_registers_enabled);
+        writer.write_bool(self.sound_registers_enabled);
+
+        writer.write_u16(self.timer_reload);
+        writer.write_u16(self.timer_counter);
+        writer.write_bool(self.timer_repeat);
+        writer.write_bool(self.timer_enabled);
+        writer.write_bool(self.timer_irq);
+
+        writer.write_bool(self.motor_on);
+        writer.write_bool(self.reset_transfer);
+        writer.write_bool(self.read_mode);
+        writer.write_bool(self.crc_control);
+        writer.write_bool(self.previous_crc_control);
+        writer.write_bool(self.transfer_started);
+        writer.write_bool(self.transfer_irq_enabled);
+        writer.write_bool(self.transfer_irq);
+        writer.write_bool(self.transfer_complete);
+        writer.write_u8(self.read_data);
+        writer.write_u8(self.write_data);
+        writer.write_u8(self.external);
+
+        writer.write_usize(self.position);
+        writer.write_u32(self.delay);
+        writer.write_bool(self.end_of_head);
+        writer.write_bool(self.scanning);
+        writer.write_bool(self.gap_ended);
+        writer.write_u16(self.crc);
+
+        self.audio.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.read_bytes_into(&mut self.prg_ram)?;
+        reader.read_bytes_into(&mut self.chr_ram)?;
+        self.mirroring = Mirroring::from_u8(reader.read_u8()?)?;
+        if reader.read_usize()? != self.sides.len() {
+            return Err(StateError::InvalidValue("FDS disk sides"));
+        }
+        for side in self.sides.iter_mut() {
+            *side = reader.read_bytes()?.to_vec();
+        }
+        let inserted = reader.read_bool()?;
+        let side = reader.read_usize()?;
+        self.side = if inserted { Some(side) } else { None };
+
+        self.disk_registers_enabled = reader.read_bool()?;
+        self.sound_registers_enabled = reader.read_bool()?;
+
+        self.timer_reload = reader.read_u16()?;
+        self.timer_counter = reader.read_u16()?;
+        self.timer_repeat = reader.read_bool()?;
+        self.timer_enabled = reader.read_bool()?;
+        self.timer_irq = reader.read_bool()?;
+
+        self.motor_on = reader.read_bool()?;
+        self.reset_transfer = reader.read_bool()?;
+        self.read_mode = reader.read_bool()?;
+        self.crc_control = reader.read_bool()?;
+        self.previous_crc_control = reader.read_bool()?;
+        self.transfer_started = reader.read_bool()?;
+        self.transfer_irq_enabled = reader.read_bool()?;
+        self.transfer_irq = reader.read_bool()?;
+        self.transfer_complete = reader.read_bool()?;
+        self.read_data = reader.read_u8()?;
+        self.write_data = reader.read_u8()?;
+        self.external = reader.read_u8()?;
+
+        self.position = reader.read_usize()?;
+        self.delay = reader.read_u32()?;
+        self.end_of_head = reader.read_bool()?;
+        self.scanning = reader.read_bool()?;
+        self.gap_ended = reader.read_bool()?;
+        self.crc = reader.read_u16()?;
+        if let Some(side) = self.side {
+            let len = self.sides.get(side).map_or(0, |s| s.len());
+            if side >= self.sides.len()
+                || self.position > len
+                || self.motor_on && self.position == len
+            {
+                return Err(StateError::InvalidValue("FDS disk position"));
+            }
+        }
+
+        self.audio.load_state(reader)
+    }
+
+    fn irq(&self) -> bool {
+        self.timer_irq || self.transfer_irq
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn disk_system(&self) -> Option<&FDS> {
+        Some(self)
+    }
+
+    fn disk_system_mut(&mut self) -> Option<&mut FDS> {
+        Some(self)
+    }
+}
+
+impl FDS {
+    /// `sides` as stored in an `.fds` file, the first one starts in the drive
+    pub(crate) fn new(bios: Vec<u8>, sides: &[&[u8]]) -> Self {
+        Self {
+            bios,
+            prg_ram: vec![0; RAM_SIZE],
+            chr_ram: vec![0; CHR_RAM_SIZE],
+            mirroring: Mirroring::Vertical,
+            sides: sides.iter().map(|side| disk::add_gaps(side)).collect(),
+            side: Some(0),
+
+            disk_registers_enabled: false,
+            sound_registers_enabled: false,
+
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_repeat: false,
+            timer_enabled: false,
+            timer_irq: false,
+
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            crc_control: false,
+            previous_crc_control: false,
+            transfer_started: false,
+            transfer_irq_enabled: false,
+            transfer_irq: false,
+            transfer_complete: false,
+            read_data: 0,
+            write_data: 0,
+            external: 0,
+
+            position: 0,
+            delay: 0,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            crc: 0,
+
+            audio: FdsAudio::new(),
+        }
+    }
+
+    pub(crate) fn sides(&self) -> usize {
+        self.sides.len()
+    }
+
+    /// Side in the drive
+    pub(crate) fn side(&self) -> Option<usize> {
+        self.side
+    }
+
+    /// Returns false if the disk has no such side
+    pub(crate) fn insert(&mut self, side: usize) -> bool {
+        if side >= self.sides.len() {
+            return false;
+        }
+        self.side = Some(side);
+        self.end_of_head = true;
+        true
+    }
+
+    pub(crate) fn eject(&mut self) {
+        self.side = None;
+    }
+
+    /// Disk content in the `.fds` format, without header
+    pub(crate) fn disk_image(&self) -> Vec<u8> {
+        self.sides
+            .iter()
+            .flat_map(|side| disk::strip_gaps(side))
+            .collect()
+    }
+
+    /// Replaces the disk content with an image from `disk_image`, returns false if it
+    /// doesn't have the same number of sides
+    pub(crate) fn load_disk_image(&mut self, image: &[u8]) -> bool {
+        if image.len() != self.sides.len() * disk::SIDE_SIZE {
+            return false;
+        }
+        self.sides = image.chunks(disk::SIDE_SIZE).map(disk::add_gaps).collect();
+        self.end_of_head = true;
+        true
+    }
+
+    fn read_register(&self, address: u16) -> u8 {
+        match address {
+            // CRC errors and the end of head flag aren't reported, the BIOS copes
+            0x4030 => self.timer_irq as u8 | (self.transfer_complete as u8) << 1,
+            0x4031 => self.read_data,
+            0x4032 => {
+                let no_disk = self.side.is_none();
+                no_disk as u8 | ((no_disk || !self.scanning) as u8) << 1 | (no_disk as u8) << 2
+            }
+            // the battery is always good
+            _ => 0x80 | self.external & 0x7F,
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x4020 => self.timer_reload = self.timer_reload & 0xFF00 | value as u16,
+            0x4021 => self.timer_reload = self.timer_reload & 0x00FF | (value as u16) << 8,
+            0x4022 => {
+                self.timer_repeat = value & 0x01 != 0;
+                self.timer_enabled = value & 0x02 != 0 && self.disk_registers_enabled;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_registers_enabled = value & 0x01 != 0;
+                self.sound_registers_enabled = value & 0x02 != 0;
+                if !self.disk_registers_enabled {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                    self.transfer_irq = false;
+                }
+            }
+            _ if !self.disk_registers_enabled => {}
+            0x4024 => {
+                self.write_data = value;
+                self.transfer_complete = false;
+                self.transfer_irq = false;
+            }
+            0x4025 => {
+                self.motor_on = value & 0x01 != 0;
+                self.reset_transfer = value & 0x02 != 0;
+                self.read_mode = value & 0x04 != 0;
+                self.mirroring = if value & 0x08 != 0 {
+                    Mirroring::Horizontal
+                } else {
+                    Mirroring::Vertical
+                };
+                self.crc_control = value & 0x10 != 0;
+                self.transfer_started = value & 0x40 != 0;
+                self.transfer_irq_enabled = value & 0x80 != 0;
+                self.transfer_irq = false;
+            }
+            _ => self.external = value,
+        }
+    }
+
+    fn tick_timer(&mut self) {
+        if !self.timer_enabled {
+            return;
+        }
+        if self.timer_counter == 0 {
+            self.timer_irq = true;
+            self.timer_counter = self.timer_reload;
+            if !self.timer_repeat {
+                self.timer_enabled = false;
+            }
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    /// Moves the disk under the head, a byte is transferred every `BYTE_CYCLES`
+    fn tick_drive(&mut self) {
+        let side = match self.side {
+            Some(side) if self.motor_on => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning = false;
+                return;
+            }
+        };
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = REWIND_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        if self.read_mode {
+            let value = self.sides[side][self.position];
+            if !self.previous_crc_control {
+                self.crc = disk::update_crc(self.crc, value);
+            }
+            let mut irq = self.transfer_irq_enabled;
+            if !self.transfer_started {
+                self.gap_ended = false;
+                self.crc = 0;
+            } else if value != 0 && !self.gap_ended {
+                // the block start mark isn't handed to the BIOS
+                self.gap_ended = true;
+                irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = value;
+                self.transfer_irq |= irq;
+            }
+        } else {
+            let mut value = 0;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                value = self.write_data;
+                self.transfer_irq |= self.transfer_irq_enabled;
+            }
+            if !self.transfer_started {
+                value = 0;
+            }
+            if !self.crc_control {
+                self.crc = disk::update_crc(self.crc, value);
+            } else {
+                if !self.previous_crc_control {
+                    self.crc = disk::update_crc(disk::update_crc(self.crc, 0), 0);
+                }
+                value = self.crc as u8;
+                self.crc >>= 8;
+            }
+            // the write head trails the read head by two bytes
+            if let Some(byte) = self
+                .position
+                .checked_sub(2)
+                .and_then(|position| self.sides[side].get_mut(position))
+            {
+                *byte = value;
+            }
+            self.gap_ended = false;
+        }
+        self.previous_crc_control = self.crc_control;
+
+        self.position += 1;
+        if self.position >= self.sides[side].len() {
+            self.motor_on = false;
+        } else {
+            self.delay = BYTE_CYCLES;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::disk::tests::side;
+
+    fn fds() -> FDS {
+        let side = side();
+        let mut fds = FDS::new(vec![0xEA; disk::BIOS_SIZE], &[&side, &side]);
+        fds.write(0x4023, 0x03);
+        fds
+    }
+
+    fn run(fds: &mut FDS, cycles: u32) {
+        for _ in 0..cycles {
+            fds.tick();
+        }
+    }
+
+    #[test]
+    fn memory_map() {
+        let mut fds = fds();
+        assert_eq!(fds.read(0xE000), 0xEA);
+        fds.write(0x6000, 0x12);
+        fds.write(0xDFFF, 0x34);
+        fds.write(0xE000, 0x56);
+        assert_eq!(fds.read(0x6000), 0x12);
+        assert_eq!(fds.read(0xDFFF), 0x34);
+        assert_eq!(fds.read(0xE000), 0xEA);
+        assert_eq!(fds.get_save_ram().len(), RAM_SIZE);
+
+        let mut vram = [0u8; 0x800];
+        fds.ppu_write(0x1FFF, 0x78, &mut vram);
+        assert_eq!(fds.ppu_read(0x1FFF, &vram), 0x78);
+        // horizontal mirroring
+        fds.write(0x4025, 0x08);
+        fds.ppu_write(0x2400, 0x9A, &mut vram);
+        assert_eq!(fds.ppu_read(0x2000, &vram), 0x9A);
+    }
+
+    #[test]
+    fn timer_irq() {
+        let mut fds = fds();
+        fds.write(0x4020, 10);
+        fds.write(0x4021, 0);
+        fds.write(0x4022, 0x03);
+        run(&mut fds, 10);
+        assert!(!fds.irq());
+        run(&mut fds, 1);
+        assert!(fds.irq());
+        assert_eq!(fds.read(0x4030) & 0x01, 0x01);
+        fds.bus_read(0x4030);
+        assert!(!fds.irq());
+
+        // repeats until disabled
+        run(&mut fds, 11);
+        assert!(fds.irq());
+        fds.write(0x4022, 0x00);
+        assert!(!fds.irq());
+        run(&mut fds, 100);
+        assert!(!fds.irq());
+
+        // disabling the disk registers stops it too
+        fds.write(0x4022, 0x02);
+        fds.write(0x4023, 0x00);
+        run(&mut fds, 100);
+        assert!(!fds.irq());
+    }
+
+    #[test]
+    fn reads_the_disk() {
+        let mut fds = fds();
+        assert_eq!(fds.read(0x4032) & 0x07, 0x02);
+        // motor on, read mode, transfer IRQ
+        fds.write(0x4025, 0x85);
+        run(&mut fds, REWIND_CYCLES + 2);
+        assert_eq!(fds.read(0x4032) & 0x07, 0x00);
+        // in the gap, then looking for the block
+        fds.write(0x4025, 0xC5);
+        while !fds.irq() {
+            fds.tick();
+        }
+        assert_eq!(fds.bus_read(0x4031), 0x01);
+        assert!(!fds.irq());
+        let mut block = vec![0x01];
+        while block.len() < 15 {
+            fds.tick();
+            if fds.irq() {
+                block.push(fds.bus_read(0x4031));
+            }
+        }
+        assert_eq!(&block[..], b"\x01*NINTENDO-HVC*");
+    }
+
+    #[test]
+    fn disk_sides() {
+        let mut fds = fds();
+        assert_eq!(fds.sides(), 2);
+        assert_eq!(fds.side(), Some(0));
+        fds.eject();
+        assert_eq!(fds.read(0x4032) & 0x07, 0x07);
+        assert!(!fds.insert(2));
+        assert!(fds.insert(1));
+        assert_eq!(fds.side(), Some(1));
+
+        let mut image = fds.disk_image();
+        assert_eq!(image, [side(), side()].concat());
+        // a byte of the file on the second side
+        image[disk::SIDE_SIZE + 76] = 0x42;
+        assert!(fds.load_disk_image(&image));
+        assert_eq!(fds.disk_image(), image);
+        assert!(!fds.load_disk_image(&image[..disk::SIDE_SIZE]));
+    }
+}
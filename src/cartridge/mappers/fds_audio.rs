@@ -0,0 +1,405 @@
+//! FDS wavetable channel: a 64 step wave played at a pitch bent by a modulation unit,
+//! registers at $4040-$408A
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Wave gain multipliers for the 4 master volume settings, out of 36
+const MASTER_VOLUMES: [u32; 4] = [36, 24, 17, 14];
+/// Pitch change of each modulation table entry, 4 resets the counter
+const MOD_STEPS: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+/// Level of the highest output in the APU mixer, the FDS at full volume is about 2.4 times
+/// as loud as a pulse channel
+const MAX_OUTPUT: f32 = 2.4 * 0.149;
+
+/// Volume or modulation envelope
+#[derive(Clone, Copy)]
+struct Envelope {
+    speed: u8,
+    increase: bool,
+    disabled: bool,
+    gain: u8,
+    timer: u32,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Self {
+            speed: 0,
+            increase: false,
+            disabled: true,
+            gain: 0,
+            timer: 0,
+        }
+    }
+
+    /// $4080 or $4084
+    fn write(&mut self, value: u8, master_speed: u8) {
+        self.speed = value & 0x3F;
+        self.increase = value & 0x40 != 0;
+        self.disabled = value & 0x80 != 0;
+        self.reset_timer(master_speed);
+        if self.disabled {
+            self.gain = self.speed;
+        }
+    }
+
+    fn reset_timer(&mut self, master_speed: u8) {
+        self.timer = 8 * (self.speed as u32 + 1) * master_speed as u32;
+    }
+
+    /// Returns true when the gain was clocked
+    fn tick(&mut self, master_speed: u8) -> bool {
+        if self.disabled || master_speed == 0 {
+            return false;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            if self.timer > 0 {
+                return false;
+            }
+        }
+        self.reset_timer(master_speed);
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+        true
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.speed);
+        writer.write_bool(self.increase);
+        writer.write_bool(self.disabled);
+        writer.write_u8(self.gain);
+        writer.write_u32(self.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.speed = reader.read_u8()?;
+        self.increase = reader.read_bool()?;
+        self.disabled = reader.read_bool()?;
+        self.gain = reader.read_u8()?;
+        self.timer = reader.read_u32()?;
+        if self.speed > 0x3F || self.gain > 0x3F {
+            return Err(StateError::InvalidValue("FDS envelope"));
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct FdsAudio {
+    wave: [u8; 64],
+    wave_write: bool,
+    wave_position: u8,
+    wave_accumulator: u16,
+    wave_halted: bool,
+    frequency: u16,
+    volume: Envelope,
+    envelopes_halted: bool,
+    master_volume: u8,
+    master_envelope_speed: u8,
+
+    mod_table: [u8; 64],
+    mod_position: u8,
+    mod_accumulator: u16,
+    mod_frequency: u16,
+    mod_halted: bool,
+    /// 7-bit signed
+    mod_counter: i8,
+    mod_envelope: Envelope,
+    /// Pitch change from the modulation unit
+    mod_output: i32,
+
+    output: u8,
+}
+
+impl FdsAudio {
+    pub(crate) fn new() -> Self {
+        Self {
+            wave: [0; 64],
+            wave_write: false,
+            wave_position: 0,
+            wave_accumulator: 0,
+            wave_halted: true,
+            frequency: 0,
+            volume: Envelope::new(),
+            envelopes_halted: false,
+            master_volume: 0,
+            master_envelope_speed: 0xE8,
+
+            mod_table: [0; 64],
+            mod_position: 0,
+            mod_accumulator: 0,
+            mod_frequency: 0,
+            mod_halted: true,
+            mod_counter: 0,
+            mod_envelope: Envelope::new(),
+            mod_output: 0,
+
+            output: 0,
+        }
+    }
+
+    pub(crate) fn read(&self, address: u16) -> Option<u8> {
+        match address {
+            0x4040..=0x407F => Some(self.wave[address as usize - 0x4040]),
+            0x4090 => Some(self.volume.gain),
+            0x4092 => Some(self.mod_envelope.gain),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x4040..=0x407F if self.wave_write => {
+                self.wave[address as usize - 0x4040] = value & 0x3F;
+            }
+            0x4080 => self.volume.write(value, self.master_envelope_speed),
+            0x4082 => self.frequency = self.frequency & 0x0F00 | value as u16,
+            0x4083 => {
+                self.frequency = self.frequency & 0x00FF | ((value & 0x0F) as u16) << 8;
+                self.wave_halted = value & 0x80 != 0;
+                self.envelopes_halted = value & 0x40 != 0;
+                if self.wave_halted {
+                    self.wave_position = 0;
+                    self.wave_accumulator = 0;
+                }
+                if self.envelopes_halted {
+                    self.volume.reset_timer(self.master_envelope_speed);
+                    self.mod_envelope.reset_timer(self.master_envelope_speed);
+                }
+            }
+            0x4084 => {
+                self.mod_envelope.write(value, self.master_envelope_speed);
+                self.update_mod_output();
+            }
+            0x4085 => {
+                self.set_mod_counter(value as i32 & 0x7F);
+                self.update_mod_output();
+            }
+            0x4086 => self.mod_frequency = self.mod_frequency & 0x0F00 | value as u16,
+            0x4087 => {
+                self.mod_frequency = self.mod_frequency & 0x00FF | ((value & 0x0F) as u16) << 8;
+                self.mod_halted = value & 0x80 != 0;
+                if self.mod_halted {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // the table can only be written while the unit is halted, each write fills
+            // two entries
+            0x4088 if self.mod_halted => {
+                let position = self.mod_position as usize;
+                self.mod_table[position] = value & 0x07;
+                self.mod_table[(position + 1) & 0x3F] = value & 0x07;
+                self.mod_position = (self.mod_position + 2) & 0x3F;
+            }
+            0x4089 => {
+                self.master_volume = value & 0x03;
+                self.wave_write = value & 0x80 != 0;
+            }
+            0x408A => self.master_envelope_speed = value,
+            _ => {}
+        }
+    }
+
+    /// Called every CPU cycle
+    pub(crate) fn tick(&mut self) {
+        if !self.wave_halted && !self.envelopes_halted {
+            self.volume.tick(self.master_envelope_speed);
+            if self.mod_envelope.tick(self.master_envelope_speed) {
+                self.update_mod_output();
+            }
+        }
+
+        if !self.mod_halted && self.mod_frequency > 0 {
+            let (accumulator, overflow) = self.mod_accumulator.overflowing_add(self.mod_frequency);
+            self.mod_accumulator = accumulator;
+            if overflow {
+                let step = self.mod_table[self.mod_position as usize];
+                let counter = if step == 4 {
+                    0
+                } else {
+                    self.mod_counter as i32 + MOD_STEPS[step as usize] as i32
+                };
+                self.set_mod_counter(counter);
+                self.mod_position = (self.mod_position + 1) & 0x3F;
+                self.update_mod_output();
+            }
+        }
+
+        self.update_output();
+        if self.wave_halted || self.wave_write {
+            return;
+        }
+        let pitch = self.frequency as i32 + self.mod_output;
+        if pitch > 0 {
+            let (accumulator, overflow) = self.wave_accumulator.overflowing_add(pitch as u16);
+            self.wave_accumulator = accumulator;
+            if overflow {
+                self.wave_position = (self.wave_position + 1) & 0x3F;
+            }
+        }
+    }
+
+    /// Level in the APU mixer
+    pub(crate) fn output(&self) -> f32 {
+        self.output as f32 * MAX_OUTPUT / 63.
+    }
+
+    /// Wraps the counter to 7-bit signed
+    fn set_mod_counter(&mut self, value: i32) {
+        self.mod_counter = ((value + 64) & 0x7F) as i8 - 64;
+    }
+
+    /// Pitch change from the counter and gain, with the rounding of the real chip
+    fn update_mod_output(&mut self) {
+        let counter = self.mod_counter as i32;
+        let mut temp = counter * self.mod_envelope.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+
+        temp *= self.frequency as i32;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        self.mod_output = temp;
+    }
+
+    fn update_output(&mut self) {
+        let gain = self.volume.gain.min(32) as u32 * MASTER_VOLUMES[self.master_volume as usize];
+        self.output = (self.wave[self.wave_position as usize] as u32 * gain / 1152) as u8;
+    }
+
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.wave);
+        writer.write_bool(self.wave_write);
+        writer.write_u8(self.wave_position);
+        writer.write_u16(self.wave_accumulator);
+        writer.write_bool(self.wave_halted);
+        writer.write_u16(self.frequency);
+        self.volume.save_state(writer);
+        writer.write_bool(self.envelopes_halted);
+        writer.write_u8(self.master_volume);
+        writer.write_u8(self.master_envelope_speed);
+
+        writer.write_bytes(&self.mod_table);
+        writer.write_u8(self.mod_position);
+        writer.write_u16(self.mod_accumulator);
+        writer.write_u16(self.mod_frequency);
+        writer.write_bool(self.mod_halted);
+        writer.write_i8(self.mod_counter);
+        self.mod_envelope.save_state(writer);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.read_bytes_into(&mut self.wave)?;
+        self.wave_write = reader.read_bool()?;
+        self.wave_position = reader.read_u8()? & 0x3F;
+        self.wave_accumulator = reader.read_u16()?;
+        self.wave_halted = reader.read_bool()?;
+        self.frequency = reader.read_u16()? & 0x0FFF;
+        self.volume.load_state(reader)?;
+        self.envelopes_halted = reader.read_bool()?;
+        self.master_volume = reader.read_u8()? & 0x03;
+        self.master_envelope_speed = reader.read_u8()?;
+
+        reader.read_bytes_into(&mut self.mod_table)?;
+        if self.wave.iter().any(|v| *v > 0x3F) || self.mod_table.iter().any(|v| *v > 0x07) {
+            return Err(StateError::InvalidValue("FDS wave"));
+        }
+        self.mod_position = reader.read_u8()? & 0x3F;
+        self.mod_accumulator = reader.read_u16()?;
+        self.mod_frequency = reader.read_u16()? & 0x0FFF;
+        self.mod_halted = reader.read_bool()?;
+        self.mod_counter = reader.read_i8()?;
+        self.set_mod_counter(self.mod_counter as i32);
+        self.mod_envelope.load_state(reader)?;
+        self.update_mod_output();
+        self.update_output();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wave_playback() {
+        let mut audio = FdsAudio::new();
+        audio.write(0x4089, 0x80);
+        for i in 0..64 {
+            audio.write(0x4040 + i, i as u8);
+        }
+        assert_eq!(audio.read(0x407F), Some(63));
+        // direct volume 32, master volume 2/2
+        audio.write(0x4089, 0x00);
+        audio.write(0x4080, 0xA0);
+        assert_eq!(audio.read(0x4090), Some(32));
+        // the wave can't be written while it plays
+        audio.write(0x4040, 0x3F);
+        assert_eq!(audio.read(0x4040), Some(0));
+
+        // one step every 0x10000 / 0x800 = 32 cycles
+        audio.write(0x4082, 0x00);
+        audio.write(0x4083, 0x08);
+        for _ in 0..32 * 10 {
+            audio.tick();
+        }
+        audio.tick();
+        assert_eq!(audio.output, 10);
+        assert!(audio.output() > 0.);
+
+        // halting resets the wave
+        audio.write(0x4083, 0x88);
+        audio.tick();
+        assert_eq!(audio.output, 0);
+    }
+
+    #[test]
+    fn modulation() {
+        let mut audio = FdsAudio::new();
+        audio.write(0x4087, 0x80);
+        // +1, +1, reset, -1, the 32 writes bring the position back to the start
+        for step in [1, 4, 7].iter().chain([0; 29].iter()) {
+            audio.write(0x4088, *step);
+        }
+        assert_eq!(audio.mod_table[..6], [1, 1, 4, 4, 7, 7]);
+
+        audio.write(0x4082, 0x00);
+        audio.write(0x4083, 0x01);
+        // gain 32
+        audio.write(0x4084, 0xA0);
+        audio.write(0x4085, 0x3F);
+        // counter 63 * gain 32 / 16 = 126, then * pitch 256 / 64
+        assert_eq!(audio.mod_output, 504);
+        audio.write(0x4085, 0x7F);
+        assert_eq!(audio.mod_counter, -1);
+        assert_eq!(audio.mod_output, -8);
+
+        // one table step every 16 cycles
+        audio.write(0x4085, 0x00);
+        audio.write(0x4086, 0xFF);
+        audio.write(0x4087, 0x0F);
+        audio.write(0x4084, 0x80);
+        let mut counters = vec![audio.mod_counter];
+        for _ in 0..7 * 16 {
+            audio.tick();
+            if counters.last() != Some(&audio.mod_counter) {
+                counters.push(audio.mod_counter);
+            }
+        }
+        assert_eq!(counters, [0, 1, 2, 0, -1, -2]);
+    }
+}
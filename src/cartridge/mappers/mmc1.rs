@@ -1,11 +1,12 @@
 use super::{
     super::{BANK_1_OFFSET, BANK_2_OFFSET},
-    Mapper, Mirroring,
+    prg_ram_offset, Mapper, Mirroring,
 };
 use crate::cpu::addresses::{EXPANSION_ROM, PRG_ROM_LOWER, SAVE_RAM};
+use crate::state::{StateError, StateReader, StateWriter};
 
 pub(crate) struct MMC1 {
-    save_ram: [u8; 0x2000],
+    save_ram: Vec<u8>,
     lower_bank: usize,
     higher_bank: usize,
     banks: Vec<[u8; 0x4000]>,
@@ -38,9 +39,9 @@ impl Mapper for MMC1 {
             self.banks[self.lower_bank][(address - BANK_1_OFFSET) as usize]
         } else if address >= SAVE_RAM {
             // PRG RAM
-            self.save_ram[(address - SAVE_RAM) as usize]
+            prg_ram_offset(address, self.save_ram.len()).map_or(0, |i| self.save_ram[i])
         } else {
-            unreachable!()
+            0
         }
     }
 
@@ -77,7 +78,9 @@ impl Mapper for MMC1 {
             }
         } else {
             // PRG RAM
-            self.save_ram[(address - SAVE_RAM) as usize] = value;
+            if let Some(i) = prg_ram_offset(address, self.save_ram.len()) {
+                self.save_ram[i] = value;
+            }
         }
     }
 
@@ -125,19 +128,123 @@ impl Mapper for MMC1 {
         }
     }
 
+    fn cpu_byte_mut(&mut self, address: u16) -> Option<&mut u8> {
+        if address >= BANK_2_OFFSET {
+            Some(&mut self.banks[self.higher_bank][(address - BANK_2_OFFSET) as usize])
+        } else if address >= BANK_1_OFFSET {
+            Some(&mut self.banks[self.lower_bank][(address - BANK_1_OFFSET) as usize])
+        } else if address >= SAVE_RAM {
+            let i = prg_ram_offset(address, self.save_ram.len())?;
+            Some(&mut self.save_ram[i])
+        } else {
+            None
+        }
+    }
+
+    fn prg_banks(&self) -> Vec<&[u8]> {
+        self.banks.iter().map(|b| &b[..]).collect()
+    }
+
+    fn prg_banks_mut(&mut self) -> Vec<&mut [u8]> {
+        self.banks.iter_mut().map(|b| &mut b[..]).collect()
+    }
+
+    fn chr_banks(&self) -> Vec<&[u8]> {
+        self.chr_banks.iter().map(|b| &b[..]).collect()
+    }
+
+    fn chr_banks_mut(&mut self) -> Vec<&mut [u8]> {
+        self.chr_banks.iter_mut().map(|b| &mut b[..]).collect()
+    }
+
     fn get_save_ram(&self) -> Vec<u8> {
         self.save_ram.iter().cloned().collect()
     }
 
     fn set_save_ram(&mut self, data: Vec<u8>) {
-        for (i, x) in data.into_iter().take(0x2000).enumerate() {
+        for (i, x) in data.into_iter().take(self.save_ram.len()).enumerate() {
             self.save_ram[i] = x;
         }
     }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.save_ram);
+        writer.write_usize(self.lower_bank);
+        writer.write_usize(self.higher_bank);
+        writer.write_usize(self.lower_chr);
+        writer.write_usize(self.higher_chr);
+        // CHR banks are writable (see ppu_write), so they have to be part of the state
+        writer.write_u32(self.chr_banks.len() as u32);
+        for bank in self.chr_banks.iter() {
+            writer.write_bytes(bank);
+        }
+        writer.write_u8(self.load_register);
+        writer.write_usize(self.written_last_cycle);
+        writer.write_u8(match self.prg_rom_switch_mode {
+            PrgRomSwitchMode::Switch32k => 0,
+            PrgRomSwitchMode::FirstFixed => 1,
+            PrgRomSwitchMode::LastFixed => 2,
+        });
+        writer.write_u8(match self.chr_rom_switch_mode {
+            ChrRomSwitchMode::Switch8k => 0,
+            ChrRomSwitchMode::SwitchSeparate => 1,
+        });
+        writer.write_u8(self.mirroring.to_u8());
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        if reader.version() >= 2 {
+            reader.read_bytes_into(&mut self.save_ram)?;
+        } else {
+            // version 1 always saved 8K of PRG RAM
+            let ram = reader.read_bytes()?;
+            if ram.len() != 0x2000 {
+                return Err(StateError::InvalidValue("byte array length"));
+            }
+            let len = ram.len().min(self.save_ram.len());
+            self.save_ram[..len].copy_from_slice(&ram[..len]);
+        }
+        self.lower_bank = reader.read_usize()?;
+        self.higher_bank = reader.read_usize()?;
+        self.lower_chr = reader.read_usize()?;
+        self.higher_chr = reader.read_usize()?;
+        if self.lower_bank >= self.banks.len()
+            || self.higher_bank >= self.banks.len()
+            || self.lower_chr >= self.chr_banks.len()
+            || self.higher_chr >= self.chr_banks.len()
+        {
+            return Err(StateError::InvalidValue("MMC1 bank"));
+        }
+        if reader.read_u32()? as usize != self.chr_banks.len() {
+            return Err(StateError::InvalidValue("MMC1 CHR bank count"));
+        }
+        for bank in self.chr_banks.iter_mut() {
+            reader.read_bytes_into(bank)?;
+        }
+        self.load_register = reader.read_u8()?;
+        self.written_last_cycle = reader.read_usize()?;
+        self.prg_rom_switch_mode = match reader.read_u8()? {
+            0 => PrgRomSwitchMode::Switch32k,
+            1 => PrgRomSwitchMode::FirstFixed,
+            2 => PrgRomSwitchMode::LastFixed,
+            _ => return Err(StateError::InvalidValue("MMC1 PRG ROM switch mode")),
+        };
+        self.chr_rom_switch_mode = match reader.read_u8()? {
+            0 => ChrRomSwitchMode::Switch8k,
+            1 => ChrRomSwitchMode::SwitchSeparate,
+            _ => return Err(StateError::InvalidValue("MMC1 CHR ROM switch mode")),
+        };
+        self.mirroring = Mirroring::from_u8(reader.read_u8()?)?;
+        Ok(())
+    }
 }
 
 impl MMC1 {
-    pub(crate) fn new(banks: Vec<[u8; 0x4000]>, chr_banks: Vec<[u8; 0x1000]>) -> Self {
+    pub(crate) fn new(
+        banks: Vec<[u8; 0x4000]>,
+        chr_banks: Vec<[u8; 0x1000]>,
+        prg_ram_size: usize,
+    ) -> Self {
         let last_bank = banks.len() - 1;
         Self {
             lower_bank: 0,
@@ -152,7 +259,7 @@ impl MMC1 {
             prg_rom_switch_mode: PrgRomSwitchMode::LastFixed,
             mirroring: Mirroring::Vertical,
             chr_rom_switch_mode: ChrRomSwitchMode::Switch8k,
-            save_ram: [0; 0x2000]
+            save_ram: vec![0; prg_ram_size],
         }
     }
 
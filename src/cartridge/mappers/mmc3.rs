@@ -0,0 +1,389 @@
+use super::{prg_ram_offset, Mapper, Mirroring};
+use crate::cpu::addresses::SAVE_RAM;
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// CPU cycles A12 has to stay low before a rise clocks the scanline counter,
+/// filters out the rises between the sprite pattern fetches
+const A12_LOW_CYCLES: usize = 3;
+
+/// Mapper 4: 8K PRG banks, 1K/2K CHR banks and a scanline counter clocked by PPU A12
+pub(crate) struct MMC3 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    save_ram: Vec<u8>,
+    save_ram_enabled: bool,
+    save_ram_write_protect: bool,
+    bank_select: u8,
+    bank_registers: [u8; 8],
+    mirroring: Mirroring,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    a12: bool,
+    a12_low_cycles: usize,
+}
+
+impl Mapper for MMC3 {
+    fn read(&self, address: u16) -> u8 {
+        if address >= 0x8000 {
+            self.prg_rom[self.prg_address(address)]
+        } else if address >= SAVE_RAM {
+            match prg_ram_offset(address, self.save_ram.len()) {
+                Some(i) if self.save_ram_enabled => self.save_ram[i],
+                _ => 0,
+            }
+        } else {
+            0
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if address >= 0x8000 {
+            let even = address & 1 == 0;
+            match (address & 0xE000, even) {
+                (0x8000, true) => self.bank_select = value,
+                (0x8000, false) => {
+                    self.bank_registers[(self.bank_select & 0x07) as usize] = value;
+                }
+                (0xA000, true) => {
+                    self.mirroring = if value & 1 == 0 {
+                        Mirroring::Vertical
+                    } else {
+                        Mirroring::Horizontal
+                    };
+                }
+                (0xA000, false) => {
+                    self.save_ram_enabled = value & 0x80 != 0;
+                    self.save_ram_write_protect = value & 0x40 != 0;
+                }
+                (0xC000, true) => self.irq_latch = value,
+                (0xC000, false) => {
+                    self.irq_counter = 0;
+                    self.irq_reload = true;
+                }
+                (0xE000, true) => {
+                    self.irq_enabled = false;
+                    self.irq_pending = false;
+                }
+                (0xE000, false) => self.irq_enabled = true,
+                _ => unreachable!(),
+            }
+        } else if address >= SAVE_RAM && self.save_ram_enabled && !self.save_ram_write_protect {
+            if let Some(i) = prg_ram_offset(address, self.save_ram.len()) {
+                self.save_ram[i] = value;
+            }
+        }
+    }
+
+    fn tick(&mut self) {
+        if !self.a12 {
+            self.a12_low_cycles = self.a12_low_cycles.saturating_add(1);
+        }
+    }
+
+    fn ppu_read(&self, address: u16, internal_vram: &[u8; 0x800]) -> u8 {
+        if address < 0x2000 {
+            self.chr[self.chr_address(address)]
+        } else if address < 0x3F00 {
+            internal_vram[self.mirroring.map_nametable_address(address)]
+        } else {
+            unreachable!();
+        }
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8, internal_vram: &mut [u8; 0x800]) {
+        if address < 0x2000 {
+            if self.chr_ram {
+                let address = self.chr_address(address);
+                self.chr[address] = value;
+            }
+        } else if address < 0x3F00 {
+            internal_vram[self.mirroring.map_nametable_address(address)] = value;
+        } else {
+            unreachable!();
+        }
+    }
+
+    fn cpu_byte_mut(&mut self, address: u16) -> Option<&mut u8> {
+        if address >= 0x8000 {
+            let address = self.prg_address(address);
+            Some(&mut self.prg_rom[address])
+        } else if address >= SAVE_RAM {
+            let i = prg_ram_offset(address, self.save_ram.len())?;
+            Some(&mut self.save_ram[i])
+        } else {
+            None
+        }
+    }
+
+    fn prg_banks(&self) -> Vec<&[u8]> {
+        vec![&self.prg_rom]
+    }
+
+    fn prg_banks_mut(&mut self) -> Vec<&mut [u8]> {
+        vec![&mut self.prg_rom]
+    }
+
+    fn chr_banks(&self) -> Vec<&[u8]> {
+        vec![&self.chr]
+    }
+
+    fn chr_banks_mut(&mut self) -> Vec<&mut [u8]> {
+        vec![&mut self.chr]
+    }
+
+    fn get_save_ram(&self) -> Vec<u8> {
+        self.save_ram.to_vec()
+    }
+
+    fn set_save_ram(&mut self, data: Vec<u8>) {
+        for (i, x) in data.into_iter().take(self.save_ram.len()).enumerate() {
+            self.save_ram[i] = x;
+        }
+    }
+
+    fn ppu_address(&mut self, address: u16) {
+        let a12 = address & 0x1000 != 0;
+        if a12 {
+            if !self.a12 && self.a12_low_cycles >= A12_LOW_CYCLES {
+                self.clock_irq_counter();
+            }
+            self.a12_low_cycles = 0;
+        }
+        self.a12 = a12;
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        if self.chr_ram {
+            writer.write_bytes(&self.chr);
+        }
+        writer.write_bytes(&self.save_ram);
+        writer.write_bool(self.save_ram_enabled);
+        writer.write_bool(self.save_ram_write_protect);
+        writer.write_u8(self.bank_select);
+        for register in self.bank_registers.iter() {
+            writer.write_u8(*register);
+        }
+        writer.write_u8(self.mirroring.to_u8());
+        writer.write_u8(self.irq_latch);
+        writer.write_u8(self.irq_counter);
+        writer.write_bool(self.irq_reload);
+        writer.write_bool(self.irq_enabled);
+        writer.write_bool(self.irq_pending);
+        writer.write_bool(self.a12);
+        writer.write_usize(self.a12_low_cycles);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        if self.chr_ram {
+            reader.read_bytes_into(&mut self.chr)?;
+        }
+        reader.read_bytes_into(&mut self.save_ram)?;
+        self.save_ram_enabled = reader.read_bool()?;
+        self.save_ram_write_protect = reader.read_bool()?;
+        self.bank_select = reader.read_u8()?;
+        for register in self.bank_registers.iter_mut() {
+            *register = reader.read_u8()?;
+        }
+        self.mirroring = Mirroring::from_u8(reader.read_u8()?)?;
+        self.irq_latch = reader.read_u8()?;
+        self.irq_counter = reader.read_u8()?;
+        self.irq_reload = reader.read_bool()?;
+        self.irq_enabled = reader.read_bool()?;
+        self.irq_pending = reader.read_bool()?;
+        self.a12 = reader.read_bool()?;
+        self.a12_low_cycles = reader.read_usize()?;
+        Ok(())
+    }
+}
+
+impl MMC3 {
+    pub(crate) fn new(
+        prg_rom: Vec<u8>,
+        chr: Vec<u8>,
+        chr_ram: bool,
+        prg_ram_size: usize,
+        mirroring: Mirroring,
+    ) -> Self {
+        Self {
+            prg_rom,
+            chr,
+            chr_ram,
+            save_ram: vec![0; prg_ram_size],
+            save_ram_enabled: true,
+            save_ram_write_protect: false,
+            bank_select: 0,
+            bank_registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            a12: false,
+            a12_low_cycles: 0,
+        }
+    }
+
+    fn prg_address(&self, address: u16) -> usize {
+        let banks = self.prg_rom.len() / 0x2000;
+        let second_last = banks - 2;
+        let swap_mode = self.bank_select & 0x40 != 0;
+        let bank = match (address & 0x6000) >> 13 {
+            0 if swap_mode => second_last,
+            0 => self.bank_registers[6] as usize,
+            1 => self.bank_registers[7] as usize,
+            2 if swap_mode => self.bank_registers[6] as usize,
+            2 => second_last,
+            3 => banks - 1,
+            _ => unreachable!(),
+        };
+        (bank % banks) * 0x2000 + (address as usize & 0x1FFF)
+    }
+
+    fn chr_address(&self, address: u16) -> usize {
+        // CHR A12 inversion swaps the 2K and 1K halves
+        let address = if self.bank_select & 0x80 != 0 {
+            address ^ 0x1000
+        } else {
+            address
+        };
+        let bank = match address >> 10 {
+            0 => self.bank_registers[0] & 0xFE,
+            1 => self.bank_registers[0] | 1,
+            2 => self.bank_registers[1] & 0xFE,
+            3 => self.bank_registers[1] | 1,
+            n => self.bank_registers[n as usize - 2],
+        };
+        let banks = self.chr.len() / 0x400;
+        (bank as usize % banks) * 0x400 + (address as usize & 0x3FF)
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every 1K of CHR and 8K of PRG is filled with its bank number
+    fn mapper() -> MMC3 {
+        let prg = (0..16).flat_map(|i| vec![i as u8; 0x2000]).collect();
+        let chr = (0..64).flat_map(|i| vec![i as u8; 0x400]).collect();
+        MMC3::new(prg, chr, false, 0x2000, Mirroring::Vertical)
+    }
+
+    /// Simulates the A12 activity of one visible scanline, background at $0000, sprites at $1000
+    fn scanline(mapper: &mut MMC3) {
+        for _ in 0..85 {
+            mapper.ppu_address(0x0000);
+            mapper.tick();
+        }
+        for _ in 0..8 {
+            mapper.ppu_address(0x2000);
+            mapper.ppu_address(0x1FF0);
+            mapper.tick();
+        }
+        for _ in 0..21 {
+            mapper.ppu_address(0x0000);
+            mapper.tick();
+        }
+    }
+
+    #[test]
+    fn prg_banking() {
+        let mut mapper = mapper();
+        mapper.write(0x8000, 6);
+        mapper.write(0x8001, 3);
+        mapper.write(0x8000, 7);
+        mapper.write(0x8001, 5);
+        assert_eq!(mapper.read(0x8000), 3);
+        assert_eq!(mapper.read(0xA000), 5);
+        assert_eq!(mapper.read(0xC000), 14);
+        assert_eq!(mapper.read(0xE000), 15);
+
+        // swap $8000 and $C000
+        mapper.write(0x8000, 0x40);
+        assert_eq!(mapper.read(0x8000), 14);
+        assert_eq!(mapper.read(0xA000), 5);
+        assert_eq!(mapper.read(0xC000), 3);
+        assert_eq!(mapper.read(0xFFFF), 15);
+    }
+
+    #[test]
+    fn chr_banking() {
+        let mut mapper = mapper();
+        let vram = [0u8; 0x800];
+        for (register, bank) in [9, 20, 30, 31, 32, 33].iter().enumerate() {
+            mapper.write(0x8000, register as u8);
+            mapper.write(0x8001, *bank);
+        }
+        let banks = |mapper: &MMC3| -> Vec<u8> {
+            (0..8)
+                .map(|i| mapper.ppu_read(i * 0x400, &vram))
+                .collect()
+        };
+        // the low bit of the 2K banks is ignored
+        assert_eq!(banks(&mapper), vec![8, 9, 20, 21, 30, 31, 32, 33]);
+
+        mapper.write(0x8000, 0x80);
+        assert_eq!(banks(&mapper), vec![30, 31, 32, 33, 8, 9, 20, 21]);
+    }
+
+    #[test]
+    fn scanline_irq() {
+        let mut mapper = mapper();
+        mapper.write(0xC000, 3);
+        mapper.write(0xC001, 0);
+        mapper.write(0xE001, 0);
+
+        // first clock reloads the counter, then it counts down to 0
+        for _ in 0..3 {
+            scanline(&mut mapper);
+            assert!(!mapper.irq());
+        }
+        scanline(&mut mapper);
+        assert!(mapper.irq());
+
+        // acknowledge
+        mapper.write(0xE000, 0);
+        assert!(!mapper.irq());
+
+        // the counter is reloaded from the latch after reaching 0
+        mapper.write(0xE001, 0);
+        for _ in 0..3 {
+            scanline(&mut mapper);
+            assert!(!mapper.irq());
+        }
+        scanline(&mut mapper);
+        assert!(mapper.irq());
+    }
+
+    #[test]
+    fn irq_disabled() {
+        let mut mapper = mapper();
+        mapper.write(0xC000, 1);
+        mapper.write(0xC001, 0);
+        for _ in 0..4 {
+            scanline(&mut mapper);
+        }
+        assert!(!mapper.irq());
+    }
+}
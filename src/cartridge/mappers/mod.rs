@@ -4,14 +4,64 @@ pub(crate) use nrom::NROM;
 mod mmc1;
 pub(crate) use mmc1::MMC1;
 
+mod uxrom;
+pub(crate) use uxrom::UxROM;
+
+mod cnrom;
+pub(crate) use cnrom::CNROM;
+
+mod mmc3;
+pub(crate) use mmc3::MMC3;
+
+mod fds;
+mod fds_audio;
+pub(crate) use fds::FDS;
+
+use crate::cpu::addresses::SAVE_RAM;
+use crate::state::{StateError, StateReader, StateWriter};
+
 pub(crate) trait Mapper {
     fn read(&self, address: u16) -> u8;
+    /// Read made by the CPU, registers can be acknowledged. `read` has no side effects so
+    /// debuggers can use it.
+    fn bus_read(&mut self, address: u16) -> u8 {
+        self.read(address)
+    }
     fn write(&mut self, address: u16, value: u8);
     fn ppu_read(&self, address: u16, internal_vram: &[u8; 0x800]) -> u8;
     fn ppu_write(&mut self, address: u16, value: u8, internal_vram: &mut [u8; 0x800]);
     fn tick(&mut self);
+    /// Byte `read` returns for `address` with the current banking, PRG ROM included,
+    /// `None` where nothing is mapped. Lets debuggers patch memory without the mapper
+    /// registers seeing a write.
+    fn cpu_byte_mut(&mut self, address: u16) -> Option<&mut u8>;
+    /// PRG ROM banks in order
+    fn prg_banks(&self) -> Vec<&[u8]>;
+    fn prg_banks_mut(&mut self) -> Vec<&mut [u8]>;
+    /// CHR ROM or RAM banks in order
+    fn chr_banks(&self) -> Vec<&[u8]>;
+    fn chr_banks_mut(&mut self) -> Vec<&mut [u8]>;
     fn get_save_ram(&self) -> Vec<u8>;
     fn set_save_ram(&mut self, data: Vec<u8>);
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError>;
+    /// Called with the address of every PPU access to the cartridge, before the access itself
+    fn ppu_address(&mut self, _address: u16) {}
+    /// Level of the IRQ output of the cartridge
+    fn irq(&self) -> bool {
+        false
+    }
+    /// Expansion audio, already at its level in the APU mixer
+    fn audio_output(&self) -> f32 {
+        0.
+    }
+    /// The FDS RAM adapter, for the disk drive controls
+    fn disk_system(&self) -> Option<&FDS> {
+        None
+    }
+    fn disk_system_mut(&mut self) -> Option<&mut FDS> {
+        None
+    }
 }
 
 pub(crate) enum Mirroring {
@@ -21,6 +71,50 @@ pub(crate) enum Mirroring {
     OneScreenUpperBank,
 }
 
+impl Mirroring {
+    pub(crate) fn to_u8(&self) -> u8 {
+        match self {
+            Mirroring::Vertical => 0,
+            Mirroring::Horizontal => 1,
+            Mirroring::OneScreenLowerBank => 2,
+            Mirroring::OneScreenUpperBank => 3,
+        }
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Result<Self, StateError> {
+        match value {
+            0 => Ok(Mirroring::Vertical),
+            1 => Ok(Mirroring::Horizontal),
+            2 => Ok(Mirroring::OneScreenLowerBank),
+            3 => Ok(Mirroring::OneScreenUpperBank),
+            _ => Err(StateError::InvalidValue("mirroring")),
+        }
+    }
+
+    /// Maps a nametable address ($2000-$3EFF) to an offset in the internal 2K VRAM
+    pub(crate) fn map_nametable_address(&self, address: u16) -> usize {
+        let table = (address & 0xC00) >> 10;
+        let low_addr = (address % 0x400) as usize;
+        let bank = match self {
+            Mirroring::Vertical => table & 1,
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::OneScreenLowerBank => 0,
+            Mirroring::OneScreenUpperBank => 1,
+        };
+        (bank as usize * 0x400) | low_addr
+    }
+}
+
+/// Offset of `address` ($6000-$7FFF) in `size` bytes of PRG RAM, smaller RAMs are mirrored
+/// over the whole range. `None` when the board has no PRG RAM.
+pub(crate) fn prg_ram_offset(address: u16, size: usize) -> Option<usize> {
+    if size == 0 {
+        None
+    } else {
+        Some((address - SAVE_RAM) as usize % size)
+    }
+}
+
 pub(crate) struct Empty;
 impl Mapper for Empty {
     fn read(&self, _address: u16) -> u8 {
@@ -32,10 +126,29 @@ impl Mapper for Empty {
         0
     }
     fn ppu_write(&mut self, _address: u16, _value: u8, _internal_vram: &mut [u8; 0x800]) {}
+    fn cpu_byte_mut(&mut self, _address: u16) -> Option<&mut u8> {
+        None
+    }
+    fn prg_banks(&self) -> Vec<&[u8]> {
+        vec![]
+    }
+    fn prg_banks_mut(&mut self) -> Vec<&mut [u8]> {
+        vec![]
+    }
+    fn chr_banks(&self) -> Vec<&[u8]> {
+        vec![]
+    }
+    fn chr_banks_mut(&mut self) -> Vec<&mut [u8]> {
+        vec![]
+    }
     fn get_save_ram(&self) -> Vec<u8> {
         vec![]
     }
     fn set_save_ram(&mut self, _data: Vec<u8>) {}
+    fn save_state(&self, _writer: &mut StateWriter) {}
+    fn load_state(&mut self, _reader: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
 }
 
 pub(crate) struct FromVec(Vec<u8>);
@@ -56,6 +169,26 @@ impl Mapper for FromVec {
         todo!()
     }
 
+    fn cpu_byte_mut(&mut self, address: u16) -> Option<&mut u8> {
+        self.0.get_mut(address as usize)
+    }
+
+    fn prg_banks(&self) -> Vec<&[u8]> {
+        vec![&self.0[0x8000..]]
+    }
+
+    fn prg_banks_mut(&mut self) -> Vec<&mut [u8]> {
+        vec![&mut self.0[0x8000..]]
+    }
+
+    fn chr_banks(&self) -> Vec<&[u8]> {
+        vec![]
+    }
+
+    fn chr_banks_mut(&mut self) -> Vec<&mut [u8]> {
+        vec![]
+    }
+
     fn get_save_ram(&self) -> Vec<u8> {
         todo!()
     }
@@ -63,6 +196,12 @@ impl Mapper for FromVec {
     fn set_save_ram(&mut self, data: Vec<u8>) {
         todo!()
     }
+
+    fn save_state(&self, _writer: &mut StateWriter) {}
+
+    fn load_state(&mut self, _reader: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
 }
 
 impl FromVec {
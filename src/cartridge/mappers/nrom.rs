@@ -1,12 +1,16 @@
 use super::{
     super::{BANK_1_OFFSET, BANK_2_OFFSET},
-    Mapper, Mirroring,
+    prg_ram_offset, Mapper, Mirroring,
 };
+use crate::cpu::addresses::SAVE_RAM;
+use crate::state::{StateError, StateReader, StateWriter};
 
 pub(crate) struct NROM {
     one_bank: bool,
     prg_banks: [[u8; 0x4000]; 2],
     chr_bank: [u8; 0x2000],
+    // sized from the header, iNES files get 8K which test ROMs report their results through
+    prg_ram: Vec<u8>,
     mirroring: Mirroring,
 }
 
@@ -20,12 +24,20 @@ impl Mapper for NROM {
             }
         } else if address >= BANK_1_OFFSET {
             self.prg_banks[0][(address - BANK_1_OFFSET) as usize]
+        } else if address >= SAVE_RAM {
+            prg_ram_offset(address, self.prg_ram.len()).map_or(0, |i| self.prg_ram[i])
         } else {
-            unreachable!()
+            0
         }
     }
 
-    fn write(&mut self, _address: u16, _value: u8) {}
+    fn write(&mut self, address: u16, value: u8) {
+        if (SAVE_RAM..BANK_1_OFFSET).contains(&address) {
+            if let Some(i) = prg_ram_offset(address, self.prg_ram.len()) {
+                self.prg_ram[i] = value;
+            }
+        }
+    }
     fn tick(&mut self) {}
 
     fn ppu_read(&self, address: u16, internal_vram: &[u8; 0x800]) -> u8 {
@@ -51,12 +63,66 @@ impl Mapper for NROM {
         }
     }
 
+    fn cpu_byte_mut(&mut self, address: u16) -> Option<&mut u8> {
+        if address >= BANK_2_OFFSET {
+            let bank = if self.one_bank { 0 } else { 1 };
+            Some(&mut self.prg_banks[bank][(address - BANK_2_OFFSET) as usize])
+        } else if address >= BANK_1_OFFSET {
+            Some(&mut self.prg_banks[0][(address - BANK_1_OFFSET) as usize])
+        } else if address >= SAVE_RAM {
+            let i = prg_ram_offset(address, self.prg_ram.len())?;
+            Some(&mut self.prg_ram[i])
+        } else {
+            None
+        }
+    }
+
+    fn prg_banks(&self) -> Vec<&[u8]> {
+        let count = if self.one_bank { 1 } else { 2 };
+        self.prg_banks[..count].iter().map(|b| &b[..]).collect()
+    }
+
+    fn prg_banks_mut(&mut self) -> Vec<&mut [u8]> {
+        let count = if self.one_bank { 1 } else { 2 };
+        self.prg_banks[..count]
+            .iter_mut()
+            .map(|b| &mut b[..])
+            .collect()
+    }
+
+    fn chr_banks(&self) -> Vec<&[u8]> {
+        vec![&self.chr_bank]
+    }
+
+    fn chr_banks_mut(&mut self) -> Vec<&mut [u8]> {
+        vec![&mut self.chr_bank]
+    }
+
     fn get_save_ram(&self) -> Vec<u8> {
-        todo!()
+        self.prg_ram.to_vec()
     }
 
     fn set_save_ram(&mut self, data: Vec<u8>) {
-        todo!()
+        for (i, x) in data.into_iter().take(self.prg_ram.len()).enumerate() {
+            self.prg_ram[i] = x;
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        // CHR is writable (see ppu_write), so it has to be part of the state
+        writer.write_bytes(&self.chr_bank);
+        writer.write_bytes(&self.prg_ram);
+        writer.write_u8(self.mirroring.to_u8());
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.read_bytes_into(&mut self.chr_bank)?;
+        // version 1 had no PRG RAM, keep the current one
+        if reader.version() >= 2 {
+            reader.read_bytes_into(&mut self.prg_ram)?;
+        }
+        self.mirroring = Mirroring::from_u8(reader.read_u8()?)?;
+        Ok(())
     }
 }
 
@@ -65,12 +131,14 @@ impl NROM {
         one_bank: bool,
         prg_banks: [[u8; 0x4000]; 2],
         chr_bank: [u8; 0x2000],
+        prg_ram_size: usize,
         mirroring: Mirroring,
     ) -> Self {
         Self {
             one_bank,
             prg_banks,
             chr_bank,
+            prg_ram: vec![0; prg_ram_size],
             mirroring,
         }
     }
@@ -0,0 +1,195 @@
+use super::{
+    super::{BANK_1_OFFSET, BANK_2_OFFSET},
+    prg_ram_offset, Mapper, Mirroring,
+};
+use crate::cpu::addresses::SAVE_RAM;
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Mapper 2: switchable 16K bank at $8000, last bank fixed at $C000 and 8K of CHR RAM
+pub(crate) struct UxROM {
+    banks: Vec<[u8; 0x4000]>,
+    selected_bank: usize,
+    chr_ram: [u8; 0x2000],
+    // the boards have none, only there for the header's PRG RAM or the trainer
+    prg_ram: Vec<u8>,
+    mirroring: Mirroring,
+}
+
+impl Mapper for UxROM {
+    fn read(&self, address: u16) -> u8 {
+        if address >= BANK_2_OFFSET {
+            self.banks[self.banks.len() - 1][(address - BANK_2_OFFSET) as usize]
+        } else if address >= BANK_1_OFFSET {
+            self.banks[self.selected_bank][(address - BANK_1_OFFSET) as usize]
+        } else if address >= SAVE_RAM {
+            prg_ram_offset(address, self.prg_ram.len()).map_or(0, |i| self.prg_ram[i])
+        } else {
+            0
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if address >= BANK_1_OFFSET {
+            // bus conflict: the ROM drives the bus at the same time as the CPU
+            let value = value & self.read(address);
+            self.selected_bank = value as usize % self.banks.len();
+        } else if address >= SAVE_RAM {
+            if let Some(i) = prg_ram_offset(address, self.prg_ram.len()) {
+                self.prg_ram[i] = value;
+            }
+        }
+    }
+
+    fn tick(&mut self) {}
+
+    fn ppu_read(&self, address: u16, internal_vram: &[u8; 0x800]) -> u8 {
+        if address < 0x2000 {
+            self.chr_ram[address as usize]
+        } else if address < 0x3F00 {
+            internal_vram[self.mirroring.map_nametable_address(address)]
+        } else {
+            unreachable!();
+        }
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8, internal_vram: &mut [u8; 0x800]) {
+        if address < 0x2000 {
+            self.chr_ram[address as usize] = value;
+        } else if address < 0x3F00 {
+            internal_vram[self.mirroring.map_nametable_address(address)] = value;
+        } else {
+            unreachable!();
+        }
+    }
+
+    fn cpu_byte_mut(&mut self, address: u16) -> Option<&mut u8> {
+        if address >= BANK_2_OFFSET {
+            let last = self.banks.len() - 1;
+            Some(&mut self.banks[last][(address - BANK_2_OFFSET) as usize])
+        } else if address >= BANK_1_OFFSET {
+            Some(&mut self.banks[self.selected_bank][(address - BANK_1_OFFSET) as usize])
+        } else if address >= SAVE_RAM {
+            let i = prg_ram_offset(address, self.prg_ram.len())?;
+            Some(&mut self.prg_ram[i])
+        } else {
+            None
+        }
+    }
+
+    fn prg_banks(&self) -> Vec<&[u8]> {
+        self.banks.iter().map(|b| &b[..]).collect()
+    }
+
+    fn prg_banks_mut(&mut self) -> Vec<&mut [u8]> {
+        self.banks.iter_mut().map(|b| &mut b[..]).collect()
+    }
+
+    fn chr_banks(&self) -> Vec<&[u8]> {
+        vec![&self.chr_ram]
+    }
+
+    fn chr_banks_mut(&mut self) -> Vec<&mut [u8]> {
+        vec![&mut self.chr_ram]
+    }
+
+    fn get_save_ram(&self) -> Vec<u8> {
+        self.prg_ram.to_vec()
+    }
+
+    fn set_save_ram(&mut self, data: Vec<u8>) {
+        for (i, x) in data.into_iter().take(self.prg_ram.len()).enumerate() {
+            self.prg_ram[i] = x;
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_usize(self.selected_bank);
+        writer.write_bytes(&self.chr_ram);
+        writer.write_bytes(&self.prg_ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        let selected_bank = reader.read_usize()?;
+        if selected_bank >= self.banks.len() {
+            return Err(StateError::InvalidValue("UxROM bank"));
+        }
+        self.selected_bank = selected_bank;
+        reader.read_bytes_into(&mut self.chr_ram)?;
+        // version 2 had no PRG RAM, keep the current one
+        if reader.version() >= 3 {
+            reader.read_bytes_into(&mut self.prg_ram)?;
+        }
+        Ok(())
+    }
+}
+
+impl UxROM {
+    pub(crate) fn new(
+        banks: Vec<[u8; 0x4000]>,
+        chr_ram: [u8; 0x2000],
+        prg_ram_size: usize,
+        mirroring: Mirroring,
+    ) -> Self {
+        Self {
+            banks,
+            selected_bank: 0,
+            chr_ram,
+            prg_ram: vec![0; prg_ram_size],
+            mirroring,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every byte of a bank holds the bank number, except for the last bank which is all $FF
+    fn numbered_banks(count: usize) -> Vec<[u8; 0x4000]> {
+        let mut banks: Vec<[u8; 0x4000]> = (0..count).map(|i| [i as u8; 0x4000]).collect();
+        banks[count - 1] = [0xFF; 0x4000];
+        banks
+    }
+
+    #[test]
+    fn bank_switching() {
+        let mut mapper = UxROM::new(numbered_banks(8), [0; 0x2000], 0, Mirroring::Vertical);
+        assert_eq!(mapper.read(0x8000), 0);
+        assert_eq!(mapper.read(0xC000), 0xFF);
+
+        mapper.write(0xC000, 5);
+        assert_eq!(mapper.read(0x8000), 5);
+        assert_eq!(mapper.read(0xBFFF), 5);
+        assert_eq!(mapper.read(0xFFFF), 0xFF);
+
+        // bank numbers wrap around the number of banks
+        mapper.write(0xC000, 9);
+        assert_eq!(mapper.read(0x8000), 1);
+    }
+
+    #[test]
+    fn bus_conflicts() {
+        let mut banks = numbered_banks(8);
+        banks[7][0x0010] = 0x03;
+        let mut mapper = UxROM::new(banks, [0; 0x2000], 0, Mirroring::Vertical);
+
+        // the written value is ANDed with the ROM byte at the same address
+        mapper.write(0xC010, 0x06);
+        assert_eq!(mapper.read(0x8000), 2);
+
+        mapper.write(0xC000, 0x06);
+        assert_eq!(mapper.read(0x8000), 6);
+    }
+
+    #[test]
+    fn chr_ram_is_writable() {
+        let mut mapper = UxROM::new(numbered_banks(2), [0; 0x2000], 0, Mirroring::Horizontal);
+        let mut vram = [0u8; 0x800];
+        mapper.ppu_write(0x1234, 0x42, &mut vram);
+        assert_eq!(mapper.ppu_read(0x1234, &vram), 0x42);
+
+        mapper.ppu_write(0x2400, 0x24, &mut vram);
+        assert_eq!(vram[0x000], 0x24);
+        assert_eq!(mapper.ppu_read(0x2000, &vram), 0x24);
+    }
+}
@@ -1,38 +1,84 @@
+pub mod database;
+pub mod disk;
+pub mod header;
 mod mappers;
+pub mod patch;
+pub mod unif;
+mod zip;
 
+pub use database::GameInfo;
+pub use header::{RomError, RomHeader};
+
+use header::{HEADER_SIZE, TRAINER_SIZE};
+
+use crate::state::{StateError, StateReader, StateWriter};
 use mappers::Mapper;
 
+/// Stands in for the iNES header of FDS cartridges, the image is in `data` followed by
+/// the BIOS
+const FDS_MAGIC: &[u8] = b"FDS\x1A";
+
 const BANK_1_OFFSET: u16 = 0x8000;
 const BANK_2_OFFSET: u16 = 0xC000;
 
 pub struct Cartridge {
     header: [u8; 16],
     data: Vec<u8>,
+    /// Database entry of the game, its fields override the header
+    game: Option<&'static GameInfo>,
     mapper: Box<dyn Mapper>,
 }
 
 impl Cartridge {
-    pub(crate) fn new(header: [u8; 16], data: Vec<u8>) -> Self {
+    pub(crate) fn new(header: [u8; 16], data: Vec<u8>) -> Result<Self, RomError> {
+        let mut rom_header = RomHeader::parse(&header)?;
+        if rom_header.prg_rom_size == 0 || !rom_header.prg_rom_size.is_multiple_of(0x4000) {
+            return Err(RomError::InvalidPrgSize(rom_header.prg_rom_size));
+        }
+        if !rom_header.chr_rom_size.is_multiple_of(0x2000) {
+            return Err(RomError::InvalidChrSize(rom_header.chr_rom_size));
+        }
+        if data.len() < rom_header.data_size() {
+            return Err(RomError::Truncated {
+                expected: rom_header.data_size(),
+                found: data.len(),
+            });
+        }
+
+        let rom_start = if rom_header.trainer { TRAINER_SIZE } else { 0 };
+        let game = database::lookup(crate::utils::crc32(&data[rom_start..rom_header.data_size()]));
+        if let Some(game) = game {
+            game.fix_header(&mut rom_header);
+        }
+
         let header_bac = header.clone();
         let data_bac = data.clone();
-        let mapper_low = (header[6] & 248) >> 4;
-        let mapper_high = header[7] & 248; // 4 highest bits
-        let mapper = mapper_high | mapper_low;
+        let mapper = rom_header.mapper;
 
-        let mirroring_bit = header[6] & 1;
-        let mirroring = if mirroring_bit == 0 {
-            mappers::Mirroring::Horizontal
-        } else {
+        let mirroring = if rom_header.vertical_mirroring {
             mappers::Mirroring::Vertical
+        } else {
+            mappers::Mirroring::Horizontal
         };
 
-        let prg_rom_banks = header[4];
-        let chr_rom_banks = header[5] * 2; // Treat banks as 4k banks
+        let mut prg_ram_size = rom_header.prg_ram_size + rom_header.prg_nvram_size;
+        if rom_header.trainer {
+            // the trainer goes to $7000
+            prg_ram_size = prg_ram_size.max(0x2000);
+        }
+
+        let prg_rom_banks = rom_header.prg_rom_size / 0x4000;
+        let chr_rom_banks = rom_header.chr_rom_size / 0x1000; // Treat banks as 4k banks
 
         let mut prg_banks = Vec::new();
         let mut chr_banks = Vec::new();
 
         let mut data_iter = data.into_iter();
+        let trainer: Vec<u8> = if rom_header.trainer {
+            data_iter.by_ref().take(TRAINER_SIZE).collect()
+        } else {
+            vec![]
+        };
 
         let mut buffer = [0u8; 0x4000];
         for _ in 0..prg_rom_banks {
@@ -65,31 +111,24 @@ impl Cartridge {
         //prg_banks.push(buffer);
         //}
 
-        match mapper {
+        let mut cartridge = match mapper {
             0 => {
+                // boards without CHR ROM come with 8K of CHR RAM
                 let mut chr_bank = [0u8; 0x2000];
-                let mut i = 0;
-                for j in 0..0x1000 {
-                    chr_bank[i] = chr_banks[0][j];
-                    i += 1;
+                for (i, bank) in chr_banks.iter().take(2).enumerate() {
+                    chr_bank[i * 0x1000..(i + 1) * 0x1000].copy_from_slice(bank);
                 }
-                for j in 0..0x1000 {
-                    chr_bank[i] = chr_banks[1][j];
-                    i += 1;
-                }
-                
+
                 if prg_banks.len() == 1 {
                     Cartridge {
                         header: header_bac,
                         data: data_bac,
+                        game,
                         mapper: Box::new(mappers::NROM::new(
                             true,
                             [prg_banks[0], [0u8; 0x4000]],
-                            if chr_rom_banks > 0 {
-                                chr_bank
-                            } else {
-                                [0u8; 0x2000]
-                            },
+                            chr_bank,
+                            prg_ram_size,
                             mirroring,
                         )),
                     }
@@ -97,14 +136,12 @@ impl Cartridge {
                     Cartridge {
                         header: header_bac,
                         data: data_bac,
+                        game,
                         mapper: Box::new(mappers::NROM::new(
                             false,
                             [prg_banks[0], prg_banks[1]],
-                            if chr_rom_banks > 0 {
-                                chr_bank
-                            } else {
-                                [0u8; 0x2000]
-                            },
+                            chr_bank,
+                            prg_ram_size,
                             mirroring,
                         )),
                     }
@@ -117,24 +154,144 @@ impl Cartridge {
                     Cartridge {
                         header: header_bac,
                         data: data_bac,
-                        mapper: Box::new(mappers::MMC1::new(prg_banks, chr_banks)),
+                        game,
+                        mapper: Box::new(mappers::MMC1::new(prg_banks, chr_banks, prg_ram_size)),
                     }
                 } else {
                     Cartridge {
                         header: header_bac,
                         data: data_bac,
-                        mapper: Box::new(mappers::MMC1::new(prg_banks, chr_banks)),
+                        game,
+                        mapper: Box::new(mappers::MMC1::new(prg_banks, chr_banks, prg_ram_size)),
                     }
                 }
             }
-            _ => unimplemented!("Unimplemented mapper {}", mapper),
+            2 => {
+                // boards without CHR ROM come with 8K of CHR RAM
+                let mut chr_ram = [0u8; 0x2000];
+                for (i, bank) in chr_banks.iter().take(2).enumerate() {
+                    chr_ram[i * 0x1000..(i + 1) * 0x1000].copy_from_slice(bank);
+                }
+                Cartridge {
+                    header: header_bac,
+                    data: data_bac,
+                    game,
+                    mapper: Box::new(mappers::UxROM::new(
+                        prg_banks,
+                        chr_ram,
+                        prg_ram_size,
+                        mirroring,
+                    )),
+                }
+            }
+            3 => {
+                if chr_banks.is_empty() {
+                    return Err(RomError::MissingChrRom);
+                }
+                let chr_banks = chr_banks
+                    .chunks(2)
+                    .map(|banks| {
+                        let mut chr_bank = [0u8; 0x2000];
+                        chr_bank[..0x1000].copy_from_slice(&banks[0]);
+                        chr_bank[0x1000..].copy_from_slice(&banks[1]);
+                        chr_bank
+                    })
+                    .collect();
+                Cartridge {
+                    header: header_bac,
+                    data: data_bac,
+                    game,
+                    mapper: Box::new(mappers::CNROM::new(
+                        prg_banks,
+                        chr_banks,
+                        prg_ram_size,
+                        mirroring,
+                    )),
+                }
+            }
+            4 => {
+                let prg_rom = prg_banks.iter().flatten().copied().collect();
+                let chr = if chr_banks.is_empty() {
+                    // 8K of CHR RAM
+                    vec![0u8; 0x2000]
+                } else {
+                    chr_banks.iter().flatten().copied().collect()
+                };
+                Cartridge {
+                    header: header_bac,
+                    data: data_bac,
+                    game,
+                    mapper: Box::new(mappers::MMC3::new(
+                        prg_rom,
+                        chr,
+                        chr_banks.is_empty(),
+                        prg_ram_size,
+                        mirroring,
+                    )),
+                }
+            }
+            _ => return Err(RomError::UnsupportedMapper(mapper)),
+        };
+        for (address, value) in (0x7000..).zip(trainer) {
+            cartridge.cpu_poke(address, value);
         }
+        Ok(cartridge)
+    }
+
+    /// Loads the content of an iNES or UNIF file, or of a zip archive holding one
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, RomError> {
+        if zip::is_zip(bytes) {
+            Self::from_image(&zip::extract_rom(bytes)?)
+        } else {
+            Self::from_image(bytes)
+        }
+    }
+
+    /// Like `from_bytes`, with an IPS or BPS patch applied to the ROM file
+    pub fn from_bytes_patched(bytes: &[u8], patch: &[u8]) -> Result<Self, RomError> {
+        let patched = if zip::is_zip(bytes) {
+            patch::apply_patch(&zip::extract_rom(bytes)?, patch)?
+        } else {
+            patch::apply_patch(bytes, patch)?
+        };
+        Self::from_image(&patched)
+    }
+
+    /// Loads a Famicom Disk System image, `bios` is the 8K disksys.rom
+    pub fn from_fds(image: &[u8], bios: &[u8]) -> Result<Self, RomError> {
+        if bios.len() != disk::BIOS_SIZE {
+            return Err(RomError::InvalidBios(bios.len()));
+        }
+        let sides = disk::sides(image)?;
+        let mut data: Vec<u8> = sides.concat();
+        data.extend_from_slice(bios);
+        let mut header = [0u8; HEADER_SIZE];
+        header[..FDS_MAGIC.len()].copy_from_slice(FDS_MAGIC);
+        Ok(Self {
+            header,
+            data,
+            game: None,
+            mapper: Box::new(mappers::FDS::new(bios.to_vec(), &sides)),
+        })
+    }
+
+    fn from_image(bytes: &[u8]) -> Result<Self, RomError> {
+        if unif::is_unif(bytes) {
+            return Self::from_image(&unif::to_ines(bytes)?);
+        }
+        if bytes.len() < HEADER_SIZE {
+            return Err(RomError::TooShort(bytes.len()));
+        }
+        let mut header = [0u8; HEADER_SIZE];
+        header.copy_from_slice(&bytes[..HEADER_SIZE]);
+        Self::new(header, bytes[HEADER_SIZE..].to_vec())
     }
 
     pub fn from_vec(memory: Vec<u8>) -> Self {
         Self {
             header: [0; 16],
             data: memory.clone(),
+            game: None,
             mapper: Box::new(mappers::FromVec::new(memory)),
         }
     }
@@ -143,10 +300,89 @@ impl Cartridge {
         Self {
             header: [1; 16],
             data: Vec::new(),
+            game: None,
             mapper: Box::new(mappers::Empty {}),
         }
     }
 
+    /// 16K NROM, seen at both $8000 and $C000, starting with `prg` and ending with the
+    /// NMI, reset and IRQ `vectors`. Its 8K CHR bank starts with `chr`.
+    #[cfg(test)]
+    pub(crate) fn test_nrom(prg: &[u8], vectors: [u16; 3], chr: &[u8]) -> Self {
+        let mut data = vec![0u8; 0x4000 + 0x2000];
+        data[..prg.len()].copy_from_slice(prg);
+        for (i, vector) in vectors.iter().enumerate() {
+            data[0x3FFA + i * 2..0x3FFC + i * 2].copy_from_slice(&vector.to_le_bytes());
+        }
+        data[0x4000..0x4000 + chr.len()].copy_from_slice(chr);
+
+        let mut header = [0u8; 16];
+        header[..6].copy_from_slice(b"NES\x1A\x01\x01");
+        Self::new(header, data).unwrap()
+    }
+
+    /// `test_nrom` running `program` from $C000, with an RTI at $D000 for the NMIs and IRQs
+    #[cfg(test)]
+    pub(crate) fn test_program(program: &[u8]) -> Self {
+        let mut prg = vec![0u8; 0x1001];
+        prg[..program.len()].copy_from_slice(program);
+        prg[0x1000] = 0x40;
+        Self::test_nrom(&prg, [0xD000, 0xC000, 0xD000], &[])
+    }
+
+    /// Header of the ROM file with the database fixes applied, errors for cartridges that
+    /// didn't come from one
+    pub fn header(&self) -> Result<RomHeader, RomError> {
+        let mut header = RomHeader::parse(&self.header)?;
+        if let Some(game) = self.game {
+            game.fix_header(&mut header);
+        }
+        Ok(header)
+    }
+
+    /// The game as recognized by the database
+    pub fn game(&self) -> Option<&'static GameInfo> {
+        self.game
+    }
+
+    /// PRG ROM as stored in the file, bank after bank
+    pub fn prg_rom(&self) -> &[u8] {
+        let size = self.header().map_or(0, |h| h.prg_rom_size).min(self.data.len());
+        &self.data[..size]
+    }
+
+    /// Patches the byte mapped at `address`, the mapper registers don't see the write
+    pub(crate) fn cpu_poke(&mut self, address: u16, value: u8) {
+        if let Some(byte) = self.mapper.cpu_byte_mut(address) {
+            *byte = value;
+        }
+    }
+
+    pub(crate) fn prg_rom_size(&self) -> usize {
+        self.mapper.prg_banks().iter().map(|b| b.len()).sum()
+    }
+
+    /// Byte `offset` of the PRG ROM loaded by the mapper, patches included
+    pub(crate) fn peek_prg(&self, offset: usize) -> Option<u8> {
+        bank_byte(self.mapper.prg_banks(), offset).copied()
+    }
+
+    pub(crate) fn poke_prg(&mut self, offset: usize, value: u8) -> bool {
+        poke_bank_byte(self.mapper.prg_banks_mut(), offset, value)
+    }
+
+    pub(crate) fn chr_size(&self) -> usize {
+        self.mapper.chr_banks().iter().map(|b| b.len()).sum()
+    }
+
+    pub(crate) fn peek_chr(&self, offset: usize) -> Option<u8> {
+        bank_byte(self.mapper.chr_banks(), offset).copied()
+    }
+
+    pub(crate) fn poke_chr(&mut self, offset: usize, value: u8) -> bool {
+        poke_bank_byte(self.mapper.chr_banks_mut(), offset, value)
+    }
+
     pub(crate) fn cpu_read(&self, address: u16) -> u8 {
         self.mapper.read(address)
     }
@@ -159,20 +395,194 @@ impl Cartridge {
     pub(crate) fn ppu_write(&mut self, address: u16, value: u8, internal_vram: &mut [u8; 0x800]) {
         self.mapper.ppu_write(address, value, internal_vram)
     }
+    pub(crate) fn ppu_address(&mut self, address: u16) {
+        self.mapper.ppu_address(address)
+    }
+    pub(crate) fn irq(&self) -> bool {
+        self.mapper.irq()
+    }
+    pub(crate) fn cpu_bus_read(&mut self, address: u16) -> u8 {
+        self.mapper.bus_read(address)
+    }
+    pub(crate) fn audio_output(&self) -> f32 {
+        self.mapper.audio_output()
+    }
     pub(crate) fn tick(&mut self) {
         self.mapper.tick()
     }
+    /// Whether the PRG RAM keeps its content when the console is off, FDS disks can
+    /// always be written
+    pub fn has_battery(&self) -> bool {
+        self.header().is_ok_and(|h| h.battery) || self.mapper.disk_system().is_some()
+    }
+    /// Number of disk sides, 0 for cartridges
+    pub(crate) fn disk_sides(&self) -> usize {
+        self.mapper.disk_system().map_or(0, |fds| fds.sides())
+    }
+    pub(crate) fn disk_side(&self) -> Option<usize> {
+        self.mapper.disk_system().and_then(|fds| fds.side())
+    }
+    pub(crate) fn insert_disk(&mut self, side: usize) -> bool {
+        self.mapper
+            .disk_system_mut()
+            .is_some_and(|fds| fds.insert(side))
+    }
+    pub(crate) fn eject_disk(&mut self) {
+        if let Some(fds) = self.mapper.disk_system_mut() {
+            fds.eject();
+        }
+    }
+    /// Disk content in the `.fds` format, `None` for cartridges
+    pub(crate) fn disk_image(&self) -> Option<Vec<u8>> {
+        self.mapper.disk_system().map(|fds| fds.disk_image())
+    }
+    pub(crate) fn load_disk_image(&mut self, image: &[u8]) -> bool {
+        self.mapper
+            .disk_system_mut()
+            .is_some_and(|fds| fds.load_disk_image(image))
+    }
     pub(crate) fn get_save_data(&self) -> Vec<u8> {
         self.mapper.as_ref().get_save_ram()
     }
     pub(crate) fn set_save_data(&mut self, data: Vec<u8>) {
         self.mapper.as_mut().set_save_ram(data)
     }
+    /// CRC-32 of the header and ROM data, identifies the game a save state belongs to
+    pub(crate) fn fingerprint(&self) -> u32 {
+        let crc = crate::utils::crc32(&self.header);
+        crate::utils::crc32_update(crc, &self.data)
+    }
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        self.mapper.save_state(writer)
+    }
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.mapper.load_state(reader)
+    }
     pub(crate) fn reset(&mut self) {
+        if self.header.starts_with(FDS_MAGIC) {
+            // the disk keeps what was written to it
+            let disk = self.disk_image();
+            let (image, bios) = self.data.split_at(self.data.len() - disk::BIOS_SIZE);
+            *self = Self::from_fds(image, bios).expect("the cartridge was loaded from the same image");
+            if let Some(disk) = disk {
+                self.load_disk_image(&disk);
+            }
+            return;
+        }
         match self.header[0] {
             0 => *self = Self::from_vec(self.data.clone()),
             1 => *self = Self::empty(),
-            _ => *self = Self::new(self.header.clone(), self.data.clone()),
+            _ => {
+                *self = Self::new(self.header, self.data.clone())
+                    .expect("the cartridge was loaded from the same header")
+            }
+        }
+    }
+}
+
+/// Byte `offset` of `banks` laid out one after the other
+fn bank_byte(banks: Vec<&[u8]>, mut offset: usize) -> Option<&u8> {
+    for bank in banks {
+        if offset < bank.len() {
+            return bank.get(offset);
+        }
+        offset -= bank.len();
+    }
+    None
+}
+
+fn poke_bank_byte(banks: Vec<&mut [u8]>, mut offset: usize, value: u8) -> bool {
+    for bank in banks {
+        if offset < bank.len() {
+            bank[offset] = value;
+            return true;
+        }
+        offset -= bank.len();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `prg_banks` of 16K PRG ROM and one 8K CHR bank after `header[4..]`
+    fn cartridge(header_bytes: &[u8], prg_banks: u8, trainer: Option<&[u8]>) -> Cartridge {
+        let mut header = [0u8; 16];
+        header[..4].copy_from_slice(b"NES\x1A");
+        header[4] = prg_banks;
+        header[5] = 1;
+        header[6..6 + header_bytes.len()].copy_from_slice(header_bytes);
+
+        let mut data = trainer.map_or(vec![], |t| t.to_vec());
+        data.extend(vec![0u8; prg_banks as usize * 0x4000 + 0x2000]);
+        Cartridge::new(header, data).unwrap()
+    }
+
+    #[test]
+    fn trainer() {
+        let trainer: Vec<u8> = (0..TRAINER_SIZE).map(|i| i as u8).collect();
+        let cartridge = cartridge(&[0x04], 1, Some(&trainer));
+        assert_eq!(cartridge.cpu_read(0x7000), 0);
+        assert_eq!(cartridge.cpu_read(0x71FF), 0xFF);
+        // the PRG ROM starts after the trainer
+        assert_eq!(cartridge.cpu_read(0x8000), 0);
+    }
+
+    #[test]
+    fn trainer_without_prg_ram_on_the_board() {
+        let trainer: Vec<u8> = (0..TRAINER_SIZE).map(|i| i as u8 ^ 0x5A).collect();
+        // UxROM and CNROM, NES 2.0 headers without PRG RAM
+        for &flags in [0x24, 0x34].iter() {
+            let mut cartridge = cartridge(&[flags, 0x08], 2, Some(&trainer));
+            assert_eq!(cartridge.get_save_data().len(), 0x2000);
+            assert_eq!(cartridge.cpu_read(0x7000), 0x5A);
+            assert_eq!(cartridge.cpu_read(0x71FF), 0xA5);
+            cartridge.cpu_write(0x6000, 0x42);
+            assert_eq!(cartridge.cpu_read(0x6000), 0x42);
+        }
+
+        let uxrom = cartridge(&[0x20, 0x08], 2, None);
+        assert!(uxrom.get_save_data().is_empty());
+        assert_eq!(uxrom.cpu_read(0x7000), 0);
+    }
+
+    #[test]
+    fn prg_ram_size() {
+        // iNES files get 8K unless they ask for more
+        assert_eq!(cartridge(&[0x10], 2, None).get_save_data().len(), 0x2000);
+        assert_eq!(cartridge(&[0x10, 0, 2], 2, None).get_save_data().len(), 0x4000);
+
+        // NES 2.0 MMC1 with 2K of battery RAM, mirrored over $6000-$7FFF
+        let mut mmc1 = cartridge(&[0x12, 0x08, 0, 0, 0x50], 2, None);
+        assert_eq!(mmc1.get_save_data().len(), 0x800);
+        mmc1.cpu_write(0x6001, 0xAB);
+        assert_eq!(mmc1.cpu_read(0x7801), 0xAB);
+
+        // NES 2.0 NROM without PRG RAM
+        let nrom = cartridge(&[0x00, 0x08], 1, None);
+        assert!(nrom.get_save_data().is_empty());
+        assert_eq!(nrom.cpu_read(0x6000), 0);
+    }
+
+    #[test]
+    fn battery() {
+        assert!(cartridge(&[0x02], 1, None).has_battery());
+        assert!(!cartridge(&[0x00], 1, None).has_battery());
+        assert!(!Cartridge::empty().has_battery());
+    }
+
+    #[test]
+    fn chr_ram() {
+        // NROM and UxROM without CHR ROM
+        for mapper in [0x00, 0x20].iter() {
+            let mut header = [0u8; 16];
+            header[..6].copy_from_slice(b"NES\x1A\x02\x00");
+            header[6] = *mapper;
+            let mut cartridge = Cartridge::new(header, vec![0; 0x8000]).unwrap();
+            let mut vram = [0; 0x800];
+            cartridge.ppu_write(0x1FFF, 0xAB, &mut vram);
+            assert_eq!(cartridge.ppu_read(0x1FFF, &vram), 0xAB);
         }
     }
 }
@@ -0,0 +1,363 @@
+//! IPS and BPS soft patches, applied to the whole ROM file (header included) before loading
+
+use super::RomError;
+use crate::utils::crc32;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+/// Source, target and patch CRC-32s
+const BPS_FOOTER_SIZE: usize = 12;
+
+/// Applies an IPS or BPS patch, told apart by their magic bytes
+pub fn apply_patch(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, RomError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else {
+        Err(invalid("unknown patch format"))
+    }
+}
+
+/// IPS records overwrite or fill ranges of the ROM, growing it if needed. IPS has no
+/// checksums, patching the wrong ROM goes unnoticed.
+pub fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, RomError> {
+    if !patch.starts_with(IPS_MAGIC) {
+        return Err(invalid("not an IPS patch"));
+    }
+    let mut out = rom.to_vec();
+    let mut reader = PatchReader::new(&patch[IPS_MAGIC.len()..]);
+    loop {
+        let offset = reader.bytes(3)?;
+        if offset == IPS_EOF {
+            break;
+        }
+        let offset = be(offset);
+        let size = be(reader.bytes(2)?);
+        let (len, data) = if size == 0 {
+            // run length encoded record
+            let len = be(reader.bytes(2)?);
+            (len, None)
+        } else {
+            (size, Some(reader.bytes(size)?))
+        };
+
+        if out.len() < offset + len {
+            out.resize(offset + len, 0);
+        }
+        match data {
+            Some(data) => out[offset..offset + len].copy_from_slice(data),
+            None => {
+                let value = reader.byte()?;
+                out[offset..offset + len]
+                    .iter_mut()
+                    .for_each(|b| *b = value);
+            }
+        }
+    }
+    // optional truncation extension
+    if let Ok(size) = reader.bytes(3) {
+        out.truncate(be(size));
+    }
+    Ok(out)
+}
+
+/// BPS patches describe the whole target file and carry the CRC-32 of the source they
+/// were made for
+pub fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, RomError> {
+    if !patch.starts_with(BPS_MAGIC) || patch.len() < BPS_MAGIC.len() + BPS_FOOTER_SIZE {
+        return Err(invalid("not a BPS patch"));
+    }
+    let footer = patch.len() - BPS_FOOTER_SIZE;
+    let source_crc = le_u32(&patch[footer..]);
+    let target_crc = le_u32(&patch[footer + 4..]);
+    let patch_crc = le_u32(&patch[footer + 8..]);
+    if crc32(&patch[..footer + 8]) != patch_crc {
+        return Err(invalid("corrupt BPS patch"));
+    }
+    let found = crc32(rom);
+    if found != source_crc {
+        return Err(RomError::PatchSourceMismatch {
+            expected: source_crc,
+            found,
+        });
+    }
+
+    let mut reader = PatchReader::new(&patch[BPS_MAGIC.len()..footer]);
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    let metadata_size = reader.number()?;
+    reader.bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(invalid("BPS patch made for a ROM of a different size"));
+    }
+
+    // target_size comes from the patch, the target grows as actions fill it
+    let mut target = Vec::new();
+    let mut source_offset = 0usize;
+    let mut target_offset = 0usize;
+    while !reader.is_empty() {
+        let action = reader.number()?;
+        let length = (action >> 2) + 1;
+        if length > target_size - target.len() {
+            return Err(out_of_range());
+        }
+        match action & 3 {
+            // source read, from the same offset as the output
+            0 => {
+                let start = target.len();
+                let data = rom.get(start..start + length).ok_or_else(out_of_range)?;
+                target.extend_from_slice(data);
+            }
+            // target read, from the patch
+            1 => target.extend_from_slice(reader.bytes(length)?),
+            // source copy
+            2 => {
+                source_offset = relative_offset(source_offset, reader.number()?)?;
+                let data = source_offset
+                    .checked_add(length)
+                    .and_then(|end| rom.get(source_offset..end))
+                    .ok_or_else(out_of_range)?;
+                target.extend_from_slice(data);
+                source_offset += length;
+            }
+            // target copy, byte by byte as the ranges can overlap
+            _ => {
+                target_offset = relative_offset(target_offset, reader.number()?)?;
+                for _ in 0..length {
+                    let byte = *target.get(target_offset).ok_or_else(out_of_range)?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if target.len() != target_size || crc32(&target) != target_crc {
+        return Err(invalid("BPS patch produced a corrupt ROM"));
+    }
+    Ok(target)
+}
+
+struct PatchReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], RomError> {
+        let bytes = self
+            .position
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.position..end))
+            .ok_or_else(|| invalid("truncated patch"))?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, RomError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// BPS variable length number, 7 bits per byte with the last byte flagged by bit 7
+    fn number(&mut self) -> Result<usize, RomError> {
+        let mut value = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let byte = self.byte()?;
+            value = (byte as usize & 0x7F)
+                .checked_mul(shift)
+                .and_then(|v| v.checked_add(value))
+                .ok_or_else(out_of_range)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(0x80).ok_or_else(out_of_range)?;
+            value = value.checked_add(shift).ok_or_else(out_of_range)?;
+        }
+    }
+}
+
+/// Moves `offset` by a BPS signed delta, the sign is in bit 0
+fn relative_offset(offset: usize, delta: usize) -> Result<usize, RomError> {
+    let result = if delta & 1 != 0 {
+        offset.checked_sub(delta >> 1)
+    } else {
+        offset.checked_add(delta >> 1)
+    };
+    result.ok_or_else(out_of_range)
+}
+
+fn be(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |value, &b| value << 8 | b as usize)
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn invalid(message: &str) -> RomError {
+    RomError::InvalidPatch(message.to_string())
+}
+
+fn out_of_range() -> RomError {
+    invalid("BPS patch reads outside of its data")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ips() {
+        let mut patch = b"PATCH".to_vec();
+        // 2 bytes at $000001
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+        // 3 times $CC at $000006, past the end
+        patch.extend_from_slice(&[0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x03, 0xCC]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(
+            apply_patch(&[0, 1, 2, 3, 4], &patch),
+            Ok(vec![0, 0xAA, 0xBB, 3, 4, 0, 0xCC, 0xCC, 0xCC])
+        );
+
+        // truncation
+        patch.extend_from_slice(&[0x00, 0x00, 0x04]);
+        assert_eq!(
+            apply_ips(&[0, 1, 2, 3, 4], &patch),
+            Ok(vec![0, 0xAA, 0xBB, 3])
+        );
+
+        assert_eq!(
+            apply_ips(&[0], b"PATCH\x00\x00\x01\x00\x04\xAA"),
+            Err(invalid("truncated patch"))
+        );
+    }
+
+    fn number(mut value: usize, out: &mut Vec<u8>) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte | 0x80);
+                return;
+            }
+            out.push(byte);
+            value -= 1;
+        }
+    }
+
+    /// BPS patch from `source` to `target` made of `actions`
+    fn bps(source: &[u8], target: &[u8], actions: &[u8]) -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        number(source.len(), &mut patch);
+        number(target.len(), &mut patch);
+        number(4, &mut patch);
+        patch.extend_from_slice(b"meta");
+        patch.extend_from_slice(actions);
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let crc = crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    fn action(command: usize, length: usize, out: &mut Vec<u8>) {
+        number((length - 1) << 2 | command, out);
+    }
+
+    #[test]
+    fn bps_actions() {
+        let source = b"0123456789";
+        let target = b"01xy2301230123";
+        let mut actions = Vec::new();
+        // "01" from the source
+        action(0, 2, &mut actions);
+        // "xy" from the patch
+        action(1, 2, &mut actions);
+        actions.extend_from_slice(b"xy");
+        // "23" from source offset 2
+        action(2, 2, &mut actions);
+        number(2 << 1, &mut actions);
+        // "01" from source offset 0, two back from where the last copy ended
+        action(2, 2, &mut actions);
+        number(4 << 1 | 1, &mut actions);
+        // "230123" repeating the target from offset 4
+        action(3, 6, &mut actions);
+        number(4 << 1, &mut actions);
+
+        let patch = bps(source, target, &actions);
+        assert_eq!(apply_patch(source, &patch), Ok(target.to_vec()));
+
+        assert_eq!(
+            apply_bps(b"9876543210", &patch),
+            Err(RomError::PatchSourceMismatch {
+                expected: crc32(source),
+                found: crc32(b"9876543210"),
+            })
+        );
+
+        let mut corrupt = patch.clone();
+        corrupt[8] ^= 1;
+        assert_eq!(
+            apply_bps(source, &corrupt),
+            Err(invalid("corrupt BPS patch"))
+        );
+    }
+
+    #[test]
+    fn bps_sizes() {
+        let source = b"0123";
+        // a target size far too large to allocate up front
+        let mut patch = b"BPS1".to_vec();
+        number(source.len(), &mut patch);
+        number(usize::MAX >> 8, &mut patch);
+        number(0, &mut patch);
+        action(1, 1, &mut patch);
+        patch.push(b'x');
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&0u32.to_le_bytes());
+        let crc = crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        assert_eq!(
+            apply_bps(source, &patch),
+            Err(invalid("BPS patch produced a corrupt ROM"))
+        );
+
+        // a target copy running past the target size
+        let mut actions = Vec::new();
+        action(1, 1, &mut actions);
+        actions.push(b'x');
+        action(3, 1000, &mut actions);
+        number(0, &mut actions);
+        assert_eq!(
+            apply_bps(source, &bps(source, b"xx", &actions)),
+            Err(out_of_range())
+        );
+    }
+
+    #[test]
+    fn patched_cartridges() {
+        let mut rom = b"NES\x1A\x01\x01".to_vec();
+        rom.resize(16 + 0x4000 + 0x2000, 0);
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x10, 0x00, 0x01, 0xEA]);
+        patch.extend_from_slice(b"EOF");
+
+        let cartridge = crate::Cartridge::from_bytes_patched(&rom, &patch).unwrap();
+        assert_eq!(cartridge.cpu_read(0x8000), 0xEA);
+        assert_eq!(
+            crate::Cartridge::from_bytes_patched(&rom, b"nonsense").err(),
+            Some(invalid("unknown patch format"))
+        );
+    }
+}
@@ -0,0 +1,212 @@
+//! UNIF images: a board name and PRG/CHR chunks instead of an iNES header. They are
+//! rebuilt into iNES images so they load like any other ROM.
+
+use super::header::HEADER_SIZE;
+use super::RomError;
+
+const MAGIC: &[u8] = b"UNIF";
+const UNIF_HEADER_SIZE: usize = 32;
+const CHUNK_HEADER_SIZE: usize = 8;
+
+pub(crate) fn is_unif(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// iNES mapper of a UNIF board name, with or without the `NES-`, `UNL-`... prefix
+pub fn board_mapper(board: &str) -> Option<u16> {
+    let name = board.split_once('-').map_or(
+        board,
+        |(prefix, name)| {
+            if prefix.len() == 3 {
+                name
+            } else {
+                board
+            }
+        },
+    );
+    match name {
+        "NROM" | "NROM-128" | "NROM-256" | "RROM" | "RROM-128" => Some(0),
+        "SAROM" | "SBROM" | "SCROM" | "SEROM" | "SFROM" | "SGROM" | "SHROM" | "SJROM" | "SKROM"
+        | "SLROM" | "SL1ROM" | "SNROM" | "SOROM" | "SUROM" | "SXROM" => Some(1),
+        "UNROM" | "UOROM" => Some(2),
+        "CNROM" => Some(3),
+        "TBROM" | "TEROM" | "TFROM" | "TGROM" | "TKROM" | "TLROM" | "TL1ROM" | "TNROM"
+        | "TSROM" => Some(4),
+        _ => None,
+    }
+}
+
+/// Rebuilds the iNES image of a UNIF file
+pub(crate) fn to_ines(data: &[u8]) -> Result<Vec<u8>, RomError> {
+    if data.len() < UNIF_HEADER_SIZE {
+        return Err(RomError::TooShort(data.len()));
+    }
+
+    let mut board = None;
+    let mut prg_chunks: [Option<&[u8]>; 16] = [None; 16];
+    let mut chr_chunks: [Option<&[u8]>; 16] = [None; 16];
+    let mut mirroring = 0;
+    let mut battery = false;
+
+    let mut position = UNIF_HEADER_SIZE;
+    while position + CHUNK_HEADER_SIZE <= data.len() {
+        let id = &data[position..position + 4];
+        let length_bytes = &data[position + 4..position + 8];
+        let length = u32::from_le_bytes([
+            length_bytes[0],
+            length_bytes[1],
+            length_bytes[2],
+            length_bytes[3],
+        ]) as usize;
+        let start = position + CHUNK_HEADER_SIZE;
+        let chunk = data.get(start..start + length).ok_or(RomError::Truncated {
+            expected: start + length,
+            found: data.len(),
+        })?;
+
+        match id {
+            b"MAPR" => {
+                let name = chunk.split(|b| *b == 0).next().unwrap_or(&[]);
+                board = Some(String::from_utf8_lossy(name).trim().to_string());
+            }
+            b"MIRR" => mirroring = chunk.first().copied().unwrap_or(0),
+            b"BATR" => battery = chunk.first().is_none_or(|b| *b != 0),
+            [b'P', b'R', b'G', n] => {
+                if let Some(i) = (*n as char).to_digit(16) {
+                    prg_chunks[i as usize] = Some(chunk);
+                }
+            }
+            [b'C', b'H', b'R', n] => {
+                if let Some(i) = (*n as char).to_digit(16) {
+                    chr_chunks[i as usize] = Some(chunk);
+                }
+            }
+            // NAME, READ, DINF, TVCI, CTRL and the checksums aren't needed to run the game
+            _ => {}
+        }
+        position = start + length;
+    }
+
+    let board = board.ok_or_else(|| RomError::InvalidUnif("no MAPR chunk".to_string()))?;
+    let mapper = board_mapper(&board).ok_or(RomError::UnsupportedBoard(board))?;
+    let prg: Vec<u8> = prg_chunks
+        .iter()
+        .flatten()
+        .flat_map(|c| c.iter())
+        .copied()
+        .collect();
+    let chr: Vec<u8> = chr_chunks
+        .iter()
+        .flatten()
+        .flat_map(|c| c.iter())
+        .copied()
+        .collect();
+    if prg.is_empty() || !prg.len().is_multiple_of(0x4000) || prg.len() / 0x4000 > 0xFF {
+        return Err(RomError::InvalidUnif(format!(
+            "{} bytes of PRG ROM",
+            prg.len()
+        )));
+    }
+    if !chr.len().is_multiple_of(0x2000) || chr.len() / 0x2000 > 0xFF {
+        return Err(RomError::InvalidUnif(format!(
+            "{} bytes of CHR ROM",
+            chr.len()
+        )));
+    }
+
+    let mut header = [0u8; HEADER_SIZE];
+    header[..4].copy_from_slice(b"NES\x1A");
+    header[4] = (prg.len() / 0x4000) as u8;
+    header[5] = (chr.len() / 0x2000) as u8;
+    header[6] = (mapper as u8 & 0x0F) << 4;
+    match mirroring {
+        1 => header[6] |= 0x01,
+        4 => header[6] |= 0x08,
+        // horizontal, or switched by the mapper
+        _ => {}
+    }
+    if battery {
+        header[6] |= 0x02;
+    }
+    header[7] = mapper as u8 & 0xF0;
+
+    Ok([&header[..], &prg, &chr].concat())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::Cartridge;
+
+    fn chunk(id: &[u8], data: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(id);
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(data);
+    }
+
+    /// UNIF file for `board` with `prg_size` bytes of PRG ROM
+    pub(crate) fn unif(board: &str, prg_size: usize) -> Vec<u8> {
+        let mut data = b"UNIF".to_vec();
+        data.extend_from_slice(&7u32.to_le_bytes());
+        data.resize(UNIF_HEADER_SIZE, 0);
+        chunk(b"NAME", b"Homebrew\0", &mut data);
+        chunk(b"MAPR", format!("{}\0", board).as_bytes(), &mut data);
+        chunk(b"MIRR", &[1], &mut data);
+        chunk(b"BATR", &[1], &mut data);
+        // PRG split in two chunks
+        let mut prg = vec![0u8; prg_size / 2];
+        prg[0] = 0xEA;
+        chunk(b"PRG0", &prg, &mut data);
+        prg[0] = 0x60;
+        chunk(b"PRG1", &prg, &mut data);
+        chunk(b"CHR0", &[0; 0x2000], &mut data);
+        data
+    }
+
+    #[test]
+    fn boards() {
+        assert_eq!(board_mapper("NES-NROM-256"), Some(0));
+        assert_eq!(board_mapper("NROM-128"), Some(0));
+        assert_eq!(board_mapper("NES-SLROM"), Some(1));
+        assert_eq!(board_mapper("UNL-UNROM"), Some(2));
+        assert_eq!(board_mapper("HVC-TLROM"), Some(4));
+        assert_eq!(board_mapper("BMC-Super24in1SC03"), None);
+    }
+
+    #[test]
+    fn loads_unif() {
+        let cartridge = Cartridge::from_bytes(&unif("NES-NROM-256", 0x8000)).unwrap();
+        let header = cartridge.header().unwrap();
+        assert_eq!(header.mapper, 0);
+        assert_eq!(header.prg_rom_size, 0x8000);
+        assert!(header.vertical_mirroring);
+        assert!(header.battery);
+        assert_eq!(cartridge.cpu_read(0x8000), 0xEA);
+        assert_eq!(cartridge.cpu_read(0xC000), 0x60);
+
+        let cartridge = Cartridge::from_bytes(&unif("NES-SNROM", 0x20000)).unwrap();
+        assert_eq!(cartridge.header().unwrap().mapper, 1);
+    }
+
+    #[test]
+    fn unif_errors() {
+        assert_eq!(
+            to_ines(&unif("BMC-Super24in1SC03", 0x8000)),
+            Err(RomError::UnsupportedBoard("BMC-Super24in1SC03".to_string()))
+        );
+        assert_eq!(
+            to_ines(&unif("NES-NROM-128", 0x1000)),
+            Err(RomError::InvalidUnif("4096 bytes of PRG ROM".to_string()))
+        );
+
+        let data = unif("NES-NROM-256", 0x8000);
+        let truncated = &data[..data.len() - 1];
+        assert_eq!(
+            to_ines(truncated),
+            Err(RomError::Truncated {
+                expected: data.len(),
+                found: data.len() - 1,
+            })
+        );
+    }
+}
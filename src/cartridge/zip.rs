@@ -0,0 +1,474 @@
+//! Just enough of the zip format to pull a ROM out of an archive: stored and deflated
+//! entries, no encryption and no zip64
+
+use super::RomError;
+use crate::utils::crc32;
+
+const LOCAL_HEADER: &[u8] = b"PK\x03\x04";
+const CENTRAL_HEADER: &[u8] = b"PK\x01\x02";
+const END_OF_CENTRAL_DIRECTORY: &[u8] = b"PK\x05\x06";
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+pub(crate) fn is_zip(data: &[u8]) -> bool {
+    data.starts_with(LOCAL_HEADER)
+}
+
+/// File extensions of the ROMs looked for in archives
+const ROM_EXTENSIONS: [&str; 3] = [".nes", ".unf", ".unif"];
+
+/// Content of the only iNES or UNIF file in the archive
+pub(crate) fn extract_rom(archive: &[u8]) -> Result<Vec<u8>, RomError> {
+    let entries = entries(archive)?;
+    let mut roms = entries.iter().filter(|entry| {
+        let name = entry.name.to_ascii_lowercase();
+        ROM_EXTENSIONS
+            .iter()
+            .any(|extension| name.ends_with(extension))
+    });
+    let rom = roms
+        .next()
+        .ok_or_else(|| archive_error("no ROM in the archive"))?;
+    if roms.next().is_some() {
+        return Err(archive_error("more than one ROM in the archive"));
+    }
+    rom.extract(archive)
+}
+
+struct Entry {
+    name: String,
+    method: u16,
+    crc: u32,
+    compressed_size: usize,
+    size: usize,
+    local_header: usize,
+}
+
+impl Entry {
+    fn extract(&self, archive: &[u8]) -> Result<Vec<u8>, RomError> {
+        let header = self.local_header;
+        if !archive
+            .get(header..)
+            .ok_or_else(truncated)?
+            .starts_with(LOCAL_HEADER)
+        {
+            return Err(truncated());
+        }
+        let start = header
+            + 30
+            + u16_at(archive, header + 26)? as usize
+            + u16_at(archive, header + 28)? as usize;
+        let compressed = archive
+            .get(start..start + self.compressed_size)
+            .ok_or_else(truncated)?;
+
+        let data = match self.method {
+            STORED => compressed.to_vec(),
+            DEFLATED => inflate(compressed)?,
+            method => {
+                return Err(archive_error(format!(
+                    "unsupported compression method {}",
+                    method
+                )))
+            }
+        };
+        if data.len() != self.size || crc32(&data) != self.crc {
+            return Err(archive_error(format!("{} is corrupt", self.name)));
+        }
+        Ok(data)
+    }
+}
+
+/// Reads the central directory at the end of the archive
+fn entries(archive: &[u8]) -> Result<Vec<Entry>, RomError> {
+    let last = archive
+        .len()
+        .checked_sub(END_OF_CENTRAL_DIRECTORY_SIZE)
+        .ok_or_else(truncated)?;
+    // followed by a comment of up to 64K
+    let end = (last.saturating_sub(0xFFFF)..=last)
+        .rev()
+        .find(|&i| archive[i..].starts_with(END_OF_CENTRAL_DIRECTORY))
+        .ok_or_else(truncated)?;
+    let count = u16_at(archive, end + 10)?;
+    let mut position = u32_at(archive, end + 16)? as usize;
+
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        if !archive
+            .get(position..)
+            .ok_or_else(truncated)?
+            .starts_with(CENTRAL_HEADER)
+        {
+            return Err(truncated());
+        }
+        let name_len = u16_at(archive, position + 28)? as usize;
+        let name = archive
+            .get(position + 46..position + 46 + name_len)
+            .ok_or_else(truncated)?;
+        entries.push(Entry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            method: u16_at(archive, position + 10)?,
+            crc: u32_at(archive, position + 16)?,
+            compressed_size: u32_at(archive, position + 20)? as usize,
+            size: u32_at(archive, position + 24)? as usize,
+            local_header: u32_at(archive, position + 42)? as usize,
+        });
+        position += 46
+            + name_len
+            + u16_at(archive, position + 30)? as usize
+            + u16_at(archive, position + 32)? as usize;
+    }
+    Ok(entries)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, RomError> {
+    let bytes = data.get(offset..offset + 2).ok_or_else(truncated)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, RomError> {
+    let bytes = data.get(offset..offset + 4).ok_or_else(truncated)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn archive_error(message: impl Into<String>) -> RomError {
+    RomError::Archive(message.into())
+}
+
+fn truncated() -> RomError {
+    archive_error("truncated archive")
+}
+
+fn corrupt() -> RomError {
+    archive_error("corrupt deflate data")
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order the code length code lengths of dynamic blocks are stored in
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Decompresses a raw deflate stream (RFC 1951)
+pub(crate) fn inflate(data: &[u8]) -> Result<Vec<u8>, RomError> {
+    let mut input = BitReader::new(data);
+    let mut out = Vec::new();
+    loop {
+        let last = input.bits(1)? == 1;
+        match input.bits(2)? {
+            0 => stored_block(&mut input, &mut out)?,
+            1 => {
+                let (literals, distances) = fixed_codes();
+                compressed_block(&mut input, &mut out, &literals, &distances)?
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut input)?;
+                compressed_block(&mut input, &mut out, &literals, &distances)?
+            }
+            _ => return Err(corrupt()),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+/// Reads bits starting from the least significant bit of every byte
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            position: 0,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    /// Up to 16 bits
+    fn bits(&mut self, n: u32) -> Result<u32, RomError> {
+        while self.count < n {
+            let byte = *self.data.get(self.position).ok_or_else(corrupt)?;
+            self.position += 1;
+            self.buffer |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1 << n) - 1);
+        self.buffer >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Skips to the next byte boundary
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+}
+
+/// Canonical Huffman code: how many codes of every length and the symbols ordered by code
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, input: &mut BitReader) -> Result<u16, RomError> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..16 {
+            code |= input.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(corrupt())
+    }
+}
+
+fn stored_block(input: &mut BitReader, out: &mut Vec<u8>) -> Result<(), RomError> {
+    input.align();
+    let length = input.bits(16)?;
+    if input.bits(16)? != !length & 0xFFFF {
+        return Err(corrupt());
+    }
+    for _ in 0..length {
+        out.push(input.bits(8)? as u8);
+    }
+    Ok(())
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [8u8; 288];
+    lengths[144..256].iter_mut().for_each(|l| *l = 9);
+    lengths[256..280].iter_mut().for_each(|l| *l = 7);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(input: &mut BitReader) -> Result<(Huffman, Huffman), RomError> {
+    let literal_count = input.bits(5)? as usize + 257;
+    let distance_count = input.bits(5)? as usize + 1;
+    let code_length_count = input.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[symbol] = input.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+
+    let total = literal_count + distance_count;
+    let mut lengths = Vec::with_capacity(total);
+    while lengths.len() < total {
+        let (length, repeat) = match code_lengths.decode(input)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths.last().ok_or_else(corrupt)?;
+                (previous, 3 + input.bits(2)?)
+            }
+            17 => (0, 3 + input.bits(3)?),
+            _ => (0, 11 + input.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(length, repeat as usize));
+    }
+    if lengths.len() != total {
+        return Err(corrupt());
+    }
+    Ok((
+        Huffman::new(&lengths[..literal_count]),
+        Huffman::new(&lengths[literal_count..]),
+    ))
+}
+
+fn compressed_block(
+    input: &mut BitReader,
+    out: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), RomError> {
+    loop {
+        let symbol = literals.decode(input)? as usize;
+        if symbol < 256 {
+            out.push(symbol as u8);
+            continue;
+        } else if symbol == 256 {
+            return Ok(());
+        }
+
+        let i = symbol - 257;
+        if i >= LENGTH_BASE.len() {
+            return Err(corrupt());
+        }
+        let length = LENGTH_BASE[i] as usize + input.bits(LENGTH_EXTRA[i] as u32)? as usize;
+        let d = distances.decode(input)? as usize;
+        if d >= DISTANCE_BASE.len() {
+            return Err(corrupt());
+        }
+        let distance = DISTANCE_BASE[d] as usize + input.bits(DISTANCE_EXTRA[d] as u32)? as usize;
+        if distance > out.len() {
+            return Err(corrupt());
+        }
+        let start = out.len() - distance;
+        for i in 0..length {
+            out.push(out[start + i]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cartridge;
+
+    fn expected() -> Vec<u8> {
+        [b"ab".repeat(32), b"z".repeat(24)].concat()
+    }
+
+    #[test]
+    fn inflate_blocks() {
+        let fixed = [0x4B, 0x4C, 0x4A, 0xA4, 0x08, 0x56, 0xE1, 0x00, 0x00];
+        assert_eq!(inflate(&fixed), Ok(expected()));
+
+        #[rustfmt::skip]
+        let dynamic = [
+            0x05, 0xC1, 0x01, 0x01, 0x00, 0x00, 0x00, 0x82, 0xA0, 0xAD, 0x38, 0xA3, 0xF5, 0x81,
+            0x24, 0x49, 0x92, 0x24, 0x49, 0x92, 0x24, 0x49, 0x92, 0x24, 0x49, 0xD2, 0xB6, 0x6D,
+            0xDB, 0xB6, 0x6D, 0xDB, 0xB6, 0x6D, 0xDB, 0x01,
+        ];
+        assert_eq!(inflate(&dynamic), Ok(expected()));
+
+        let stored = [
+            0x01, 0x06, 0x00, 0xF9, 0xFF, b's', b't', b'o', b'r', b'e', b'd',
+        ];
+        assert_eq!(inflate(&stored), Ok(b"stored".to_vec()));
+
+        assert_eq!(inflate(&fixed[..4]), Err(corrupt()));
+    }
+
+    /// Archive with the `files` stored uncompressed
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        let mut directory = Vec::new();
+        for (name, data) in files {
+            let mut fields = Vec::new();
+            fields.extend_from_slice(&STORED.to_le_bytes());
+            fields.extend_from_slice(&[0; 4]);
+            fields.extend_from_slice(&crc32(data).to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            fields.extend_from_slice(&[0; 2]);
+
+            directory.extend_from_slice(CENTRAL_HEADER);
+            directory.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            directory.extend_from_slice(&fields);
+            directory.extend_from_slice(&[0; 10]);
+            directory.extend_from_slice(&(archive.len() as u32).to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+
+            archive.extend_from_slice(LOCAL_HEADER);
+            archive.extend_from_slice(&[20, 0, 0, 0]);
+            archive.extend_from_slice(&fields);
+            archive.extend_from_slice(name.as_bytes());
+            archive.extend_from_slice(data);
+        }
+        let directory_offset = archive.len() as u32;
+        archive.extend_from_slice(&directory);
+        archive.extend_from_slice(END_OF_CENTRAL_DIRECTORY);
+        archive.extend_from_slice(&[0; 4]);
+        archive.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        archive.extend_from_slice(&directory_offset.to_le_bytes());
+        archive.extend_from_slice(&[0; 2]);
+        archive
+    }
+
+    fn rom() -> Vec<u8> {
+        let mut rom = b"NES\x1A\x01\x01".to_vec();
+        rom.resize(16 + 0x4000 + 0x2000, 0);
+        rom[16] = 0xEA;
+        rom
+    }
+
+    #[test]
+    fn extracts_the_rom() {
+        let archive = zip(&[("readme.txt", b"hi"), ("Game.NES", &rom())]);
+        assert!(is_zip(&archive));
+        assert_eq!(extract_rom(&archive), Ok(rom()));
+
+        let cartridge = Cartridge::from_bytes(&archive).unwrap();
+        assert_eq!(cartridge.cpu_read(0x8000), 0xEA);
+
+        let unif = crate::cartridge::unif::tests::unif("NES-NROM-256", 0x8000);
+        let archive = zip(&[("Game.unf", &unif)]);
+        assert_eq!(extract_rom(&archive), Ok(unif));
+        assert!(Cartridge::from_bytes(&archive).is_ok());
+    }
+
+    #[test]
+    fn archive_errors() {
+        assert_eq!(
+            extract_rom(&zip(&[("readme.txt", b"hi")])),
+            Err(archive_error("no ROM in the archive"))
+        );
+        assert_eq!(
+            extract_rom(&zip(&[("a.nes", &rom()), ("b.nes", &rom())])),
+            Err(archive_error("more than one ROM in the archive"))
+        );
+
+        let mut archive = zip(&[("game.nes", &rom())]);
+        archive[40] ^= 0xFF;
+        assert_eq!(
+            extract_rom(&archive),
+            Err(archive_error("game.nes is corrupt"))
+        );
+        assert_eq!(extract_rom(&archive[..100]), Err(truncated()));
+    }
+}
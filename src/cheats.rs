@@ -0,0 +1,485 @@
+//! Cheats: Game Genie codes, applied to CPU reads from cartridge space without touching
+//! the ROM, and RAM freezes written to memory at the end of every frame.
+//! Cheat files use the FCEUX .cht format.
+
+const LETTERS: &[u8; 16] = b"APZLGITYEOXUKSVN";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheatError {
+    /// Game Genie codes are 6 or 8 letters long
+    InvalidLength(usize),
+    InvalidLetter(char),
+    /// Raw codes are `AAAA:VV` or `AAAA:VV:CC`, in hex
+    InvalidCode(String),
+    /// 1-based line of a cheat file
+    InvalidLine(usize),
+}
+
+impl std::fmt::Display for CheatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheatError::InvalidLength(len) => {
+                write!(f, "a Game Genie code has 6 or 8 letters, found {}", len)
+            }
+            CheatError::InvalidLetter(c) => write!(f, "'{}' is not a Game Genie letter", c),
+            CheatError::InvalidCode(code) => write!(f, "invalid cheat code {}", code),
+            CheatError::InvalidLine(line) => write!(f, "invalid cheat on line {}", line),
+        }
+    }
+}
+
+impl std::error::Error for CheatError {}
+
+/// Replaces the byte read at `address` with `value`, if `compare` is set only when the ROM
+/// holds that byte (8 letter codes), so the code only hits the right bank
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GameGenieCode {
+    pub address: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+impl GameGenieCode {
+    pub fn decode(code: &str) -> Result<Self, CheatError> {
+        let n = code
+            .chars()
+            .map(|c| {
+                LETTERS
+                    .iter()
+                    .position(|l| *l as char == c.to_ascii_uppercase())
+                    .map(|n| n as u16)
+                    .ok_or(CheatError::InvalidLetter(c))
+            })
+            .collect::<Result<Vec<u16>, _>>()?;
+        if n.len() != 6 && n.len() != 8 {
+            return Err(CheatError::InvalidLength(n.len()));
+        }
+
+        let address = 0x8000
+            | ((n[3] & 7) << 12)
+            | ((n[5] & 7) << 8)
+            | ((n[4] & 8) << 8)
+            | ((n[2] & 7) << 4)
+            | ((n[1] & 8) << 4)
+            | (n[4] & 7)
+            | (n[3] & 8);
+        let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7);
+
+        Ok(if n.len() == 6 {
+            Self {
+                address,
+                value: (value | (n[5] & 8)) as u8,
+                compare: None,
+            }
+        } else {
+            Self {
+                address,
+                value: (value | (n[7] & 8)) as u8,
+                compare: Some(
+                    (((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8)) as u8,
+                ),
+            }
+        })
+    }
+
+    /// The code for the patch, 6 letters without a compare value and 8 with one
+    pub fn encode(&self) -> String {
+        let address = self.address;
+        let value = self.value as u16;
+        let mut n = vec![
+            (value & 7) | ((value >> 4) & 8),
+            ((value >> 4) & 7) | ((address >> 4) & 8),
+            (address >> 4) & 7,
+            ((address >> 12) & 7) | (address & 8),
+            (address & 7) | ((address >> 8) & 8),
+            (address >> 8) & 7,
+        ];
+        match self.compare {
+            None => n[5] |= value & 8,
+            Some(compare) => {
+                let compare = compare as u16;
+                // tells the Game Genie to read two more letters
+                n[2] |= 8;
+                n[5] |= compare & 8;
+                n.push((compare & 7) | ((compare >> 4) & 8));
+                n.push(((compare >> 4) & 7) | (value & 8));
+            }
+        }
+        n.into_iter().map(|n| LETTERS[n as usize] as char).collect()
+    }
+
+    pub(crate) fn applies(&self, address: u16, value: u8) -> bool {
+        self.address == address && self.compare.is_none_or(|compare| compare == value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheatEffect {
+    /// Substitutes reads from cartridge space
+    Patch(GameGenieCode),
+    /// Written to CPU memory at the end of every frame, if `compare` is set only when the
+    /// memory holds that value
+    Freeze {
+        address: u16,
+        value: u8,
+        compare: Option<u8>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cheat {
+    /// The code as entered, upper case. Game Genie codes for patches, `AAAA:VV[:CC]` for
+    /// RAM freezes.
+    pub code: String,
+    /// Description from the cheat file
+    pub name: String,
+    pub effect: CheatEffect,
+    pub enabled: bool,
+}
+
+impl Cheat {
+    pub fn new(code: &str) -> Result<Self, CheatError> {
+        let code = code.trim().to_ascii_uppercase();
+        let effect = if code.contains(':') {
+            let (address, value, compare) =
+                parse_raw(&code).ok_or_else(|| CheatError::InvalidCode(code.clone()))?;
+            CheatEffect::Freeze {
+                address,
+                value,
+                compare,
+            }
+        } else {
+            CheatEffect::Patch(GameGenieCode::decode(&code)?)
+        };
+        Ok(Self {
+            code,
+            name: String::new(),
+            effect,
+            enabled: true,
+        })
+    }
+}
+
+/// Parses `AAAA:VV` or `AAAA:VV:CC`, the address has to be below the cartridge space
+fn parse_raw(code: &str) -> Option<(u16, u8, Option<u8>)> {
+    let mut parts = code.split(':');
+    let address = parse_hex(parts.next()?, 4)? as u16;
+    let value = parse_hex(parts.next()?, 2)? as u8;
+    let compare = match parts.next() {
+        Some(compare) => Some(parse_hex(compare, 2)? as u8),
+        None => None,
+    };
+    if parts.next().is_some() || address >= 0x8000 {
+        return None;
+    }
+    Some((address, value, compare))
+}
+
+fn parse_hex(s: &str, max_digits: usize) -> Option<u32> {
+    if s.is_empty() || s.len() > max_digits {
+        return None;
+    }
+    u32::from_str_radix(s, 16).ok()
+}
+
+/// Parses an FCEUX cheat file. Every line is `[S][C][:]AAAA:VV[:CC]:name`, where `S` marks
+/// a substitution of cartridge reads instead of a RAM freeze, `C` a compare value and the
+/// colon a disabled cheat.
+pub fn parse_cht(text: &str) -> Result<Vec<Cheat>, CheatError> {
+    let mut cheats = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        let cheat = parse_cht_line(line).ok_or(CheatError::InvalidLine(i + 1))?;
+        cheats.push(cheat);
+    }
+    Ok(cheats)
+}
+
+fn parse_cht_line(line: &str) -> Option<Cheat> {
+    let (substitute, line) = strip_flag(line, 'S');
+    let (has_compare, line) = strip_flag(line, 'C');
+    let (disabled, line) = strip_flag(line, ':');
+
+    let mut parts = line.splitn(if has_compare { 4 } else { 3 }, ':');
+    let address = parse_hex(parts.next()?, 4)? as u16;
+    let value = parse_hex(parts.next()?, 2)? as u8;
+    let compare = if has_compare {
+        Some(parse_hex(parts.next()?, 2)? as u8)
+    } else {
+        None
+    };
+    let name = parts.next().unwrap_or("").to_string();
+
+    let (code, effect) = if substitute {
+        // only cartridge space can be patched
+        if address < 0x8000 {
+            return None;
+        }
+        let patch = GameGenieCode {
+            address,
+            value,
+            compare,
+        };
+        (patch.encode(), CheatEffect::Patch(patch))
+    } else {
+        // freezes are written to memory, the ROM has to be patched instead
+        if address >= 0x8000 {
+            return None;
+        }
+        let code = match compare {
+            Some(compare) => format!("{:04X}:{:02X}:{:02X}", address, value, compare),
+            None => format!("{:04X}:{:02X}", address, value),
+        };
+        let effect = CheatEffect::Freeze {
+            address,
+            value,
+            compare,
+        };
+        (code, effect)
+    };
+    Some(Cheat {
+        code,
+        name,
+        effect,
+        enabled: !disabled,
+    })
+}
+
+fn strip_flag(line: &str, flag: char) -> (bool, &str) {
+    match line.strip_prefix(flag) {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    }
+}
+
+/// Value the CPU sees when reading `value` from `address` in cartridge space
+pub(crate) fn patch_read(patches: &[GameGenieCode], address: u16, value: u8) -> u8 {
+    patches
+        .iter()
+        .find(|patch| patch.applies(address, value))
+        .map_or(value, |patch| patch.value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cartridge, Nes};
+
+    #[test]
+    fn decode() {
+        assert_eq!(
+            GameGenieCode::decode("GOSSIP"),
+            Ok(GameGenieCode {
+                address: 0xD1DD,
+                value: 0x14,
+                compare: None,
+            })
+        );
+        assert_eq!(
+            GameGenieCode::decode("zexpygla"),
+            Ok(GameGenieCode {
+                address: 0x94A7,
+                value: 0x02,
+                compare: Some(0x03),
+            })
+        );
+        assert_eq!(
+            GameGenieCode::decode("GOSSI"),
+            Err(CheatError::InvalidLength(5))
+        );
+        assert_eq!(
+            GameGenieCode::decode("GOSSIB"),
+            Err(CheatError::InvalidLetter('B'))
+        );
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(
+            GameGenieCode::decode("ZEXPYGLA").unwrap().encode(),
+            "ZEXPYGLA"
+        );
+        for (address, value, compare) in [
+            (0x8000, 0x00, None),
+            (0xFFFF, 0xFF, None),
+            (0xD1DD, 0x14, None),
+            (0xA5A5, 0x5A, Some(0xC3)),
+        ]
+        .iter()
+        {
+            let patch = GameGenieCode {
+                address: *address,
+                value: *value,
+                compare: *compare,
+            };
+            assert_eq!(GameGenieCode::decode(&patch.encode()), Ok(patch));
+        }
+    }
+
+    /// 16K NROM looping on LDA $C100, STA $0200, LDA $C101, STA $0201 from $C000
+    fn nes() -> Nes {
+        let mut program = vec![0u8; 0x102];
+        program[..0x0F].copy_from_slice(&[
+            0xAD, 0x00, 0xC1, 0x8D, 0x00, 0x02, 0xAD, 0x01, 0xC1, 0x8D, 0x01, 0x02, 0x4C, 0x00,
+            0xC0,
+        ]);
+        program[0x100] = 0x11;
+        program[0x101] = 0x22;
+        Nes::with_cartridge(Cartridge::test_program(&program))
+    }
+
+    fn code(address: u16, value: u8, compare: Option<u8>) -> String {
+        GameGenieCode {
+            address,
+            value,
+            compare,
+        }
+        .encode()
+    }
+
+    #[test]
+    fn patches_cpu_reads() {
+        let mut nes = nes();
+        nes.add_cheat(&code(0xC100, 0x99, None)).unwrap();
+        // the compare value doesn't match, the read is left alone
+        nes.add_cheat(&code(0xC101, 0x88, Some(0x00))).unwrap();
+        nes.run_until_frame();
+
+        assert_eq!(nes.peek(0x0200), 0x99);
+        assert_eq!(nes.peek(0x0201), 0x22);
+        // the ROM itself is untouched
+        assert_eq!(nes.peek_prg(0x100), Some(0x11));
+        // the mirror at $8100 is a different address
+        assert_eq!(nes.peek(0x8100), 0x11);
+    }
+
+    #[test]
+    fn raw_codes() {
+        assert_eq!(
+            Cheat::new("0075:09").unwrap().effect,
+            CheatEffect::Freeze {
+                address: 0x0075,
+                value: 0x09,
+                compare: None,
+            }
+        );
+        assert_eq!(
+            Cheat::new("6001:ff:0a").unwrap().effect,
+            CheatEffect::Freeze {
+                address: 0x6001,
+                value: 0xFF,
+                compare: Some(0x0A),
+            }
+        );
+        assert_eq!(
+            Cheat::new("12345:00"),
+            Err(CheatError::InvalidCode("12345:00".to_string()))
+        );
+        assert_eq!(
+            Cheat::new("8000:ea"),
+            Err(CheatError::InvalidCode("8000:EA".to_string()))
+        );
+    }
+
+    #[test]
+    fn cht_files() {
+        let cheats = parse_cht(
+            "075a:08:Infinite lives\n\
+             C:07ed:01:00:Star power\n\
+             \n\
+             SCd1dd:14:09:Jump higher:really\n",
+        )
+        .unwrap();
+        assert_eq!(cheats.len(), 3);
+
+        assert_eq!(cheats[0].code, "075A:08");
+        assert_eq!(cheats[0].name, "Infinite lives");
+        assert!(cheats[0].enabled);
+
+        assert_eq!(
+            cheats[1].effect,
+            CheatEffect::Freeze {
+                address: 0x07ED,
+                value: 0x01,
+                compare: Some(0x00),
+            }
+        );
+        assert!(!cheats[1].enabled);
+
+        let patch = GameGenieCode {
+            address: 0xD1DD,
+            value: 0x14,
+            compare: Some(0x09),
+        };
+        assert_eq!(cheats[2].effect, CheatEffect::Patch(patch));
+        assert_eq!(cheats[2].code, patch.encode());
+        assert_eq!(cheats[2].name, "Jump higher:really");
+
+        assert_eq!(
+            parse_cht("0000:00:ok\nnonsense\n"),
+            Err(CheatError::InvalidLine(2))
+        );
+        // only substitutions can change the cartridge space
+        assert_eq!(
+            parse_cht("7fff:00:ok\nc000:ea:Patch the ROM\n"),
+            Err(CheatError::InvalidLine(2))
+        );
+        assert_eq!(parse_cht("S0100:00:RAM\n"), Err(CheatError::InvalidLine(1)));
+    }
+
+    #[test]
+    fn ram_freezes() {
+        let mut nes = nes();
+        // $0200 is rewritten by the program, the freeze wins at the end of the frame
+        nes.add_cheat("0200:42").unwrap();
+        nes.add_cheat("0300:24").unwrap();
+        nes.add_cheat("0301:24:01").unwrap();
+        nes.run_until_frame();
+        assert_eq!(nes.peek(0x0200), 0x42);
+        assert_eq!(nes.peek(0x0300), 0x24);
+        assert_eq!(nes.peek(0x0301), 0x00);
+
+        nes.poke(0x0301, 0x01);
+        nes.run_until_frame();
+        assert_eq!(nes.peek(0x0301), 0x24);
+    }
+
+    #[test]
+    fn loading_cheat_files() {
+        let mut nes = nes();
+        let path = std::env::temp_dir().join(format!("rnes-cheats-{}.cht", std::process::id()));
+        std::fs::write(&path, "0300:24:Test\n:0301:24:Disabled\n").unwrap();
+        nes.try_load_cheats(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(nes.cheats().len(), 2);
+        nes.run_until_frame();
+        assert_eq!(nes.peek(0x0300), 0x24);
+        assert_eq!(nes.peek(0x0301), 0x00);
+
+        // a missing file is not an error
+        nes.try_load_cheats("/nonexistent/rnes.cht");
+        assert_eq!(nes.cheats().len(), 2);
+    }
+
+    #[test]
+    fn toggling_cheats() {
+        let mut nes = nes();
+        let code = code(0xC101, 0x77, Some(0x22));
+        nes.add_cheat(&code).unwrap();
+        nes.run_until_frame();
+        assert_eq!(nes.peek(0x0201), 0x77);
+
+        assert!(nes.set_cheat_enabled(&code, false));
+        nes.run_until_frame();
+        assert_eq!(nes.peek(0x0201), 0x22);
+        assert!(!nes.cheats()[0].enabled);
+
+        nes.remove_cheat(&code);
+        assert!(nes.cheats().is_empty());
+        assert!(!nes.set_cheat_enabled(&code, true));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::state::{StateError, StateReader, StateWriter};
 use crate::utils::merge_u16;
 
 use super::Cpu;
@@ -284,6 +285,145 @@ impl AddressingMode {
             value: 0,
         }
     }
+
+    /// Returns the bytes and the page crossed flag (if any) holding the progress of the
+    /// addressing, in declaration order
+    fn state_fields(&mut self) -> (Vec<&mut u8>, Option<&mut bool>) {
+        match self {
+            AddressingMode::Implied
+            | AddressingMode::Accumulator
+            | AddressingMode::Immediate
+            | AddressingMode::Relative => (vec![], None),
+            AddressingMode::AbsoluteJMP { low_addr } => (vec![low_addr], None),
+            AddressingMode::AbsoluteAddr {
+                low_addr,
+                high_addr,
+            }
+            | AddressingMode::AbsoluteVal {
+                low_addr,
+                high_addr,
+            }
+            | AddressingMode::AbsoluteXVal {
+                low_addr,
+                high_addr,
+            }
+            | AddressingMode::AbsoluteYVal {
+                low_addr,
+                high_addr,
+            } => (vec![low_addr, high_addr], None),
+            AddressingMode::AbsoluteValAddr {
+                low_addr,
+                high_addr,
+                value,
+            }
+            | AddressingMode::AbsoluteXValAddr {
+                low_addr,
+                high_addr,
+                value,
+            }
+            | AddressingMode::AbsoluteYValAddr {
+                low_addr,
+                high_addr,
+                value,
+            } => (vec![low_addr, high_addr, value], None),
+            AddressingMode::ZeroPageAddr { address } | AddressingMode::ZeroPageVal { address } => {
+                (vec![address], None)
+            }
+            AddressingMode::ZeroPageValAddr { address, value } => (vec![address, value], None),
+            AddressingMode::ZeroPageXAddr {
+                address,
+                effective_address,
+            }
+            | AddressingMode::ZeroPageXVal {
+                address,
+                effective_address,
+            }
+            | AddressingMode::ZeroPageYAddr {
+                address,
+                effective_address,
+            }
+            | AddressingMode::ZeroPageYVal {
+                address,
+                effective_address,
+            } => (vec![address, effective_address], None),
+            AddressingMode::ZeroPageXValAddr {
+                address,
+                effective_address,
+                value,
+            } => (vec![address, effective_address, value], None),
+            AddressingMode::AbsoluteXAddr {
+                low_addr,
+                high_addr,
+                page_crossed,
+            }
+            | AddressingMode::AbsoluteYAddr {
+                low_addr,
+                high_addr,
+                page_crossed,
+            } => (vec![low_addr, high_addr], Some(page_crossed)),
+            AddressingMode::Indirect {
+                low_addr,
+                high_addr,
+                latch,
+            } => (vec![low_addr, high_addr, latch], None),
+            AddressingMode::IndexedIndirectAddr {
+                pointer,
+                low_addr,
+                high_addr,
+            }
+            | AddressingMode::IndexedIndirectVal {
+                pointer,
+                low_addr,
+                high_addr,
+            }
+            | AddressingMode::IndirectIndexedVal {
+                pointer,
+                low_addr,
+                high_addr,
+            } => (vec![pointer, low_addr, high_addr], None),
+            AddressingMode::IndexedIndirectValAddr {
+                pointer,
+                low_addr,
+                high_addr,
+                value,
+            } => (vec![pointer, low_addr, high_addr, value], None),
+            AddressingMode::IndirectIndexedAddr {
+                pointer,
+                low_addr,
+                high_addr,
+                page_crossed,
+            } => (vec![pointer, low_addr, high_addr], Some(page_crossed)),
+            AddressingMode::IndirectIndexedValAddr {
+                pointer,
+                low_addr,
+                high_addr,
+                page_crossed,
+                value,
+            } => (vec![pointer, low_addr, high_addr, value], Some(page_crossed)),
+        }
+    }
+
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        let mut mode = *self;
+        let (bytes, page_crossed) = mode.state_fields();
+        for byte in bytes {
+            writer.write_u8(*byte);
+        }
+        if let Some(page_crossed) = page_crossed {
+            writer.write_bool(*page_crossed);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        let (bytes, page_crossed) = self.state_fields();
+        for byte in bytes {
+            *byte = reader.read_u8()?;
+        }
+        if let Some(page_crossed) = page_crossed {
+            *page_crossed = reader.read_bool()?;
+        }
+        Ok(())
+    }
 }
 
 impl AddressingMode {
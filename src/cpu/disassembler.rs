@@ -0,0 +1,420 @@
+//! 6502 disassembler covering all 256 opcodes, unofficial ones use the same names as the emulator
+
+use crate::utils::merge_u16;
+
+#[rustfmt::skip]
+const MNEMONICS: [&str; 256] = [
+    "BRK", "ORA", "KIL", "SLO", "DOP", "ORA", "ASL", "SLO", "PHP", "ORA", "ASL", "AAC", "TOP", "ORA", "ASL", "SLO",
+    "BPL", "ORA", "KIL", "SLO", "DOP", "ORA", "ASL", "SLO", "CLC", "ORA", "NOP", "SLO", "TOP", "ORA", "ASL", "SLO",
+    "JSR", "AND", "KIL", "RLA", "BIT", "AND", "ROL", "RLA", "PLP", "AND", "ROL", "AAC", "BIT", "AND", "ROL", "RLA",
+    "BMI", "AND", "KIL", "RLA", "DOP", "AND", "ROL", "RLA", "SEC", "AND", "NOP", "RLA", "TOP", "AND", "ROL", "RLA",
+    "RTI", "EOR", "KIL", "SRE", "DOP", "EOR", "LSR", "SRE", "PHA", "EOR", "LSR", "ALR", "JMP", "EOR", "LSR", "SRE",
+    "BVC", "EOR", "KIL", "SRE", "DOP", "EOR", "LSR", "SRE", "CLI", "EOR", "NOP", "SRE", "TOP", "EOR", "LSR", "SRE",
+    "RTS", "ADC", "KIL", "RRA", "DOP", "ADC", "ROR", "RRA", "PLA", "ADC", "ROR", "ARR", "JMP", "ADC", "ROR", "RRA",
+    "BVS", "ADC", "KIL", "RRA", "DOP", "ADC", "ROR", "RRA", "SEI", "ADC", "NOP", "RRA", "TOP", "ADC", "ROR", "RRA",
+    "DOP", "STA", "DOP", "AAX", "STY", "STA", "STX", "AAX", "DEY", "DOP", "TXA", "XAA", "STY", "STA", "STX", "AAX",
+    "BCC", "STA", "KIL", "AXA", "STY", "STA", "STX", "AAX", "TYA", "STA", "TXS", "XAS", "SYA", "STA", "SXA", "AXA",
+    "LDY", "LDA", "LDX", "LAX", "LDY", "LDA", "LDX", "LAX", "TAY", "LDA", "TAX", "LAX", "LDY", "LDA", "LDX", "LAX",
+    "BCS", "LDA", "KIL", "LAX", "LDY", "LDA", "LDX", "LAX", "CLV", "LDA", "TSX", "LAR", "LDY", "LDA", "LDX", "LAX",
+    "CPY", "CMP", "DOP", "DCP", "CPY", "CMP", "DEC", "DCP", "INY", "CMP", "DEX", "AXS", "CPY", "CMP", "DEC", "DCP",
+    "BNE", "CMP", "KIL", "DCP", "DOP", "CMP", "DEC", "DCP", "CLD", "CMP", "NOP", "DCP", "TOP", "CMP", "DEC", "DCP",
+    "CPX", "SBC", "DOP", "ISC", "CPX", "SBC", "INC", "ISC", "INX", "SBC", "NOP", "SBC", "CPX", "SBC", "INC", "ISC",
+    "BEQ", "SBC", "KIL", "ISC", "DOP", "SBC", "INC", "ISC", "SED", "SBC", "NOP", "ISC", "TOP", "SBC", "INC", "ISC",
+];
+
+pub fn mnemonic(opcode: u8) -> &'static str {
+    MNEMONICS[opcode as usize]
+}
+
+/// False for the opcodes missing from the MOS documentation
+pub fn is_official(opcode: u8) -> bool {
+    match mnemonic(opcode) {
+        "AAC" | "AAX" | "ALR" | "ARR" | "AXA" | "AXS" | "DCP" | "DOP" | "ISC" | "KIL" | "LAR"
+        | "LAX" | "RLA" | "RRA" | "SLO" | "SRE" | "SXA" | "SYA" | "TOP" | "XAA" | "XAS" => false,
+        "NOP" => opcode == 0xEA,
+        "SBC" => opcode != 0xEB,
+        _ => true,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    Implied,
+    Accumulator,
+    Immediate(u8),
+    ZeroPage(u8),
+    ZeroPageX(u8),
+    ZeroPageY(u8),
+    Absolute(u16),
+    AbsoluteX(u16),
+    AbsoluteY(u16),
+    Indirect(u16),
+    IndexedIndirect(u8),
+    IndirectIndexed(u8),
+    Relative(i8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndexedIndirect,
+    IndirectIndexed,
+    Relative,
+}
+
+/// Addressing mode from the aaabbbcc layout of the opcode
+fn mode(opcode: u8) -> Mode {
+    let a = opcode >> 5;
+    let b = (opcode >> 2) & 7;
+    let c = opcode & 3;
+    // STX, LDX and their unofficial neighbours index with Y
+    let index_y = (c == 2 || c == 3) && (a == 4 || a == 5);
+    match (c, b) {
+        (0, 0) if a == 1 => Mode::Absolute,
+        (0, 0) if a < 4 => Mode::Implied,
+        (0, 0) => Mode::Immediate,
+        (0, 2) | (0, 6) | (2, 4) | (2, 6) => Mode::Implied,
+        (0, 3) if a == 3 => Mode::Indirect,
+        (0, 4) => Mode::Relative,
+        (1, 0) | (3, 0) => Mode::IndexedIndirect,
+        (1, 2) | (3, 2) => Mode::Immediate,
+        (1, 4) | (3, 4) => Mode::IndirectIndexed,
+        (1, 6) | (3, 6) => Mode::AbsoluteY,
+        (2, 0) if a < 4 => Mode::Implied,
+        (2, 0) => Mode::Immediate,
+        (2, 2) if a < 4 => Mode::Accumulator,
+        (2, 2) => Mode::Implied,
+        (_, 1) => Mode::ZeroPage,
+        (_, 3) => Mode::Absolute,
+        (_, 5) if index_y => Mode::ZeroPageY,
+        (_, 5) => Mode::ZeroPageX,
+        (_, 7) if index_y => Mode::AbsoluteY,
+        _ => Mode::AbsoluteX,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Disassembled {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub operand: Operand,
+}
+
+/// Decodes the instruction at `address`, `read` must not have side effects
+pub fn disassemble(address: u16, read: impl Fn(u16) -> u8) -> Disassembled {
+    let opcode = read(address);
+    let byte = || read(address.wrapping_add(1));
+    let word = || merge_u16(read(address.wrapping_add(1)), read(address.wrapping_add(2)));
+    let operand = match mode(opcode) {
+        Mode::Implied => Operand::Implied,
+        Mode::Accumulator => Operand::Accumulator,
+        Mode::Immediate => Operand::Immediate(byte()),
+        Mode::ZeroPage => Operand::ZeroPage(byte()),
+        Mode::ZeroPageX => Operand::ZeroPageX(byte()),
+        Mode::ZeroPageY => Operand::ZeroPageY(byte()),
+        Mode::Absolute => Operand::Absolute(word()),
+        Mode::AbsoluteX => Operand::AbsoluteX(word()),
+        Mode::AbsoluteY => Operand::AbsoluteY(word()),
+        Mode::Indirect => Operand::Indirect(word()),
+        Mode::IndexedIndirect => Operand::IndexedIndirect(byte()),
+        Mode::IndirectIndexed => Operand::IndirectIndexed(byte()),
+        Mode::Relative => Operand::Relative(byte() as i8),
+    };
+    let len = Disassembled::operand_len(&operand) + 1;
+    Disassembled {
+        address,
+        bytes: (0..len).map(|i| read(address.wrapping_add(i))).collect(),
+        operand,
+    }
+}
+
+impl Disassembled {
+    fn operand_len(operand: &Operand) -> u16 {
+        match operand {
+            Operand::Implied | Operand::Accumulator => 0,
+            Operand::Absolute(_)
+            | Operand::AbsoluteX(_)
+            | Operand::AbsoluteY(_)
+            | Operand::Indirect(_) => 2,
+            _ => 1,
+        }
+    }
+
+    pub fn opcode(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        mnemonic(self.opcode())
+    }
+
+    pub fn size(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    /// Address of the following instruction
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.size())
+    }
+
+    /// Destination of branches, JMP and JSR
+    pub fn target(&self, read: impl Fn(u16) -> u8) -> Option<u16> {
+        match (self.mnemonic(), self.operand) {
+            (_, Operand::Relative(offset)) => Some(self.next_address().wrapping_add(offset as u16)),
+            ("JMP", Operand::Absolute(address)) | ("JSR", Operand::Absolute(address)) => {
+                Some(address)
+            }
+            ("JMP", Operand::Indirect(pointer)) => Some(read_u16_same_page(pointer, read)),
+            _ => None,
+        }
+    }
+
+    /// Memory address the instruction reads or writes, with the registers it would run with
+    pub fn effective_address(&self, x: u8, y: u8, read: impl Fn(u16) -> u8) -> Option<u16> {
+        if self.target(&read).is_some() {
+            return None;
+        }
+        match self.operand {
+            Operand::ZeroPage(address) => Some(address as u16),
+            Operand::ZeroPageX(address) => Some(address.wrapping_add(x) as u16),
+            Operand::ZeroPageY(address) => Some(address.wrapping_add(y) as u16),
+            Operand::Absolute(address) => Some(address),
+            Operand::AbsoluteX(address) => Some(address.wrapping_add(x as u16)),
+            Operand::AbsoluteY(address) => Some(address.wrapping_add(y as u16)),
+            Operand::IndexedIndirect(pointer) => {
+                Some(read_u16_zero_page(pointer.wrapping_add(x), read))
+            }
+            Operand::IndirectIndexed(pointer) => {
+                Some(read_u16_zero_page(pointer, read).wrapping_add(y as u16))
+            }
+            _ => None,
+        }
+    }
+
+    /// Operand annotated like nestest.log: `($20),Y = 0300 @ 0310 = 5B`
+    pub fn resolved_operand(&self, x: u8, y: u8, read: impl Fn(u16) -> u8) -> String {
+        let effective = self.effective_address(x, y, &read);
+        let value = effective.map(&read).unwrap_or(0);
+        match self.operand {
+            Operand::ZeroPage(address) => format!("${:02X} = {:02X}", address, value),
+            Operand::ZeroPageX(address) | Operand::ZeroPageY(address) => format!(
+                "{} @ {:02X} = {:02X}",
+                self.operand_text(),
+                effective.unwrap_or(address as u16),
+                value
+            ),
+            Operand::Absolute(address) if effective.is_some() => {
+                format!("${:04X} = {:02X}", address, value)
+            }
+            Operand::AbsoluteX(_) | Operand::AbsoluteY(_) => format!(
+                "{} @ {:04X} = {:02X}",
+                self.operand_text(),
+                effective.unwrap_or(0),
+                value
+            ),
+            Operand::Indirect(pointer) => format!(
+                "(${:04X}) = {:04X}",
+                pointer,
+                self.target(&read).unwrap_or(0)
+            ),
+            Operand::IndexedIndirect(pointer) => format!(
+                "(${:02X},X) @ {:02X} = {:04X} = {:02X}",
+                pointer,
+                pointer.wrapping_add(x),
+                effective.unwrap_or(0),
+                value
+            ),
+            Operand::IndirectIndexed(pointer) => format!(
+                "(${:02X}),Y = {:04X} @ {:04X} = {:02X}",
+                pointer,
+                read_u16_zero_page(pointer, &read),
+                effective.unwrap_or(0),
+                value
+            ),
+            _ => self.operand_text(),
+        }
+    }
+
+    /// Operand without any annotation, `($20),Y`
+    pub fn operand_text(&self) -> String {
+        match self.operand {
+            Operand::Implied => String::new(),
+            Operand::Accumulator => "A".to_string(),
+            Operand::Immediate(value) => format!("#${:02X}", value),
+            Operand::ZeroPage(address) => format!("${:02X}", address),
+            Operand::ZeroPageX(address) => format!("${:02X},X", address),
+            Operand::ZeroPageY(address) => format!("${:02X},Y", address),
+            Operand::Absolute(address) => format!("${:04X}", address),
+            Operand::AbsoluteX(address) => format!("${:04X},X", address),
+            Operand::AbsoluteY(address) => format!("${:04X},Y", address),
+            Operand::Indirect(pointer) => format!("(${:04X})", pointer),
+            Operand::IndexedIndirect(pointer) => format!("(${:02X},X)", pointer),
+            Operand::IndirectIndexed(pointer) => format!("(${:02X}),Y", pointer),
+            Operand::Relative(offset) => {
+                format!("${:04X}", self.next_address().wrapping_add(offset as u16))
+            }
+        }
+    }
+
+    /// Instruction bytes in hex, `B1 20`
+    pub fn hex_bytes(&self) -> String {
+        let hex: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        hex.join(" ")
+    }
+}
+
+impl std::fmt::Display for Disassembled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let operand = self.operand_text();
+        if operand.is_empty() {
+            write!(f, "{}", self.mnemonic())
+        } else {
+            write!(f, "{} {}", self.mnemonic(), operand)
+        }
+    }
+}
+
+/// Pointers in the zero page wrap around without leaving it
+fn read_u16_zero_page(pointer: u8, read: impl Fn(u16) -> u8) -> u16 {
+    merge_u16(read(pointer as u16), read(pointer.wrapping_add(1) as u16))
+}
+
+/// JMP ($xxFF) takes the high byte from the start of the same page
+fn read_u16_same_page(pointer: u16, read: impl Fn(u16) -> u8) -> u16 {
+    let high_pointer = (pointer & 0xFF00) | (pointer.wrapping_add(1) & 0x00FF);
+    merge_u16(read(pointer), read(high_pointer))
+}
+
+/// Disassembles `data` as if it was mapped at `origin`, until the end of the data
+pub fn disassemble_block(data: &[u8], origin: u16) -> Vec<Disassembled> {
+    let read = |address: u16| {
+        data.get(address.wrapping_sub(origin) as usize)
+            .copied()
+            .unwrap_or(0)
+    };
+    let mut out = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let instruction = disassemble(origin.wrapping_add(offset as u16), read);
+        offset += instruction.size() as usize;
+        out.push(instruction);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{AddressingMode, Instruction};
+
+    fn disassemble_bytes(bytes: &[u8]) -> Disassembled {
+        disassemble_block(bytes, 0xC000).remove(0)
+    }
+
+    #[test]
+    fn formats_operands() {
+        let cases: &[(&[u8], &str)] = &[
+            (&[0xEA], "NOP"),
+            (&[0x0A], "ASL A"),
+            (&[0xA9, 0x20], "LDA #$20"),
+            (&[0xA5, 0x20], "LDA $20"),
+            (&[0xB5, 0x20], "LDA $20,X"),
+            (&[0xB6, 0x20], "LDX $20,Y"),
+            (&[0xAD, 0x34, 0x12], "LDA $1234"),
+            (&[0xBD, 0x34, 0x12], "LDA $1234,X"),
+            (&[0xBE, 0x34, 0x12], "LDX $1234,Y"),
+            (&[0x6C, 0x34, 0x12], "JMP ($1234)"),
+            (&[0xA1, 0x20], "LDA ($20,X)"),
+            (&[0xB1, 0x20], "LDA ($20),Y"),
+            (&[0xD0, 0xFE], "BNE $C000"),
+            (&[0x20, 0x00, 0x80], "JSR $8000"),
+            (&[0xB3, 0x20], "LAX ($20),Y"),
+            (&[0xCB, 0x10], "AXS #$10"),
+            (&[0x9E, 0x34, 0x12], "SXA $1234,Y"),
+            (&[0x02], "KIL"),
+        ];
+        for (bytes, text) in cases {
+            let instruction = disassemble_bytes(bytes);
+            assert_eq!(instruction.to_string(), *text);
+            assert_eq!(instruction.size() as usize, bytes.len(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn resolves_effective_addresses() {
+        let mut memory = vec![0u8; 0x10000];
+        memory[0x20] = 0x00;
+        memory[0x21] = 0x03;
+        memory[0x0310] = 0x5B;
+        memory[0x30] = 0x34;
+        memory[0x31] = 0x12;
+        memory[0x1234] = 0x42;
+        memory[0x02FF] = 0x11;
+        memory[0x0200] = 0x22;
+        let read = |address: u16| memory[address as usize];
+
+        let instruction = disassemble_bytes(&[0xB1, 0x20]);
+        assert_eq!(instruction.effective_address(0, 0x10, read), Some(0x0310));
+        assert_eq!(
+            instruction.resolved_operand(0, 0x10, read),
+            "($20),Y = 0300 @ 0310 = 5B"
+        );
+
+        let instruction = disassemble_bytes(&[0xA1, 0x2E]);
+        assert_eq!(
+            instruction.resolved_operand(2, 0, read),
+            "($2E,X) @ 30 = 1234 = 42"
+        );
+
+        // the pointer doesn't carry into the next page
+        let instruction = disassemble_bytes(&[0x6C, 0xFF, 0x02]);
+        assert_eq!(instruction.target(read), Some(0x2211));
+        assert_eq!(instruction.effective_address(0, 0, read), None);
+
+        let instruction = disassemble_bytes(&[0xF0, 0x04]);
+        assert_eq!(instruction.target(read), Some(0xC006));
+    }
+
+    /// The decoded length has to agree with the addressing modes the CPU emulates
+    #[test]
+    fn lengths_match_the_cpu() {
+        for opcode in 0..=255u8 {
+            let mode = Instruction::from_opcode(opcode).addressing_mode;
+            let expected = match mode {
+                // JSR reads its address while executing, unknown opcodes are decoded as implied
+                AddressingMode::Implied if opcode == 0x20 => 3,
+                AddressingMode::Implied if mnemonic(opcode) != "NOP" && is_official(opcode) => 1,
+                AddressingMode::Implied => continue,
+                AddressingMode::Accumulator => 1,
+                AddressingMode::Immediate
+                | AddressingMode::Relative
+                | AddressingMode::ZeroPageAddr { .. }
+                | AddressingMode::ZeroPageVal { .. }
+                | AddressingMode::ZeroPageValAddr { .. }
+                | AddressingMode::ZeroPageXAddr { .. }
+                | AddressingMode::ZeroPageXVal { .. }
+                | AddressingMode::ZeroPageXValAddr { .. }
+                | AddressingMode::ZeroPageYAddr { .. }
+                | AddressingMode::ZeroPageYVal { .. }
+                | AddressingMode::IndexedIndirectAddr { .. }
+                | AddressingMode::IndexedIndirectVal { .. }
+                | AddressingMode::IndexedIndirectValAddr { .. }
+                | AddressingMode::IndirectIndexedAddr { .. }
+                | AddressingMode::IndirectIndexedVal { .. }
+                | AddressingMode::IndirectIndexedValAddr { .. } => 2,
+                _ => 3,
+            };
+            let instruction = disassemble_bytes(&[opcode, 0, 0]);
+            assert_eq!(instruction.size(), expected, "opcode {:#04X}", opcode);
+        }
+    }
+}
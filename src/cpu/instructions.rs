@@ -6,6 +6,7 @@ use super::{
 
 use crate::{
     cpu,
+    state::{StateError, StateReader, StateWriter},
     utils::{
         self, add_with_carry, compare_u8, merge_u16, overflowing_add_u8_i8, rotate_left,
         rotate_right, shift_left, shift_right, split_u16, subtract_with_carry,
@@ -14,6 +15,7 @@ use crate::{
 
 #[derive(Clone, Copy, Debug)]
 pub(crate) struct Instruction {
+    pub(crate) opcode: u8,
     pub(crate) base_instruction: BaseInstruction,
     pub(crate) addressing_mode: AddressingMode,
     addressing_done: bool,
@@ -22,6 +24,7 @@ pub(crate) struct Instruction {
 impl Instruction {
     fn new(base_instruction: BaseInstruction, addressing_mode: AddressingMode) -> Self {
         Self {
+            opcode: 0,
             base_instruction,
             addressing_mode,
             addressing_done: false,
@@ -29,10 +32,89 @@ impl Instruction {
     }
 
     pub(crate) fn no_op() -> Self {
-        Self::new(BaseInstruction::NOP, AddressingMode::Implied)
+        Self::from_opcode(0xEA)
+    }
+
+    /// BRK sequence used to service an IRQ, jumps through the same vector as BRK
+    pub(crate) fn irq() -> Self {
+        let mut instr = Self::from_opcode(0x00);
+        instr.base_instruction = BaseInstruction::BRK(BrkTarget::IRQ, 0);
+        instr
+    }
+
+    /// BRK sequence used to service an NMI
+    pub(crate) fn nmi() -> Self {
+        let mut instr = Self::from_opcode(0x00);
+        instr.base_instruction = BaseInstruction::BRK(BrkTarget::NMI, 0);
+        instr
     }
 
     pub(crate) fn from_opcode(opcode: u8) -> Self {
+        let mut instr = Self::decode(opcode);
+        instr.opcode = opcode;
+        instr
+    }
+
+    /// Saves the instruction together with its progress, so it can be resumed mid-execution
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.opcode);
+        writer.write_bool(self.addressing_done);
+        match self.base_instruction {
+            BaseInstruction::BCC(delta)
+            | BaseInstruction::BCS(delta)
+            | BaseInstruction::BEQ(delta)
+            | BaseInstruction::BMI(delta)
+            | BaseInstruction::BNE(delta)
+            | BaseInstruction::BPL(delta)
+            | BaseInstruction::BVC(delta)
+            | BaseInstruction::BVS(delta) => writer.write_i8(delta),
+            BaseInstruction::BRK(target, pcl) => {
+                writer.write_u8(match target {
+                    BrkTarget::BRK => 0,
+                    BrkTarget::IRQ => 1,
+                    BrkTarget::NMI => 2,
+                });
+                writer.write_u8(pcl);
+            }
+            BaseInstruction::JSR(pcl) | BaseInstruction::RTI(pcl) | BaseInstruction::RTS(pcl) => {
+                writer.write_u8(pcl)
+            }
+            _ => {}
+        }
+        self.addressing_mode.save_state(writer);
+    }
+
+    pub(crate) fn load_state(reader: &mut StateReader) -> Result<Self, StateError> {
+        let mut instr = Self::from_opcode(reader.read_u8()?);
+        instr.addressing_done = reader.read_bool()?;
+        match &mut instr.base_instruction {
+            BaseInstruction::BCC(delta)
+            | BaseInstruction::BCS(delta)
+            | BaseInstruction::BEQ(delta)
+            | BaseInstruction::BMI(delta)
+            | BaseInstruction::BNE(delta)
+            | BaseInstruction::BPL(delta)
+            | BaseInstruction::BVC(delta)
+            | BaseInstruction::BVS(delta) => *delta = reader.read_i8()?,
+            BaseInstruction::BRK(target, pcl) => {
+                *target = match reader.read_u8()? {
+                    0 => BrkTarget::BRK,
+                    1 => BrkTarget::IRQ,
+                    2 => BrkTarget::NMI,
+                    _ => return Err(StateError::InvalidValue("BRK target")),
+                };
+                *pcl = reader.read_u8()?;
+            }
+            BaseInstruction::JSR(pcl) | BaseInstruction::RTI(pcl) | BaseInstruction::RTS(pcl) => {
+                *pcl = reader.read_u8()?
+            }
+            _ => {}
+        }
+        instr.addressing_mode.load_state(reader)?;
+        Ok(instr)
+    }
+
+    fn decode(opcode: u8) -> Self {
         match opcode {
             // AAC
             0x0B => Self::new(BaseInstruction::AAC, AddressingMode::Immediate),
@@ -479,6 +561,7 @@ macro_rules! branch_if_condition {
                         // branch now
                         let (_, high) = split_u16($cpu.program_counter);
                         $cpu.program_counter = merge_u16(res, high);
+                        $cpu.delayed_poll = true;
                         $cpu.instr_cycle = 0;
                     }
                 }
@@ -801,25 +884,31 @@ impl BaseInstruction {
                     cpu.decrement_stack_pointer();
                 }
                 5 => {
-                    // TODO: Handle IRQ interrupts here
-                    *target = if cpu.nmi {
-                        BrkTarget::NMI
-                    } else {
-                        BrkTarget::BRK
-                    };
+                    // the break flag tells BRK from hardware interrupts, even when an NMI
+                    // hijacks the BRK
+                    let software = matches!(*target, BrkTarget::BRK);
+                    // an NMI arriving before the flags are pushed hijacks BRK and IRQ
+                    if cpu.nmi || cpu.nmi_flag {
+                        *target = BrkTarget::NMI;
+                    }
                     let target = *target;
                     let mut status = cpu.get_processor_status();
                     status = status | 0b00100000;
+                    if software {
+                        status = status | 0b00010000; // set break flag
+                    } else {
+                        status = status & 0b11101111; // clear break flag
+                    }
                     match target {
-                        BrkTarget::BRK => {
-                            status = status | 0b00010000; // set break flag
-                        }
+                        BrkTarget::BRK => {}
                         BrkTarget::IRQ => {
-                            status = status & 0b11101111; // clear break flag
+                            cpu.irq = false;
                         }
                         BrkTarget::NMI => {
-                            status = status & 0b11101111; // clear break flag
                             cpu.nmi = false;
+                            cpu.nmi_flag = false;
+                            // a hijacked IRQ is lost, it's polled again if the line is still asserted
+                            cpu.irq = false;
                         }
                     }
                     cpu.memory.write_u8(cpu.stack_address(), status);
@@ -1,4 +1,4 @@
-use crate::cpu::CpuStatus;
+use crate::cpu::{disassembler, Cpu, CpuStatus};
 use std::{collections::VecDeque, io::Write};
 
 #[derive(Clone)]
@@ -13,12 +13,23 @@ struct LoggedInstr {
 #[derive(Clone)]
 enum LoggedEvent {
     LoggedInstr(LoggedInstr),
+    /// Line already formatted when the instruction started
+    Line(String),
     NMI,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Default,
+    /// Same layout as nestest.log, with operands and the PPU position
+    Nestest,
+}
+
 pub struct Logger {
     instructions: VecDeque<LoggedEvent>,
     is_logging: bool,
+    format: LogFormat,
+    ppu_position: (usize, usize),
 }
 
 impl Logger {
@@ -26,9 +37,32 @@ impl Logger {
         Self {
             instructions: VecDeque::new(),
             is_logging: false,
+            format: LogFormat::Default,
+            ppu_position: (0, 0),
         }
     }
 
+    pub fn set_format(&mut self, format: LogFormat) {
+        self.format = format;
+    }
+
+    pub fn format(&self) -> LogFormat {
+        self.format
+    }
+
+    /// Scanline and dot shown by the nestest format, updated before every CPU cycle
+    pub fn set_ppu_position(&mut self, scanline: usize, dot: usize) {
+        self.ppu_position = (scanline, dot);
+    }
+
+    pub fn ppu_position(&self) -> (usize, usize) {
+        self.ppu_position
+    }
+
+    pub fn log_line(&mut self, line: String) {
+        self.instructions.push_back(LoggedEvent::Line(line));
+    }
+
     pub fn log_nmi(&mut self) {
         self.instructions.push_back(LoggedEvent::NMI);
     }
@@ -77,18 +111,29 @@ impl Logger {
     pub fn get_log(&self) -> String {
         let mut complete = String::new();
         for event in self.instructions.iter() {
-            match event {
-                LoggedEvent::LoggedInstr(instr) => {
-                    complete.push_str(&Self::print_instruction(instr));
-                    complete.push('\n');
-                }
-                LoggedEvent::NMI => complete.push_str("------ NMI ------\n"),
-            }
+            complete.push_str(&Self::print_event(event));
+            complete.push('\n');
         }
 
         complete
     }
 
+    /// Removes the logged lines, oldest first
+    pub fn take_lines(&mut self) -> Vec<String> {
+        self.instructions
+            .drain(..)
+            .map(|e| Self::print_event(&e))
+            .collect()
+    }
+
+    fn print_event(event: &LoggedEvent) -> String {
+        match event {
+            LoggedEvent::LoggedInstr(instr) => Self::print_instruction(instr),
+            LoggedEvent::Line(line) => line.clone(),
+            LoggedEvent::NMI => "------ NMI ------".to_string(),
+        }
+    }
+
     fn print_instruction(instr: &LoggedInstr) -> String {
         let mut out = String::with_capacity(90);
         out.push_str(&format!("{:04X}  ", instr.address));
@@ -100,7 +145,7 @@ impl Logger {
         while out.len() < 16 {
             out.push(' ');
         }
-        out.push_str(opcode_to_mnemonic(instr.data[0]));
+        out.push_str(disassembler::mnemonic(instr.data[0]));
         out.push(' ');
 
         if let Some((addr, val)) = instr.target_address.as_ref() {
@@ -141,79 +186,54 @@ impl Logger {
     }
 }
 
-fn opcode_to_mnemonic(opcode: u8) -> &'static str {
-    match opcode {
-        0x0B | 0x2B => "AAC",
-        0x87 | 0x97 | 0x83 | 0x8F => "AAX",
-        0x61 | 0x65 | 0x69 | 0x6D | 0x71 | 0x75 | 0x79 | 0x7D => "ADC",
-        0x4B => "ALR",
-        0x21 | 0x25 | 0x29 | 0x2D | 0x31 | 0x35 | 0x39 | 0x3D => "AND",
-        0x6B => "ARR",
-        0x06 | 0x0A | 0x0E | 0x16 | 0x1E => "ASL",
-        0xCB => "AXS",
-        0x24 | 0x2C => "BIT",
-        0x90 => "BCC",
-        0xB0 => "BCS",
-        0xF0 => "BEQ",
-        0x30 => "BMI",
-        0xD0 => "BNE",
-        0x10 => "BPL",
-        0x50 => "BVC",
-        0x70 => "BVS",
-        0x00 => "BRK",
-        0x18 => "CLC",
-        0xD8 => "CLD",
-        0x58 => "CLI",
-        0xB8 => "CLV",
-        0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => "CMP",
-        0xE0 | 0xE4 | 0xEC => "CPX",
-        0xC0 | 0xC4 | 0xCC => "CPY",
-        0xC7 | 0xD7 | 0xCF | 0xDF | 0xDB | 0xC3 | 0xD3 => "DCP",
-        0xC6 | 0xD6 | 0xCE | 0xDE => "DEC",
-        0xCA => "DEX",
-        0x88 => "DEY",
-        0x04 | 0x14 | 0x34 | 0x44 | 0x54 | 0x64 | 0x74 | 0x80 | 0x82 | 0x89 | 0xC2 | 0xD4
-        | 0xE2 | 0xF4 => "DOP",
-        0x41 | 0x45 | 0x49 | 0x4D | 0x51 | 0x55 | 0x59 | 0x5D => "EOR",
-        0xE6 | 0xF6 | 0xEE | 0xFE => "INC",
-        0xE8 => "INX",
-        0xC8 => "INY",
-        0xE7 | 0xF7 | 0xEF | 0xFF | 0xFB | 0xE3 | 0xF3 => "ISC",
-        0xA7 | 0xB7 | 0xAF | 0xBF | 0xA3 | 0xB3 | 0xAB => "LAX",
-        0xA1 | 0xA5 | 0xA9 | 0xAD | 0xB1 | 0xB5 | 0xB9 | 0xBD => "LDA",
-        0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => "LDX",
-        0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => "LDY",
-        0x46 | 0x4A | 0x4E | 0x56 | 0x5E => "LSR",
-        0x4C | 0x6C => "JMP",
-        0x20 => "JSR",
-        0xEA | 0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => "NOP",
-        0x01 | 0x05 | 0x09 | 0x0D | 0x11 | 0x15 | 0x19 | 0x1D => "ORA",
-        0x48 => "PHA",
-        0x08 => "PHP",
-        0x68 => "PLA",
-        0x28 => "PLP",
-        0x27 | 0x37 | 0x2F | 0x3F | 0x3B | 0x23 | 0x33 => "RLA",
-        0x67 | 0x77 | 0x6F | 0x7F | 0x7B | 0x63 | 0x73 => "RRA",
-        0x26 | 0x2A | 0x2E | 0x36 | 0x3E => "ROL",
-        0x66 | 0x6A | 0x6E | 0x76 | 0x7E => "ROR",
-        0x40 => "RTI",
-        0x60 => "RTS",
-        0xE1 | 0xE5 | 0xE9 | 0xEB | 0xED | 0xF1 | 0xF5 | 0xF9 | 0xFD => "SBC",
-        0x38 => "SEC",
-        0xF8 => "SED",
-        0x78 => "SEI",
-        0x07 | 0x17 | 0x0F | 0x1F | 0x1B | 0x03 | 0x13 => "SLO",
-        0x47 | 0x57 | 0x4F | 0x5F | 0x5B | 0x43 | 0x53 => "SRE",
-        0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => "STA",
-        0x86 | 0x96 | 0x8E => "STX",
-        0x84 | 0x94 | 0x8C => "STY",
-        0xAA => "TAX",
-        0xA8 => "TAY",
-        0xBA => "TSX",
-        0x8A => "TXA",
-        0x9A => "TXS",
-        0x98 => "TYA",
-        0x0C | 0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => "TOP",
-        _ => "UNIMPLEMENTED",
+/// Formats the instruction at the CPU's program counter like nestest.log, memory is only peeked
+pub(crate) fn nestest_line(cpu: &Cpu, ppu_position: (usize, usize)) -> String {
+    let status = cpu.get_cpu_status();
+    let read = |address| cpu.peek(address);
+    let instruction = disassembler::disassemble(status.program_counter, read);
+
+    let mut out = String::with_capacity(100);
+    out.push_str(&format!(
+        "{:04X}  {:<8} ",
+        instruction.address,
+        instruction.hex_bytes()
+    ));
+    out.push(if disassembler::is_official(instruction.opcode()) {
+        ' '
+    } else {
+        '*'
+    });
+    out.push_str(nestest_mnemonic(instruction.mnemonic()));
+
+    let operand = instruction.resolved_operand(status.x, status.y, read);
+    if !operand.is_empty() {
+        out.push(' ');
+        out.push_str(&operand);
+    }
+    while out.len() < 48 {
+        out.push(' ');
+    }
+
+    out.push_str(&format!(
+        "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+        status.accumulator,
+        status.x,
+        status.y,
+        status.p,
+        status.stack_pointer,
+        ppu_position.0,
+        ppu_position.1,
+        status.cycles,
+    ));
+    out
+}
+
+/// Names used by nestest.log for the unofficial opcodes
+fn nestest_mnemonic(mnemonic: &'static str) -> &'static str {
+    match mnemonic {
+        "AAX" => "SAX",
+        "ISC" => "ISB",
+        "DOP" | "TOP" => "NOP",
+        mnemonic => mnemonic,
     }
 }
@@ -1,10 +1,16 @@
 mod addressing_mode;
+pub mod disassembler;
 mod instructions;
 mod logger;
 
-pub use logger::Logger;
+pub use logger::{LogFormat, Logger};
 
-use crate::{bus::BusAction, memory::CpuMemory};
+use crate::{
+    bus::BusAction,
+    debugger::CpuRegister,
+    memory::CpuMemory,
+    state::{StateError, StateReader, StateWriter},
+};
 pub(crate) use addressing_mode::{AddressingMode, AddressingResult};
 use instructions::Instruction;
 
@@ -22,6 +28,20 @@ pub(crate) mod addresses {
     pub(crate) const IRQ_BRK_VECTOR: u16 = 0xFFFE;
 }
 
+/// Devices that can pull the IRQ line, each one asserts and acknowledges its own interrupt
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IrqSource {
+    FrameCounter,
+    Dmc,
+    Mapper,
+}
+
+impl IrqSource {
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct CpuStatus {
     program_counter: u16,
@@ -35,6 +55,48 @@ pub struct CpuStatus {
     instr_cycle: usize,
 }
 
+impl CpuStatus {
+    pub fn pc(&self) -> u16 {
+        self.program_counter
+    }
+
+    pub fn sp(&self) -> u8 {
+        self.stack_pointer
+    }
+
+    pub fn a(&self) -> u8 {
+        self.accumulator
+    }
+
+    pub fn x(&self) -> u8 {
+        self.x
+    }
+
+    pub fn y(&self) -> u8 {
+        self.y
+    }
+
+    pub fn p(&self) -> u8 {
+        self.p
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    /// Register value, the 8 bit ones in the low byte
+    pub fn register(&self, register: CpuRegister) -> u16 {
+        match register {
+            CpuRegister::A => self.accumulator as u16,
+            CpuRegister::X => self.x as u16,
+            CpuRegister::Y => self.y as u16,
+            CpuRegister::P => self.p as u16,
+            CpuRegister::Sp => self.stack_pointer as u16,
+            CpuRegister::Pc => self.program_counter,
+        }
+    }
+}
+
 pub struct Cpu {
     program_counter: u16,
     stack_pointer: u8,
@@ -56,6 +118,15 @@ pub struct Cpu {
     pub(crate) bus_action: BusAction,
     pub(crate) nmi_flag: bool,
     nmi: bool,
+    /// One bit for every `IrqSource` currently asserting the IRQ line
+    irq_sources: u8,
+    /// IRQ line and I flag sampled at the end of the last two cycles, the interrupt
+    /// is decided on the second to last cycle of an instruction
+    irq_poll: bool,
+    prev_irq_poll: bool,
+    /// Taken branches that don't cross a page poll interrupts one cycle earlier
+    pub(crate) delayed_poll: bool,
+    irq: bool,
     pub debug: usize,
 }
 
@@ -82,11 +153,24 @@ impl Cpu {
             bus_action: BusAction::None,
             nmi_flag: false,
             nmi: false,
+            irq_sources: 0,
+            irq_poll: false,
+            prev_irq_poll: false,
+            delayed_poll: false,
+            irq: false,
             debug: 0,
         }
     }
 
     pub fn init(&mut self) {
+        self.reset();
+        // TODO: initialize state
+        self.cycles = 0;
+        self.reset_processor_status();
+    }
+
+    /// Jumps through the reset vector, RAM and the cycle count are left untouched
+    pub fn reset(&mut self) {
         let reset_addr = {
             let low = self.memory.read_u8(addresses::RESET_VECTOR);
             let high = self.memory.read_u8(addresses::RESET_VECTOR + 1);
@@ -100,10 +184,11 @@ impl Cpu {
             self.memory.read_u8(self.program_counter)
         );
         self.stack_pointer = self.stack_pointer.wrapping_sub(3);
-        // TODO: initialize state
-        self.cycles = 0;
+        self.interrupt_disable = true;
         self.instr_cycle = 0;
-        self.reset_processor_status();
+        self.nmi = false;
+        self.nmi_flag = false;
+        self.irq = false;
     }
 
     pub fn peek(&self, address: u16) -> u8 {
@@ -126,6 +211,18 @@ impl Cpu {
         self.program_counter = address;
     }
 
+    /// Only the low byte is used for the 8 bit registers
+    pub fn set_register(&mut self, register: CpuRegister, value: u16) {
+        match register {
+            CpuRegister::A => self.accumulator = value as u8,
+            CpuRegister::X => self.x = value as u8,
+            CpuRegister::Y => self.y = value as u8,
+            CpuRegister::P => self.set_processor_status(value as u8),
+            CpuRegister::Sp => self.stack_pointer = value as u8,
+            CpuRegister::Pc => self.program_counter = value,
+        }
+    }
+
     pub fn get_pc(&self) -> u16 {
         self.program_counter
     }
@@ -152,6 +249,11 @@ impl Cpu {
         }
     }
 
+    /// True between two instructions, the next tick fetches an opcode or starts an interrupt
+    pub(crate) fn at_instruction_boundary(&self) -> bool {
+        self.instr_cycle == 0
+    }
+
     pub fn tick(&mut self) {
         #[cfg(debug_assertions)]
         println!("cycle: {}", self.cycles);
@@ -164,15 +266,25 @@ impl Cpu {
                     }
 
                     let _ = self.memory.read_u8(self.program_counter);
-                    self.current_instr = Instruction::from_opcode(0x00); // force BRK instruction
+                    self.current_instr = Instruction::nmi();
                     // Don't increment PC
                     self.instr_cycle = 2;
                     
                     self.nmi_flag = false;
+                } else if self.irq {
+                    let _ = self.memory.read_u8(self.program_counter);
+                    self.current_instr = Instruction::irq();
+                    self.instr_cycle = 2;
                 } else {
+                    if self.logger.is_logging() && self.logger.format() == LogFormat::Nestest {
+                        // formatted before the opcode fetch, the operands are peeked
+                        let line = logger::nestest_line(self, self.logger.ppu_position());
+                        self.logger.log_line(line);
+                    }
                     let opcode = self.memory.read_u8(self.program_counter);
-                    if self.logger.is_logging() {
-                        self.logger.start_new_instr(self.program_counter, opcode, self.cycles);
+                    if self.logger.is_logging() && self.logger.format() == LogFormat::Default {
+                        self.logger
+                            .start_new_instr(self.program_counter, opcode, self.cycles);
                         self.logger.set_proc_status(self.get_cpu_status());
                     }
                     self.current_instr = Instruction::from_opcode(opcode);
@@ -185,23 +297,95 @@ impl Cpu {
         }
         self.cycles += 1;
 
+        self.prev_irq_poll = self.irq_poll;
+        self.irq_poll = self.irq_line() && !self.interrupt_disable;
+
         self.bus_action = self.memory.take_bus_action();
     }
 
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.program_counter);
+        writer.write_u8(self.stack_pointer);
+        writer.write_u8(self.accumulator);
+        writer.write_u8(self.x);
+        writer.write_u8(self.y);
+        writer.write_u8(self.get_processor_status());
+        writer.write_usize(self.cycles);
+        self.current_instr.save_state(writer);
+        writer.write_usize(self.instr_cycle);
+        writer.write_bool(self.nmi_flag);
+        writer.write_bool(self.nmi);
+        writer.write_u8(self.irq_sources);
+        writer.write_bool(self.irq_poll);
+        writer.write_bool(self.prev_irq_poll);
+        writer.write_bool(self.delayed_poll);
+        writer.write_bool(self.irq);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.program_counter = reader.read_u16()?;
+        self.stack_pointer = reader.read_u8()?;
+        self.accumulator = reader.read_u8()?;
+        self.x = reader.read_u8()?;
+        self.y = reader.read_u8()?;
+        let status = reader.read_u8()?;
+        self.set_processor_status(status);
+        self.cycles = reader.read_usize()?;
+        self.current_instr = Instruction::load_state(reader)?;
+        self.instr_cycle = reader.read_usize()?;
+        self.nmi_flag = reader.read_bool()?;
+        self.nmi = reader.read_bool()?;
+        if reader.version() >= 2 {
+            self.irq_sources = reader.read_u8()?;
+            self.irq_poll = reader.read_bool()?;
+            self.prev_irq_poll = reader.read_bool()?;
+            self.delayed_poll = reader.read_bool()?;
+            self.irq = reader.read_bool()?;
+        } else {
+            // version 1 had no IRQ line, the sources assert it again on their next tick
+            self.irq_sources = 0;
+            self.irq_poll = false;
+            self.prev_irq_poll = false;
+            self.delayed_poll = false;
+            self.irq = false;
+        }
+        // bus actions are consumed within the same tick, there is never one pending
+        self.bus_action = BusAction::None;
+        Ok(())
+    }
+
     pub(crate) fn pool_interrupts(&mut self) {
         // TODO: everithing
         self.debug += 1;
         if self.debug % 100000 == 0 {
             println!("{}", self.debug);
         }
+        let irq_poll = if self.delayed_poll {
+            self.prev_irq_poll
+        } else {
+            self.irq_poll
+        };
+        self.delayed_poll = false;
+
         if self.nmi_flag {
             self.nmi = true;
             println!("NMI triggered");
+        } else if irq_poll {
+            self.irq = true;
+        }
+    }
+
+    /// Asserts or releases the IRQ line on behalf of `source`
+    pub(crate) fn set_irq(&mut self, source: IrqSource, asserted: bool) {
+        if asserted {
+            self.irq_sources |= source.mask();
+        } else {
+            self.irq_sources &= !source.mask();
         }
     }
 
-    fn interrupt(&mut self) {
-        todo!("handle interrupt")
+    pub(crate) fn irq_line(&self) -> bool {
+        self.irq_sources != 0
     }
 
     pub(crate) fn stack_address(&self) -> u16 {
@@ -289,3 +473,167 @@ impl std::fmt::Debug for Cpu {
         write!(f, "current instruction: {:?}\n", self.current_instr)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{memory, Cartridge};
+
+    /// Runs `program` from $8000, the IRQ handler at $8040 stores X in $10 and counts in $11,
+    /// the NMI handler at $8050 counts in $12
+    fn cpu_with_program(program: &[u8]) -> Cpu {
+        let mut prg = vec![0u8; 0x55];
+        prg[..program.len()].copy_from_slice(program);
+        #[rustfmt::skip]
+        prg[0x40..0x47].copy_from_slice(&[
+            0x86, 0x10,             // STX $10
+            0xE6, 0x11,             // INC $11
+            0x4C, 0x44, 0x80,       // hang: JMP hang
+        ]);
+        #[rustfmt::skip]
+        prg[0x50..0x55].copy_from_slice(&[
+            0xE6, 0x12,             // INC $12
+            0x4C, 0x52, 0x80,       // hang: JMP hang
+        ]);
+        let (mut handle, _, cpu_memory, _) = memory::create_memory();
+        handle.load_cartridge(Cartridge::test_nrom(&prg, [0x8050, 0x8000, 0x8040], &[]));
+        let mut cpu = Cpu::new(cpu_memory);
+        cpu.init();
+        cpu
+    }
+
+    fn run(cpu: &mut Cpu, cycles: usize) {
+        for _ in 0..cycles {
+            cpu.tick();
+        }
+    }
+
+    #[test]
+    fn cli_takes_effect_after_next_instruction() {
+        #[rustfmt::skip]
+        let mut cpu = cpu_with_program(&[
+            0xA2, 0x00,             // LDX #$00
+            0x58,                   // CLI
+            0xE8,                   // INX
+            0xE8,                   // INX
+            0x4C, 0x05, 0x80,       // loop: JMP loop
+        ]);
+        cpu.set_irq(IrqSource::Mapper, true);
+        run(&mut cpu, 50);
+        assert_eq!(cpu.peek(0x11), 1);
+        assert_eq!(cpu.peek(0x10), 1);
+    }
+
+    #[test]
+    fn plp_takes_effect_after_next_instruction() {
+        #[rustfmt::skip]
+        let mut cpu = cpu_with_program(&[
+            0xA2, 0x00,             // LDX #$00
+            0xA9, 0x00,             // LDA #$00
+            0x48,                   // PHA
+            0x28,                   // PLP
+            0xE8,                   // INX
+            0xE8,                   // INX
+            0x4C, 0x08, 0x80,       // loop: JMP loop
+        ]);
+        cpu.set_irq(IrqSource::Mapper, true);
+        run(&mut cpu, 60);
+        assert_eq!(cpu.peek(0x11), 1);
+        assert_eq!(cpu.peek(0x10), 1);
+    }
+
+    /// Status pushed by the last interrupt or BRK, with the stack pointer `pushed_at` before it
+    fn pushed_status(cpu: &Cpu, pushed_at: u8) -> u8 {
+        cpu.peek(0x0100 + pushed_at.wrapping_sub(2) as u16)
+    }
+
+    #[test]
+    fn nmi_hijacks_brk() {
+        let mut cpu = cpu_with_program(&[0x00, 0x00]);
+        let stack_pointer = cpu.stack_pointer;
+        run(&mut cpu, 2);
+        cpu.nmi_flag = true;
+        run(&mut cpu, 20);
+        assert_eq!(cpu.peek(0x12), 1);
+        assert_eq!(cpu.peek(0x11), 0);
+        // the break flag is still pushed
+        assert_eq!(pushed_status(&cpu, stack_pointer) & 0x30, 0x30);
+    }
+
+    #[test]
+    fn nmi_hijacks_irq() {
+        let mut cpu = cpu_with_program(&[0x4C, 0x00, 0x80]);
+        let stack_pointer = cpu.stack_pointer;
+        cpu.irq = true;
+        run(&mut cpu, 2);
+        cpu.nmi_flag = true;
+        run(&mut cpu, 20);
+        assert_eq!(cpu.peek(0x12), 1);
+        assert_eq!(cpu.peek(0x11), 0);
+        assert_eq!(pushed_status(&cpu, stack_pointer) & 0x30, 0x20);
+    }
+
+    #[test]
+    fn nmi_pushes_no_break_flag() {
+        let mut cpu = cpu_with_program(&[0x4C, 0x00, 0x80]);
+        let stack_pointer = cpu.stack_pointer;
+        cpu.nmi_flag = true;
+        cpu.nmi = true;
+        run(&mut cpu, 20);
+        assert_eq!(cpu.peek(0x12), 1);
+        assert_eq!(pushed_status(&cpu, stack_pointer) & 0x30, 0x20);
+    }
+
+    #[test]
+    fn irq_is_taken_after_sei() {
+        #[rustfmt::skip]
+        let mut cpu = cpu_with_program(&[
+            0xA2, 0x00,             // LDX #$00
+            0x58,                   // CLI
+            0xE8,                   // INX
+            0x78,                   // SEI
+            0xE8,                   // INX
+            0x4C, 0x06, 0x80,       // loop: JMP loop
+        ]);
+        run(&mut cpu, 6);
+        cpu.set_irq(IrqSource::Dmc, true);
+        run(&mut cpu, 50);
+        assert_eq!(cpu.peek(0x11), 1);
+        assert_eq!(cpu.peek(0x10), 1);
+    }
+
+    #[test]
+    fn i_flag_masks_irq() {
+        #[rustfmt::skip]
+        let mut cpu = cpu_with_program(&[
+            0x78,                   // SEI
+            0xE8,                   // loop: INX
+            0x4C, 0x01, 0x80,       // JMP loop
+        ]);
+        cpu.set_irq(IrqSource::FrameCounter, true);
+        run(&mut cpu, 100);
+        assert_eq!(cpu.peek(0x11), 0);
+    }
+
+    #[test]
+    fn irq_line_is_shared() {
+        #[rustfmt::skip]
+        let mut cpu = cpu_with_program(&[
+            0x58,                   // CLI
+            0xE8,                   // loop: INX
+            0x4C, 0x01, 0x80,       // JMP loop
+        ]);
+        cpu.set_irq(IrqSource::Mapper, true);
+        cpu.set_irq(IrqSource::Dmc, true);
+        cpu.set_irq(IrqSource::Mapper, false);
+        assert!(cpu.irq_line());
+        cpu.set_irq(IrqSource::Dmc, false);
+        assert!(!cpu.irq_line());
+
+        run(&mut cpu, 100);
+        assert_eq!(cpu.peek(0x11), 0);
+        cpu.set_irq(IrqSource::Dmc, true);
+        run(&mut cpu, 20);
+        assert_eq!(cpu.peek(0x11), 1);
+    }
+}
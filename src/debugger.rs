@@ -0,0 +1,269 @@
+//! Breakpoints, watchpoints and stepping, driven through `Nes`
+
+use std::ops::RangeInclusive;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressSpace {
+    Cpu,
+    Ppu,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Watched address range. Accesses match through their mirrors too, so a watch on
+/// $0000-$07FF also sees the accesses to $0800-$1FFF
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub space: AddressSpace,
+    pub range: RangeInclusive<u16>,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Watchpoint {
+    pub fn read(space: AddressSpace, range: RangeInclusive<u16>) -> Self {
+        Self {
+            space,
+            range,
+            read: true,
+            write: false,
+        }
+    }
+
+    pub fn write(space: AddressSpace, range: RangeInclusive<u16>) -> Self {
+        Self {
+            space,
+            range,
+            read: false,
+            write: true,
+        }
+    }
+
+    pub fn read_write(space: AddressSpace, range: RangeInclusive<u16>) -> Self {
+        Self {
+            space,
+            range,
+            read: true,
+            write: true,
+        }
+    }
+
+    pub(crate) fn matches(&self, space: AddressSpace, address: u16, access: Access) -> bool {
+        let access_watched = match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+        };
+        access_watched && self.space == space && self.range.contains(&address)
+    }
+}
+
+/// A watched access, `address` is the unmirrored one.
+/// PPU accesses include the ones made while rendering.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub space: AddressSpace,
+    pub address: u16,
+    pub value: u8,
+    pub access: Access,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugEvent {
+    /// The requested step completed
+    Stepped,
+    Breakpoint(u16),
+    /// `pc` is the address of the instruction that made the access
+    Watchpoint {
+        pc: u16,
+        hit: WatchHit,
+    },
+    /// `run_until_break` ran out of frames
+    FrameLimit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuRegister {
+    A,
+    X,
+    Y,
+    P,
+    Sp,
+    Pc,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum StepUntil {
+    Instruction,
+    Scanline,
+    Frame,
+    Break { max_frames: usize },
+}
+
+pub(crate) type DebugCallback = Box<dyn FnMut(&DebugEvent)>;
+
+pub(crate) struct Debugger {
+    pub(crate) breakpoints: Vec<u16>,
+    callback: Option<DebugCallback>,
+}
+
+impl Debugger {
+    pub(crate) fn new() -> Self {
+        Self {
+            breakpoints: Vec::new(),
+            callback: None,
+        }
+    }
+
+    pub(crate) fn set_callback(&mut self, callback: Option<DebugCallback>) {
+        self.callback = callback;
+    }
+
+    pub(crate) fn notify(&mut self, event: &DebugEvent) {
+        if let Some(callback) = self.callback.as_mut() {
+            callback(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cartridge, Nes};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[rustfmt::skip]
+    const PROGRAM: [u8; 12] = [
+        0xA9, 0x05,             // C000 LDA #$05
+        0x8D, 0x00, 0x03,       // C002 STA $0300
+        0xAD, 0x00, 0x0B,       // C005 LDA $0B00
+        0xE8,                   // C008 INX
+        0x4C, 0x08, 0xC0,       // C009 JMP $C008
+    ];
+
+    #[test]
+    fn step_instruction() {
+        let mut nes = Nes::with_cartridge(Cartridge::test_program(&PROGRAM));
+        assert_eq!(nes.cpu_status().pc(), 0xC000);
+        assert_eq!(nes.step_instruction(), DebugEvent::Stepped);
+        assert_eq!(nes.cpu_status().pc(), 0xC002);
+        assert_eq!(nes.cpu_status().a(), 0x05);
+        assert_eq!(nes.step_instruction(), DebugEvent::Stepped);
+        assert_eq!(nes.cpu_status().pc(), 0xC005);
+        assert_eq!(nes.peek(0x0300), 0x05);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut nes = Nes::with_cartridge(Cartridge::test_program(&PROGRAM));
+        nes.add_breakpoint(0xC008);
+        assert_eq!(nes.run_until_break(1), DebugEvent::Breakpoint(0xC008));
+        assert_eq!(nes.cpu_status().x(), 0);
+
+        // continuing doesn't stop on the breakpoint it is sitting on
+        assert_eq!(nes.run_until_break(1), DebugEvent::Breakpoint(0xC008));
+        assert_eq!(nes.cpu_status().x(), 1);
+
+        nes.remove_breakpoint(0xC008);
+        assert_eq!(nes.run_until_break(1), DebugEvent::FrameLimit);
+    }
+
+    #[test]
+    fn cpu_watchpoints() {
+        let mut nes = Nes::with_cartridge(Cartridge::test_program(&PROGRAM));
+        nes.add_watchpoint(Watchpoint::write(AddressSpace::Cpu, 0x0300..=0x0300));
+        // $0B00 mirrors $0300
+        nes.add_watchpoint(Watchpoint::read(AddressSpace::Cpu, 0x0300..=0x03FF));
+
+        let write = WatchHit {
+            space: AddressSpace::Cpu,
+            address: 0x0300,
+            value: 0x05,
+            access: Access::Write,
+        };
+        assert_eq!(
+            nes.run_until_break(1),
+            DebugEvent::Watchpoint {
+                pc: 0xC002,
+                hit: write
+            }
+        );
+        // stops after the instruction that made the access
+        assert_eq!(nes.cpu_status().pc(), 0xC005);
+
+        let read = WatchHit {
+            access: Access::Read,
+            ..write
+        };
+        assert_eq!(
+            nes.run_until_break(1),
+            DebugEvent::Watchpoint {
+                pc: 0xC005,
+                hit: read
+            }
+        );
+
+        nes.clear_watchpoints();
+        assert_eq!(nes.run_until_break(1), DebugEvent::FrameLimit);
+    }
+
+    #[test]
+    fn ppu_watchpoints() {
+        #[rustfmt::skip]
+        let program = [
+            0xA9, 0x20,             // C000 LDA #$20
+            0x8D, 0x06, 0x20,       // C002 STA $2006
+            0xA9, 0x05,             // C005 LDA #$05
+            0x8D, 0x06, 0x20,       // C007 STA $2006
+            0xA9, 0xAB,             // C00A LDA #$AB
+            0x8D, 0x07, 0x20,       // C00C STA $2007
+            0x4C, 0x0F, 0xC0,       // C00F JMP $C00F
+        ];
+        let mut nes = Nes::with_cartridge(Cartridge::test_program(&program));
+        nes.add_watchpoint(Watchpoint::write(AddressSpace::Ppu, 0x2000..=0x23FF));
+        assert_eq!(
+            nes.run_until_break(1),
+            DebugEvent::Watchpoint {
+                pc: 0xC00C,
+                hit: WatchHit {
+                    space: AddressSpace::Ppu,
+                    address: 0x2005,
+                    value: 0xAB,
+                    access: Access::Write,
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn step_scanline_and_frame() {
+        let mut nes = Nes::with_cartridge(Cartridge::test_program(&PROGRAM));
+        let (scanline, _) = nes.ppu_position();
+        assert_eq!(nes.step_scanline(), DebugEvent::Stepped);
+        let (next, dot) = nes.ppu_position();
+        assert_eq!(next, (scanline + 1) % 262);
+        // stops on the first instruction of the new scanline
+        assert!(dot < 30);
+
+        assert_eq!(nes.step_frame(), DebugEvent::Stepped);
+    }
+
+    #[test]
+    fn callback_sees_events() {
+        let mut nes = Nes::with_cartridge(Cartridge::test_program(&PROGRAM));
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let seen = events.clone();
+        nes.set_debug_callback(move |event| seen.borrow_mut().push(*event));
+        nes.add_breakpoint(0xC005);
+        nes.run_until_break(1);
+        nes.step_instruction();
+        assert_eq!(
+            *events.borrow(),
+            vec![DebugEvent::Breakpoint(0xC005), DebugEvent::Stepped]
+        );
+    }
+}
@@ -0,0 +1,504 @@
+//! GDB remote serial protocol server, lets a debugger attach to the emulated 6502 over TCP.
+//!
+//! Registers are numbered a, x, y, p, sp, pc, the description is served as target.xml.
+//! Memory is the CPU address space, read and written without side effects. Software breakpoints
+//! (Z0/Z1) and watchpoints (Z2-Z4) map to the `Nes` debugger API.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::debugger::{Access, AddressSpace, CpuRegister, DebugEvent, Watchpoint};
+use crate::Nes;
+
+/// Register order of the `g` and `p` packets
+const REGISTERS: [CpuRegister; 6] = [
+    CpuRegister::A,
+    CpuRegister::X,
+    CpuRegister::Y,
+    CpuRegister::P,
+    CpuRegister::Sp,
+    CpuRegister::Pc,
+];
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rnes.6502">
+    <reg name="a" bitsize="8" regnum="0" type="uint8"/>
+    <reg name="x" bitsize="8" regnum="1" type="uint8"/>
+    <reg name="y" bitsize="8" regnum="2" type="uint8"/>
+    <reg name="p" bitsize="8" regnum="3" type="uint8"/>
+    <reg name="sp" bitsize="8" regnum="4" type="uint8"/>
+    <reg name="pc" bitsize="16" regnum="5" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// Sent by the client to stop a running target
+const INTERRUPT: u8 = 0x03;
+/// Largest packet we accept and send, advertised in `qSupported`
+const PACKET_SIZE: usize = 0x1000;
+
+const SIGINT: &str = "S02";
+const SIGTRAP: &str = "S05";
+
+/// Waits for a debugger on `address` and serves it until it detaches or kills the target
+pub fn serve(nes: &mut Nes, address: impl ToSocketAddrs) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    let (stream, _) = listener.accept()?;
+    run_session(nes, stream)
+}
+
+/// Serves an already connected debugger
+pub fn run_session(nes: &mut Nes, stream: TcpStream) -> io::Result<()> {
+    let mut session = Session {
+        nes,
+        connection: Connection::new(stream),
+        last_stop: SIGTRAP.to_string(),
+    };
+    while let Some(packet) = session.connection.read_packet()? {
+        match session.handle(&packet)? {
+            Some(reply) => session.connection.send_packet(&reply)?,
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+struct Session<'a> {
+    nes: &'a mut Nes,
+    connection: Connection,
+    last_stop: String,
+}
+
+impl<'a> Session<'a> {
+    /// Returns the reply, `None` ends the session
+    fn handle(&mut self, packet: &str) -> io::Result<Option<String>> {
+        let mut chars = packet.chars();
+        let command = match chars.next() {
+            Some(command) => command,
+            None => return Ok(Some(String::new())),
+        };
+        let args = chars.as_str();
+        let reply = match command {
+            '?' => self.last_stop.clone(),
+            'g' => REGISTERS.iter().map(|r| self.register_hex(*r)).collect(),
+            'G' => self.write_registers(args),
+            'p' => match parse_hex(args).and_then(|n| REGISTERS.get(n as usize)) {
+                Some(register) => self.register_hex(*register),
+                None => error(),
+            },
+            'P' => self.write_register(args),
+            'm' => self.read_memory(args),
+            'M' => self.write_memory(args),
+            'Z' | 'z' => self.breakpoint(command == 'Z', args),
+            's' => {
+                self.resume_at(args);
+                let event = self.nes.step_instruction();
+                self.stop(event)
+            }
+            'c' => {
+                self.resume_at(args);
+                self.resume()?
+            }
+            'H' => "OK".to_string(),
+            'q' => self.query(args),
+            'D' => {
+                self.connection.send_packet("OK")?;
+                return Ok(None);
+            }
+            'k' => return Ok(None),
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn register_hex(&self, register: CpuRegister) -> String {
+        let value = self.nes.cpu_status().register(register);
+        match register {
+            // little endian like the target
+            CpuRegister::Pc => format!("{:02x}{:02x}", value as u8, value >> 8),
+            _ => format!("{:02x}", value),
+        }
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        match decode_hex(args) {
+            Some(bytes) if bytes.len() == REGISTERS.len() + 1 => {
+                for (register, byte) in REGISTERS[..5].iter().zip(bytes.iter()) {
+                    self.nes.set_cpu_register(*register, *byte as u16);
+                }
+                let pc = crate::utils::merge_u16(bytes[5], bytes[6]);
+                self.nes.set_cpu_register(CpuRegister::Pc, pc);
+                "OK".to_string()
+            }
+            _ => error(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, '=');
+        let register = parts
+            .next()
+            .and_then(parse_hex)
+            .and_then(|n| REGISTERS.get(n as usize));
+        let bytes = parts.next().and_then(decode_hex);
+        match (register, bytes) {
+            (Some(register), Some(bytes)) if !bytes.is_empty() => {
+                let value = bytes
+                    .iter()
+                    .rev()
+                    .fold(0u16, |value, b| value << 8 | *b as u16);
+                self.nes.set_cpu_register(*register, value);
+                "OK".to_string()
+            }
+            _ => error(),
+        }
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        match parse_pair(args) {
+            // two hex digits per byte have to fit in a packet
+            Some((address, length)) if length as usize <= PACKET_SIZE / 2 => (0..length)
+                .map(|i| format!("{:02x}", self.nes.peek(address.wrapping_add(i) as u16)))
+                .collect(),
+            _ => error(),
+        }
+    }
+
+    /// `M address,length:data`
+    fn write_memory(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, ':');
+        let range = parts.next().and_then(parse_pair);
+        let data = parts.next().and_then(decode_hex);
+        match (range, data) {
+            (Some((address, length)), Some(data)) if data.len() == length as usize => {
+                for (i, byte) in data.into_iter().enumerate() {
+                    self.nes.poke(address.wrapping_add(i as u32) as u16, byte);
+                }
+                "OK".to_string()
+            }
+            _ => error(),
+        }
+    }
+
+    /// `Z type,address,kind`, the kind is the length for watchpoints
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut parts = args.splitn(2, ',');
+        let kind = parts.next();
+        let (address, length) = match parts.next().and_then(parse_pair) {
+            Some((address, length)) => (address as u16, length.max(1) as u16),
+            None => return error(),
+        };
+        let range = address..=address.wrapping_add(length - 1);
+        let watchpoint = match kind {
+            Some("0") | Some("1") => {
+                if insert {
+                    self.nes.add_breakpoint(address);
+                } else {
+                    self.nes.remove_breakpoint(address);
+                }
+                return "OK".to_string();
+            }
+            Some("2") => Watchpoint::write(AddressSpace::Cpu, range),
+            Some("3") => Watchpoint::read(AddressSpace::Cpu, range),
+            Some("4") => Watchpoint::read_write(AddressSpace::Cpu, range),
+            _ => return String::new(),
+        };
+        if insert {
+            self.nes.add_watchpoint(watchpoint);
+        } else {
+            self.nes.remove_watchpoint(&watchpoint);
+        }
+        "OK".to_string()
+    }
+
+    /// `s` and `c` can carry the address to resume from
+    fn resume_at(&mut self, args: &str) {
+        if let Some(address) = parse_hex(args) {
+            self.nes.set_cpu_register(CpuRegister::Pc, address as u16);
+        }
+    }
+
+    /// Runs a frame at a time, checking for an interrupt from the client in between
+    fn resume(&mut self) -> io::Result<String> {
+        loop {
+            let event = self.nes.run_until_break(1);
+            self.nes.take_audio_samples();
+            match event {
+                DebugEvent::FrameLimit => {
+                    if self.connection.interrupted()? {
+                        self.last_stop = SIGINT.to_string();
+                        return Ok(self.last_stop.clone());
+                    }
+                }
+                event => return Ok(self.stop(event)),
+            }
+        }
+    }
+
+    fn stop(&mut self, event: DebugEvent) -> String {
+        self.last_stop = match event {
+            DebugEvent::Watchpoint { hit, .. } if hit.space == AddressSpace::Cpu => {
+                let kind = match hit.access {
+                    Access::Read => "rwatch",
+                    Access::Write => "watch",
+                };
+                format!("T05{}:{:04x};", kind, hit.address)
+            }
+            _ => SIGTRAP.to_string(),
+        };
+        self.last_stop.clone()
+    }
+
+    fn query(&self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE)
+        } else if args == "Attached" {
+            "1".to_string()
+        } else if let Some(annex) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            match parse_pair(annex) {
+                Some((offset, length)) => {
+                    let offset = (offset as usize).min(TARGET_XML.len());
+                    let end = (offset + length as usize).min(TARGET_XML.len());
+                    let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                    format!("{}{}", more, &TARGET_XML[offset..end])
+                }
+                None => error(),
+            }
+        } else {
+            String::new()
+        }
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    /// Received bytes not parsed yet
+    pending: VecDeque<u8>,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// `None` when the client closed the connection
+    fn next_byte(&mut self) -> io::Result<Option<u8>> {
+        if self.pending.is_empty() {
+            let mut buffer = [0u8; 1024];
+            let n = self.stream.read(&mut buffer)?;
+            self.pending.extend(&buffer[..n]);
+        }
+        Ok(self.pending.pop_front())
+    }
+
+    /// Reads the next `$data#checksum` packet and acknowledges it.
+    /// Acks and interrupts received while the target is stopped are skipped.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.next_byte()? {
+                Some(b'$') => {}
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.next_byte()? {
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                    None => return Ok(None),
+                }
+            }
+            let mut checksum = [0u8; 2];
+            for c in checksum.iter_mut() {
+                match self.next_byte()? {
+                    Some(b) => *c = b,
+                    None => return Ok(None),
+                }
+            }
+
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|c| u8::from_str_radix(c, 16).ok());
+            if expected == Some(packet_checksum(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        write!(
+            self.stream,
+            "${}#{:02x}",
+            data,
+            packet_checksum(data.as_bytes())
+        )?;
+        self.stream.flush()
+    }
+
+    /// Checks without blocking if the client sent an interrupt
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut buffer = [0u8; 1024];
+        let res = loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => break Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.pending.extend(&buffer[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        res?;
+
+        match self.pending.iter().position(|b| *b == INTERRUPT) {
+            Some(i) => {
+                self.pending.remove(i);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+fn packet_checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn error() -> String {
+    "E01".to_string()
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+/// Parses `address,length`
+fn parse_pair(s: &str) -> Option<(u32, u32)> {
+    let mut parts = s.splitn(2, ',');
+    let first = parse_hex(parts.next()?)?;
+    let second = parse_hex(parts.next()?)?;
+    Some((first, second))
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() & 1 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cartridge;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[rustfmt::skip]
+    const PROGRAM: [u8; 9] = [
+        0xA2, 0x01,             // C000 LDX #$01
+        0xE8,                   // C002 INX
+        0xE8,                   // C003 INX
+        0x86, 0x10,             // C004 STX $10
+        0x4C, 0x02, 0xC0,       // C006 JMP $C002
+    ];
+
+    struct Client(TcpStream);
+
+    impl Client {
+        fn request(&mut self, data: &str) -> String {
+            write!(self.0, "${}#{:02x}", data, packet_checksum(data.as_bytes())).unwrap();
+            let mut reply = Vec::new();
+            let mut byte = [0u8];
+            // skip the ack
+            loop {
+                self.0.read_exact(&mut byte).unwrap();
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            loop {
+                self.0.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                reply.push(byte[0]);
+            }
+            let mut checksum = [0u8; 2];
+            self.0.read_exact(&mut checksum).unwrap();
+            self.0.write_all(b"+").unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    /// Runs `session` as the client against a server serving `nes`
+    fn with_client(nes: &mut Nes, session: impl FnOnce(&mut Client) + Send + 'static) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut client = Client(TcpStream::connect(address).unwrap());
+            session(&mut client);
+            client.0.write_all(b"$k#6b").unwrap();
+        });
+        let (stream, _) = listener.accept().unwrap();
+        run_session(nes, stream).unwrap();
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut nes = Nes::with_cartridge(Cartridge::test_program(&PROGRAM));
+        with_client(&mut nes, |client| {
+            assert_eq!(client.request("?"), "S05");
+            // a x y p sp pcl pch
+            assert_eq!(client.request("g"), "00000024fd00c0");
+            assert_eq!(client.request("mc000,3"), "a201e8");
+            assert_eq!(client.request("M0200,2:beef"), "OK");
+            assert_eq!(client.request("m0200,2"), "beef");
+            assert_eq!(client.request("m0,800").len(), 0x1000);
+            assert_eq!(client.request("m0,ffffffff"), "E01");
+            assert_eq!(client.request("P0=7f"), "OK");
+            assert_eq!(client.request("p0"), "7f");
+            assert_eq!(client.request("P5=03c0"), "OK");
+            assert_eq!(client.request("p5"), "03c0");
+            assert_eq!(client.request("G010203243000c0"), "OK");
+            assert_eq!(client.request("g"), "010203243000c0");
+            assert!(client
+                .request("qXfer:features:read:target.xml:0,1000")
+                .starts_with("l<?xml"));
+        });
+    }
+
+    #[test]
+    fn breakpoints_and_stepping() {
+        let mut nes = Nes::with_cartridge(Cartridge::test_program(&PROGRAM));
+        with_client(&mut nes, |client| {
+            assert_eq!(client.request("s"), "S05");
+            // x = 1, pc = $C002
+            assert_eq!(client.request("p1"), "01");
+            assert_eq!(client.request("p5"), "02c0");
+
+            assert_eq!(client.request("Z0,c004,1"), "OK");
+            assert_eq!(client.request("c"), "S05");
+            assert_eq!(client.request("p1"), "03");
+            assert_eq!(client.request("p5"), "04c0");
+            assert_eq!(client.request("z0,c004,1"), "OK");
+
+            assert_eq!(client.request("Z2,10,1"), "OK");
+            assert_eq!(client.request("c"), "T05watch:0010;");
+            assert_eq!(client.request("m10,1"), "03");
+        });
+    }
+}
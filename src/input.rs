@@ -1,3 +1,5 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
 #[derive(Clone, Debug)]
 pub struct InputData {
     pub a: bool,
@@ -23,6 +25,29 @@ impl InputData {
             select: false,
         }
     }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.a);
+        writer.write_bool(self.b);
+        writer.write_bool(self.up);
+        writer.write_bool(self.down);
+        writer.write_bool(self.left);
+        writer.write_bool(self.right);
+        writer.write_bool(self.start);
+        writer.write_bool(self.select);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.a = reader.read_bool()?;
+        self.b = reader.read_bool()?;
+        self.up = reader.read_bool()?;
+        self.down = reader.read_bool()?;
+        self.left = reader.read_bool()?;
+        self.right = reader.read_bool()?;
+        self.start = reader.read_bool()?;
+        self.select = reader.read_bool()?;
+        Ok(())
+    }
 }
 
 pub(crate) struct Controller {
@@ -57,6 +82,24 @@ impl Controller {
         }
     }
 
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        self.input_data.save_state(writer);
+        writer.write_bool(self.last_write);
+        self.buffer.save_state(writer);
+        writer.write_usize(self.current_tick);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.input_data.load_state(reader)?;
+        self.last_write = reader.read_bool()?;
+        self.buffer.load_state(reader)?;
+        self.current_tick = reader.read_usize()?;
+        if self.current_tick > 8 {
+            return Err(StateError::InvalidValue("controller tick"));
+        }
+        Ok(())
+    }
+
     pub(crate) fn read_from(&mut self) -> u8 {
         if self.buffer.start {
             println!("Reading start button press");
@@ -9,19 +9,32 @@ use std::io::{Read, Write};
 pub mod apu;
 pub mod bus;
 pub mod cartridge;
+pub mod cheats;
 pub mod cpu;
+pub mod debugger;
+pub mod gdb;
 pub mod input;
 pub mod memory;
+pub mod nestest;
 pub mod ppu;
+pub mod ram_search;
 pub mod roms;
+pub mod state;
+pub mod test_rom;
 mod utils;
 
 use apu::Apu;
 use bus::{BusAction, PpuAction};
 pub(crate) use cartridge::Cartridge;
-use cpu::Cpu;
+use cartridge::GameInfo;
+use cheats::{Cheat, CheatEffect, CheatError};
+use cpu::{Cpu, CpuStatus, IrqSource, LogFormat};
+use debugger::{CpuRegister, DebugEvent, Debugger, StepUntil, Watchpoint};
 use input::InputData;
+use nestest::NestestMismatch;
 use ppu::{buffer::Buffer, Ppu};
+use state::{StateError, StateFile, StateFileWriter};
+use test_rom::{TestRomResult, TestRomStatus};
 
 pub struct Nes {
     memory: memory::MemoryHandle,
@@ -30,6 +43,8 @@ pub struct Nes {
     ppu: ppu::Ppu,
     last_cycle: usize,
     running: bool,
+    debugger: Debugger,
+    cheats: Vec<Cheat>,
 }
 
 impl Nes {
@@ -42,6 +57,8 @@ impl Nes {
             ppu: Ppu::new(ppu_mem),
             last_cycle: 0,
             running: false,
+            debugger: Debugger::new(),
+            cheats: Vec::new(),
         }
     }
 
@@ -57,13 +74,19 @@ impl Nes {
         nes
     }
 
+    /// Loads a new game, the cheats of the previous one are dropped
     pub fn load_cartridge(&mut self, cartridge: Cartridge) {
+        self.clear_cheats();
         self.memory.load_cartridge(cartridge);
         self.cpu.init();
         self.running = true;
     }
 
     pub fn tick(&mut self) -> bool {
+        if self.cpu.logger.is_logging() {
+            let (scanline, dot) = self.ppu.position();
+            self.cpu.logger.set_ppu_position(scanline, dot);
+        }
         self.cpu.tick();
         let cpu_bus_action = self.cpu.bus_action;
 
@@ -94,9 +117,16 @@ impl Nes {
         }
 
         self.cpu.nmi_flag = self.cpu.nmi_flag | nmi;
+        self.cpu.set_irq(IrqSource::FrameCounter, self.apu.frame_irq());
+        self.cpu.set_irq(IrqSource::Dmc, self.apu.dmc_irq());
+        self.cpu.set_irq(IrqSource::Mapper, self.memory.mapper_irq());
 
         self.ppu.transfer_io_registers();
 
+        if frame_end {
+            self.apply_ram_freezes();
+        }
+
         //if frame_end {
         //let delta = self.cpu.cycles - self.last_cycle;
         //self.last_cycle = self.cpu.cycles;
@@ -106,6 +136,12 @@ impl Nes {
         frame_end
     }
 
+    /// Presses the reset button, RAM and the cartridge keep their content
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.ppu.reset();
+    }
+
     pub fn initialize_audio(
         &mut self,
         audio_subsystem: &sdl2::AudioSubsystem,
@@ -123,6 +159,12 @@ impl Nes {
         self.apu.update_audio_generator(current_generator);
     }
 
+    /// Audio samples generated since the last call. Without an audio device they pile up
+    /// until this is called, once per frame is enough.
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        self.apu.take_samples()
+    }
+
     pub fn render_pattern_table(&mut self, address: u16, palette_idx: usize) -> Buffer {
         self.ppu.render_pattern_table(address, palette_idx)
     }
@@ -157,6 +199,10 @@ impl Nes {
         self.cpu.logger.disable_logging();
     }
 
+    pub fn set_log_format(&mut self, format: LogFormat) {
+        self.cpu.logger.set_format(format);
+    }
+
     pub fn write_cpu_logs(&mut self, filename: &str) {
         self.cpu.logger.write_log(filename);
     }
@@ -169,21 +215,519 @@ impl Nes {
         self.cpu.set_pc(pc);
     }
 
+    /// Runs nestest.nes from $C000 with a nestest format trace, comparing it line by line
+    /// with `golden_log`. Returns the number of matching lines.
+    pub fn run_nestest(&mut self, golden_log: &str) -> Result<usize, NestestMismatch> {
+        let expected = nestest::golden_lines(golden_log);
+
+        self.set_pc(nestest::START);
+        self.cpu.cycles = nestest::START_CYCLE;
+        while self.ppu.position() != nestest::START_PPU_POSITION {
+            self.ppu.tick(PpuAction::None);
+        }
+
+        self.cpu.logger.clear();
+        self.set_log_format(LogFormat::Nestest);
+        self.enable_logging();
+
+        let mut matched = 0;
+        let mut result = Ok(expected.len());
+        'running: while matched < expected.len() {
+            if self.cpu.cycles > nestest::MAX_CYCLES {
+                result = Err(NestestMismatch::new(matched, &expected, None));
+                break;
+            }
+            self.tick();
+            for line in self.cpu.logger.take_lines() {
+                if matched == expected.len() {
+                    break 'running;
+                }
+                if line != expected[matched] {
+                    result = Err(NestestMismatch::new(matched, &expected, Some(line)));
+                    break 'running;
+                }
+                matched += 1;
+            }
+        }
+
+        self.disable_logging();
+        self.set_log_format(LogFormat::Default);
+        result
+    }
+
+    /// Reads CPU memory without side effects on the emulated hardware.
+    /// I/O registers read as the last value the CPU would see, without clearing flags.
+    pub fn peek(&self, address: u16) -> u8 {
+        self.cpu.peek(address)
+    }
+
+    /// Writes RAM, PRG RAM or the PRG ROM byte mapped at `address` without the mapper
+    /// seeing a write. Writes to the I/O registers are ignored.
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.memory.poke(address, value);
+    }
+
+    /// Reads PPU memory (pattern tables, nametables, palette RAM) without side effects
+    pub fn peek_ppu(&self, address: u16) -> u8 {
+        self.memory.peek_ppu(address)
+    }
+
+    /// Writes PPU memory without side effects, use `poke_chr` to patch CHR ROM
+    pub fn poke_ppu(&mut self, address: u16, value: u8) {
+        self.memory.poke_ppu(address, value);
+    }
+
+    pub fn peek_oam(&self, address: u8) -> u8 {
+        self.memory.peek_oam(address)
+    }
+
+    pub fn poke_oam(&mut self, address: u8, value: u8) {
+        self.memory.poke_oam(address, value);
+    }
+
+    pub fn prg_rom_size(&self) -> usize {
+        self.memory.prg_rom_size()
+    }
+
+    /// Byte `offset` of the whole PRG ROM, whatever bank is mapped.
+    /// `None` past the end of the ROM.
+    pub fn peek_prg(&self, offset: usize) -> Option<u8> {
+        self.memory.peek_prg(offset)
+    }
+
+    /// Patches the PRG ROM, returns false past its end. Patches aren't saved in states.
+    pub fn poke_prg(&mut self, offset: usize, value: u8) -> bool {
+        self.memory.poke_prg(offset, value)
+    }
+
+    /// Size of CHR ROM or RAM
+    pub fn chr_size(&self) -> usize {
+        self.memory.chr_size()
+    }
+
+    pub fn peek_chr(&self, offset: usize) -> Option<u8> {
+        self.memory.peek_chr(offset)
+    }
+
+    pub fn poke_chr(&mut self, offset: usize, value: u8) -> bool {
+        self.memory.poke_chr(offset, value)
+    }
+
+    pub fn cpu_status(&self) -> CpuStatus {
+        self.cpu.get_cpu_status()
+    }
+
+    pub fn set_cpu_register(&mut self, register: CpuRegister, value: u16) {
+        self.cpu.set_register(register, value);
+    }
+
+    /// Scanline and dot the PPU renders next
+    pub fn ppu_position(&self) -> (usize, usize) {
+        self.ppu.position()
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        if !self.debugger.breakpoints.contains(&address) {
+            self.debugger.breakpoints.push(address);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) {
+        self.debugger.breakpoints.retain(|a| *a != address);
+    }
+
+    pub fn breakpoints(&self) -> &[u16] {
+        &self.debugger.breakpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        let mut watchpoints = self.memory.watchpoints();
+        if !watchpoints.contains(&watchpoint) {
+            watchpoints.push(watchpoint);
+            self.memory.set_watchpoints(watchpoints);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) {
+        let mut watchpoints = self.memory.watchpoints();
+        watchpoints.retain(|w| w != watchpoint);
+        self.memory.set_watchpoints(watchpoints);
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.memory.set_watchpoints(Vec::new());
+    }
+
+    pub fn watchpoints(&self) -> Vec<Watchpoint> {
+        self.memory.watchpoints()
+    }
+
+    /// Called with every event returned by the stepping functions
+    pub fn set_debug_callback(&mut self, callback: impl FnMut(&DebugEvent) + 'static) {
+        self.debugger.set_callback(Some(Box::new(callback)));
+    }
+
+    pub fn clear_debug_callback(&mut self) {
+        self.debugger.set_callback(None);
+    }
+
+    /// Runs until the next instruction boundary
+    pub fn step_instruction(&mut self) -> DebugEvent {
+        self.debug_run(StepUntil::Instruction)
+    }
+
+    /// Runs until the first instruction boundary on the next scanline
+    pub fn step_scanline(&mut self) -> DebugEvent {
+        self.debug_run(StepUntil::Scanline)
+    }
+
+    /// Runs until the first instruction boundary after the end of the frame
+    pub fn step_frame(&mut self) -> DebugEvent {
+        self.debug_run(StepUntil::Frame)
+    }
+
+    /// Runs until a breakpoint or a watchpoint is hit, giving up after `max_frames` frames.
+    /// A breakpoint on the current instruction doesn't stop it, so it can be used to continue.
+    pub fn run_until_break(&mut self, max_frames: usize) -> DebugEvent {
+        self.debug_run(StepUntil::Break { max_frames })
+    }
+
+    /// Ticks until `until` is satisfied or a breakpoint or watchpoint stops execution, always
+    /// stopping on an instruction boundary. Watchpoints stop after the instruction making
+    /// the access.
+    fn debug_run(&mut self, until: StepUntil) -> DebugEvent {
+        let _ = self.memory.take_watch_hit();
+        let (start_scanline, _) = self.ppu.position();
+        let mut instr_pc = self.cpu.get_pc();
+        // resuming from a breakpoint must not hit it again before the instruction runs
+        let mut started = !self.cpu.at_instruction_boundary();
+        let mut frames = 0;
+
+        let event = loop {
+            if self.tick() {
+                frames += 1;
+            }
+            if !self.cpu.at_instruction_boundary() {
+                started = true;
+                continue;
+            }
+            if !started {
+                // DMA stall
+                continue;
+            }
+
+            if let Some(hit) = self.memory.take_watch_hit() {
+                break DebugEvent::Watchpoint { pc: instr_pc, hit };
+            }
+            let pc = self.cpu.get_pc();
+            if self.debugger.breakpoints.contains(&pc) {
+                break DebugEvent::Breakpoint(pc);
+            }
+            match until {
+                StepUntil::Instruction => break DebugEvent::Stepped,
+                StepUntil::Scanline if self.ppu.position().0 != start_scanline => {
+                    break DebugEvent::Stepped
+                }
+                StepUntil::Frame if frames > 0 => break DebugEvent::Stepped,
+                StepUntil::Break { max_frames } if frames >= max_frames => {
+                    break DebugEvent::FrameLimit
+                }
+                _ => {}
+            }
+            instr_pc = pc;
+        };
+
+        self.debugger.notify(&event);
+        event
+    }
+
+    /// Runs a test ROM using the $6000 result protocol until it reports a result,
+    /// pressing reset when asked to
+    pub fn run_test_rom(&mut self, max_frames: usize) -> TestRomResult {
+        let mut signature_seen = false;
+        let mut reset_requested_at = None;
+        let mut frames = 0;
+        while frames < max_frames {
+            self.run_until_frame();
+            self.take_audio_samples();
+            frames += 1;
+
+            if !self.has_test_rom_signature() {
+                continue;
+            }
+            signature_seen = true;
+            match self.peek(test_rom::STATUS) {
+                test_rom::RUNNING => {}
+                test_rom::NEEDS_RESET => match reset_requested_at {
+                    None => reset_requested_at = Some(frames),
+                    Some(frame) if frames - frame >= test_rom::RESET_DELAY_FRAMES => {
+                        self.reset();
+                        reset_requested_at = None;
+                    }
+                    Some(_) => {}
+                },
+                code => {
+                    return TestRomResult {
+                        status: if code == 0 {
+                            TestRomStatus::Passed
+                        } else {
+                            TestRomStatus::Failed(code)
+                        },
+                        message: self.test_rom_message(),
+                        frames,
+                    }
+                }
+            }
+        }
+
+        TestRomResult {
+            status: if signature_seen {
+                TestRomStatus::Timeout
+            } else {
+                TestRomStatus::MissingSignature
+            },
+            message: if signature_seen {
+                self.test_rom_message()
+            } else {
+                String::new()
+            },
+            frames,
+        }
+    }
+
+    fn has_test_rom_signature(&self) -> bool {
+        test_rom::SIGNATURE
+            .iter()
+            .zip(test_rom::SIGNATURE_ADDRESS..)
+            .all(|(byte, address)| self.peek(address) == *byte)
+    }
+
+    fn test_rom_message(&self) -> String {
+        let bytes: Vec<u8> = (test_rom::MESSAGE..0x8000)
+            .map(|address| self.peek(address))
+            .take_while(|b| *b != 0)
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// Adds an enabled Game Genie code or `AAAA:VV[:CC]` RAM freeze, adding the same code
+    /// again just enables it
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), CheatError> {
+        let cheat = Cheat::new(code)?;
+        self.insert_cheat(cheat);
+        self.update_rom_patches();
+        Ok(())
+    }
+
+    /// Adds the cheats of an FCEUX .cht file, keeping the enabled state stored in it
+    pub fn load_cheats(&mut self, text: &str) -> Result<(), CheatError> {
+        for cheat in cheats::parse_cht(text)? {
+            self.insert_cheat(cheat);
+        }
+        self.update_rom_patches();
+        Ok(())
+    }
+
+    fn insert_cheat(&mut self, cheat: Cheat) {
+        match self.cheats.iter_mut().find(|c| c.code == cheat.code) {
+            Some(existing) => existing.enabled = cheat.enabled,
+            None => self.cheats.push(cheat),
+        }
+    }
+
+    pub fn remove_cheat(&mut self, code: &str) {
+        let code = code.trim().to_ascii_uppercase();
+        self.cheats.retain(|c| c.code != code);
+        self.update_rom_patches();
+    }
+
+    /// Returns false if the code was never added
+    pub fn set_cheat_enabled(&mut self, code: &str, enabled: bool) -> bool {
+        let code = code.trim().to_ascii_uppercase();
+        let found = match self.cheats.iter_mut().find(|c| c.code == code) {
+            Some(cheat) => {
+                cheat.enabled = enabled;
+                true
+            }
+            None => false,
+        };
+        self.update_rom_patches();
+        found
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.cheats.clear();
+        self.update_rom_patches();
+    }
+
+    fn update_rom_patches(&mut self) {
+        let patches = self
+            .cheats
+            .iter()
+            .filter(|c| c.enabled)
+            .filter_map(|c| match c.effect {
+                CheatEffect::Patch(patch) => Some(patch),
+                CheatEffect::Freeze { .. } => None,
+            })
+            .collect();
+        self.memory.set_rom_patches(patches);
+    }
+
+    fn apply_ram_freezes(&mut self) {
+        for cheat in self.cheats.iter().filter(|c| c.enabled) {
+            if let CheatEffect::Freeze {
+                address,
+                value,
+                compare,
+            } = cheat.effect
+            {
+                if compare.is_none_or(|compare| self.cpu.peek(address) == compare) {
+                    self.memory.poke(address, value);
+                }
+            }
+        }
+    }
+
     pub fn set_input1(&mut self, input_data: InputData) {
         self.memory.set_controller1_data(input_data);
     }
 
+    /// PRG RAM content, empty when the cartridge has none
+    pub fn save_ram(&self) -> Vec<u8> {
+        self.memory.get_save_data()
+    }
+
+    /// The loaded game as recognized by the ROM database
+    pub fn game(&self) -> Option<&'static GameInfo> {
+        self.memory.game()
+    }
+
+    /// Whether the game saves to battery backed PRG RAM or to an FDS disk
+    pub fn has_battery(&self) -> bool {
+        self.memory.has_battery()
+    }
+
+    /// Number of sides of the FDS disk, 0 for cartridges
+    pub fn disk_sides(&self) -> usize {
+        self.memory.disk_sides()
+    }
+
+    /// Disk side in the FDS drive, `None` when it's empty
+    pub fn disk_side(&self) -> Option<usize> {
+        self.memory.disk_side()
+    }
+
+    /// Puts `side` in the FDS drive, returns false if the disk has no such side. Games only
+    /// notice the change after the drive was empty for a moment, eject the disk first.
+    pub fn insert_disk(&mut self, side: usize) -> bool {
+        self.memory.insert_disk(side)
+    }
+
+    pub fn eject_disk(&mut self) {
+        self.memory.eject_disk();
+    }
+
+    /// Writes the PRG RAM to `path`, or the whole disk in the `.fds` format for the FDS.
+    /// Does nothing for games without a battery.
     pub fn save_data(&self, path: &str) {
-        let data = self.memory.get_save_data();
+        if !self.has_battery() {
+            return;
+        }
+        let data = self
+            .memory
+            .disk_image()
+            .unwrap_or_else(|| self.memory.get_save_data());
         let mut f = File::create(path).unwrap();
         f.write_all(&data[..]).unwrap();
     }
 
+    /// Captures the whole machine state, the cartridge ROM itself is not included
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut file = StateFileWriter::new(self.memory.cartridge_fingerprint());
+        file.add_chunk(state::CPU_CHUNK, |w| self.cpu.save_state(w));
+        file.add_chunk(state::PPU_CHUNK, |w| self.ppu.save_state(w));
+        file.add_chunk(state::APU_CHUNK, |w| self.apu.save_state(w));
+        file.add_chunk(state::MEMORY_CHUNK, |w| self.memory.save_state(w));
+        file.add_chunk(state::MAPPER_CHUNK, |w| self.memory.save_mapper_state(w));
+        file.add_chunk(state::NES_CHUNK, |w| {
+            w.write_usize(self.last_cycle);
+            w.write_bool(self.running);
+        });
+        file.finish()
+    }
+
+    /// Restores a state produced by `save_state`, the same cartridge must already be loaded.
+    /// If the state can't be loaded the emulator is left untouched.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let file = StateFile::parse(state)?;
+        let fingerprint = self.memory.cartridge_fingerprint();
+        if file.fingerprint != fingerprint {
+            return Err(StateError::RomMismatch {
+                expected: fingerprint,
+                found: file.fingerprint,
+            });
+        }
+
+        let backup = self.save_state();
+        let res = self.read_state(&file);
+        if res.is_err() {
+            let backup = StateFile::parse(&backup).unwrap();
+            self.read_state(&backup)
+                .expect("restoring the previous state can't fail");
+        }
+        res
+    }
+
+    fn read_state(&mut self, file: &StateFile) -> Result<(), StateError> {
+        file.load_chunk(state::CPU_CHUNK, |r| self.cpu.load_state(r))?;
+        file.load_chunk(state::PPU_CHUNK, |r| self.ppu.load_state(r))?;
+        file.load_chunk(state::APU_CHUNK, |r| self.apu.load_state(r))?;
+        file.load_chunk(state::MEMORY_CHUNK, |r| self.memory.load_state(r))?;
+        file.load_chunk(state::MAPPER_CHUNK, |r| self.memory.load_mapper_state(r))?;
+        file.load_chunk(state::NES_CHUNK, |r| {
+            self.last_cycle = r.read_usize()?;
+            self.running = r.read_bool()?;
+            Ok(())
+        })
+    }
+
+    pub fn save_state_file(&self, path: &str) -> Result<(), StateError> {
+        let mut f = File::create(path)?;
+        f.write_all(&self.save_state())?;
+        Ok(())
+    }
+
+    pub fn load_state_file(&mut self, path: &str) -> Result<(), StateError> {
+        let mut data = vec![];
+        File::open(path)?.read_to_end(&mut data)?;
+        self.load_state(&data)
+    }
+
     pub fn try_load_data(&mut self, path: &str) {
+        if !self.has_battery() {
+            return;
+        }
         if let Ok(mut f) = File::open(path) {
             let mut data = vec![];
             if let Ok(_) = f.read_to_end(&mut data) {
-                self.memory.set_save_data(data);
+                if self.disk_sides() > 0 {
+                    self.memory.load_disk_image(&data);
+                } else {
+                    self.memory.set_save_data(data);
+                }
+            }
+        }
+    }
+
+    /// Loads the .cht file at `path` if there is one
+    pub fn try_load_cheats(&mut self, path: &str) {
+        if let Ok(text) = std::fs::read_to_string(path) {
+            if let Err(e) = self.load_cheats(&text) {
+                eprintln!("{}: {}", path, e);
             }
         }
     }
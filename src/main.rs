@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 use rnes::cartridge::Cartridge;
 use rnes::input::InputData;
+use rnes::ram_search::{RamSearch, SearchFilter, ValueSize};
 use rnes::roms;
 
 fn main() {
@@ -75,7 +76,7 @@ fn run_emulator() -> Result<(), String> {
     let video_subsystem = sdl_context.video().unwrap();
 
     let window = video_subsystem
-        .window("rust-sdl2 demo", SCREEN_WIDTH, SCREEN_HEIGHT)
+        .window("rnes", SCREEN_WIDTH, SCREEN_HEIGHT)
         .position_centered()
         .build()
         .unwrap();
@@ -127,6 +128,10 @@ fn run_emulator() -> Result<(), String> {
 
     let mut palette_idx = 0;
 
+    let mut ram_search: Option<RamSearch> = None;
+    // last side put in the FDS drive
+    let mut disk_side = 0;
+
     let mut event_pump = sdl_context.event_pump().unwrap();
 
     // Timing stuff
@@ -150,9 +155,17 @@ fn run_emulator() -> Result<(), String> {
                 Event::DropFile { filename, .. } => {
                     if let Ok(catridge) = try_get_cartridge(&filename) {
                         nes.load_cartridge(catridge);
+                        canvas
+                            .window_mut()
+                            .set_title(&get_window_title(&nes, &filename))
+                            .map_err(|e| e.to_string())?;
                         let new_save_path = get_save_path(&filename);
                         nes.try_load_data(&new_save_path);
                         save_path = Some(new_save_path);
+                        load_game_genie_codes(&mut nes, &get_rom_sibling_path(&filename, "gg"));
+                        nes.try_load_cheats(&get_rom_sibling_path(&filename, "cht"));
+                        ram_search = None;
+                        disk_side = 0;
                     }
                 }
                 Event::KeyDown { keycode, .. } => match keycode {
@@ -167,6 +180,16 @@ fn run_emulator() -> Result<(), String> {
 
                     Some(Keycode::P) => palette_idx = (palette_idx + 1) % 8,
                     Some(Keycode::L) => log_pressed = true,
+
+                    Some(
+                        key @ (Keycode::F1
+                        | Keycode::F2
+                        | Keycode::F3
+                        | Keycode::F4
+                        | Keycode::F5
+                        | Keycode::F6),
+                    ) => handle_ram_search_key(&nes, &mut ram_search, key),
+                    Some(Keycode::F7) => flip_disk(&mut nes, &mut disk_side),
                     _ => {}
                 },
                 Event::KeyUp { keycode, .. } => match keycode {
@@ -205,6 +228,9 @@ fn run_emulator() -> Result<(), String> {
 
         // The rest of the game loop goes here...
         nes.run_until_frame();
+        if let Some(search) = ram_search.as_mut() {
+            search.update(&nes);
+        }
         let mut frame = nes.get_frame();
         let game_render = Surface::from_data(
             frame.get_data(),
@@ -372,6 +398,50 @@ fn handle_dpad(inputs: &mut InputData, state: HatState) {
     }
 }
 
+/// F1 starts a new search, F2-F5 keep the values that changed, stayed the same,
+/// increased or decreased since the last filter, F6 lists the candidates
+fn handle_ram_search_key(nes: &rnes::Nes, ram_search: &mut Option<RamSearch>, key: Keycode) {
+    let filter = match key {
+        Keycode::F2 => SearchFilter::Changed,
+        Keycode::F3 => SearchFilter::Unchanged,
+        Keycode::F4 => SearchFilter::Increased,
+        Keycode::F5 => SearchFilter::Decreased,
+        Keycode::F6 => {
+            for candidate in ram_search.iter().flat_map(|search| search.candidates()) {
+                eprintln!(
+                    "${:04X}: {} -> {}",
+                    candidate.address, candidate.previous, candidate.value
+                );
+            }
+            return;
+        }
+        _ => {
+            let search = RamSearch::new(nes, ValueSize::Byte, false);
+            eprintln!("ram search: {} candidates", search.len());
+            *ram_search = Some(search);
+            return;
+        }
+    };
+    if let Some(search) = ram_search.as_mut() {
+        eprintln!("ram search: {} candidates", search.filter(filter));
+    }
+}
+
+/// F7 ejects the FDS disk, pressing it again inserts the next side
+fn flip_disk(nes: &mut rnes::Nes, disk_side: &mut usize) {
+    if nes.disk_sides() == 0 {
+        return;
+    }
+    if nes.disk_side().is_some() {
+        nes.eject_disk();
+        eprintln!("disk ejected");
+    } else {
+        *disk_side = (*disk_side + 1) % nes.disk_sides();
+        nes.insert_disk(*disk_side);
+        eprintln!("disk side {} inserted", *disk_side + 1);
+    }
+}
+
 fn get_save_path(rom: &str) -> String {
     let rom_path = std::path::Path::new(rom);
     let rom_path = rom_path.parent().unwrap().join(rom_path.file_stem().unwrap());
@@ -379,3 +449,40 @@ fn get_save_path(rom: &str) -> String {
     eprintln!("rom: {}\n save: {}", rom, save_path);
     save_path
 }
+
+/// Title and board of the game when the database knows it, the file name otherwise
+fn get_window_title(nes: &rnes::Nes, rom: &str) -> String {
+    match nes.game() {
+        Some(game) => format!("rnes - {} ({})", game.title, game.board),
+        None => {
+            let name = std::path::Path::new(rom).file_stem().unwrap();
+            format!("rnes - {}", name.to_string_lossy())
+        }
+    }
+}
+
+/// Path of the `<rom>.<extension>` file next to the ROM, for the cheat lists
+fn get_rom_sibling_path(rom: &str, extension: &str) -> String {
+    let rom_path = std::path::Path::new(rom);
+    let rom_path = rom_path.parent().unwrap().join(rom_path.file_stem().unwrap());
+    format!("{}.{}", rom_path.to_str().unwrap(), extension)
+}
+
+/// One Game Genie code per line, the rest of the line is free for a description.
+/// Lines starting with # are comments.
+fn load_game_genie_codes(nes: &mut rnes::Nes, path: &str) {
+    let list = match std::fs::read_to_string(path) {
+        Ok(list) => list,
+        Err(_) => return,
+    };
+    for line in list.lines() {
+        let code = match line.split_whitespace().next() {
+            Some(code) if !code.starts_with('#') => code,
+            _ => continue,
+        };
+        match nes.add_cheat(code) {
+            Ok(()) => eprintln!("game genie: {}", line.trim()),
+            Err(e) => eprintln!("{}: invalid code {}: {}", path, code, e),
+        }
+    }
+}
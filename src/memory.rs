@@ -1,9 +1,13 @@
 use std::{cell::RefCell, rc::Rc};
 
 use crate::cartridge;
-use crate::cpu::addresses::{EXPANSION_ROM, IO_REGISTERS_START, PRG_ROM_LOWER};
+use crate::cartridge::GameInfo;
+use crate::cheats::{self, GameGenieCode};
+use crate::cpu::addresses::{EXPANSION_ROM, IO_REGISTERS_START};
+use crate::debugger::{Access, AddressSpace, WatchHit, Watchpoint};
 use crate::input::{Controller, InputData};
 use crate::ppu::PpuIoRegisters;
+use crate::state::{StateError, StateReader, StateWriter};
 pub(crate) use crate::{
     bus::{BusAction, PpuAction},
     Cartridge,
@@ -22,9 +26,24 @@ struct MemoryInt {
     dma_buffer: u8,
     dma_cycle: usize,
     dma_offset: u8,
+    /// Sample address requested by the DMC, the fetch starts on the next CPU cycle
+    dmc_request: Option<u16>,
+    dmc_address: u16,
+    /// CPU cycles left in the current DMC stall, the sample is read on the last one
+    dmc_stall: u8,
+    dmc_sample: Option<u8>,
+    cpu_last_read: u16,
+    cpu_last_write: bool,
     ppu_io_registers: PpuIoRegisters,
     ppu_v: u16,
+    /// Value of $4015, updated by the APU every cycle
+    apu_status: u8,
     controller1: Controller,
+    /// Enabled Game Genie codes
+    rom_patches: Vec<GameGenieCode>,
+    watchpoints: Vec<Watchpoint>,
+    /// First watched access since the debugger last looked
+    watch_hit: Option<WatchHit>,
 }
 impl MemoryInt {
     fn new() -> Self {
@@ -42,9 +61,19 @@ impl MemoryInt {
             dma_buffer: 0,
             dma_cycle: 1000, // 0-511 -> dma running, else ended
             dma_offset: 0,
+            dmc_request: None,
+            dmc_address: 0,
+            dmc_stall: 0,
+            dmc_sample: None,
+            cpu_last_read: 0,
+            cpu_last_write: false,
             ppu_io_registers: PpuIoRegisters::new(),
             ppu_v: 0,
+            apu_status: 0,
             controller1: Controller::new(),
+            rom_patches: Vec::new(),
+            watchpoints: Vec::new(),
+            watch_hit: None,
         }
     }
 
@@ -57,6 +86,8 @@ impl MemoryInt {
     fn reset(&mut self) {
         let mut new_self = Self::new();
         std::mem::swap(&mut self.cartridge, &mut new_self.cartridge);
+        std::mem::swap(&mut self.rom_patches, &mut new_self.rom_patches);
+        std::mem::swap(&mut self.watchpoints, &mut new_self.watchpoints);
         *self = new_self;
         self.cartridge.reset();
     }
@@ -65,14 +96,45 @@ impl MemoryInt {
         let address = Self::cpu_unmirror_address(address);
 
         // if address < CARTRIDGE_SPACE {
-        if address < PRG_ROM_LOWER {
+        if address < IO_REGISTERS_START {
             self.cpu_memory[address as usize]
+        } else if address < 0x4000 {
+            // what a read would return, without the PPU seeing it
+            match address {
+                0x2002 => self.ppu_io_registers.status,
+                0x2004 => self.oam_memory[self.oam_address_mirror as usize],
+                0x2007 => self.ppudata_buffer,
+                _ => self.ppu_io_registers.last_written,
+            }
+        } else if address < EXPANSION_ROM {
+            match address {
+                0x4015 => self.apu_status,
+                _ => 0,
+            }
         } else {
-            self.cartridge.cpu_read(address)
+            self.cartridge_read(address)
+        }
+    }
+
+    /// Writes RAM or the cartridge byte mapped at `address` without side effects,
+    /// the I/O registers are left alone
+    fn cpu_poke(&mut self, address: u16, value: u8) {
+        let address = Self::cpu_unmirror_address(address);
+        if address < IO_REGISTERS_START {
+            self.cpu_memory[address as usize] = value;
+        } else if address >= EXPANSION_ROM {
+            self.cartridge.cpu_poke(address, value);
         }
     }
 
     fn cpu_read_u8(&mut self, address: u16) -> u8 {
+        let value = self.cpu_bus_read_u8(address);
+        let unmirrored = Self::cpu_unmirror_address(address);
+        self.watch(AddressSpace::Cpu, address, unmirrored, value, Access::Read);
+        value
+    }
+
+    fn cpu_bus_read_u8(&mut self, address: u16) -> u8 {
         let address = Self::cpu_unmirror_address(address);
 
         // if address < CARTRIDGE_SPACE {
@@ -118,6 +180,10 @@ impl MemoryInt {
             } else {
                 // 0x4000-0x4020
                 match address {
+                    0x4015 => {
+                        self.bus_action = BusAction::ApuStatusRead;
+                        self.apu_status
+                    }
                     0x4016 => {
                         // Controller 1
                         let val = self.controller1.read_from();
@@ -133,12 +199,27 @@ impl MemoryInt {
         //} else if address < PRG_ROM_LOWER {
             //self.cpu_memory[address as usize]
         } else {
-            self.cartridge.cpu_read(address)
+            let value = self.cartridge.cpu_bus_read(address);
+            self.patch_read(address, value)
+        }
+    }
+
+    fn cartridge_read(&self, address: u16) -> u8 {
+        self.patch_read(address, self.cartridge.cpu_read(address))
+    }
+
+    fn patch_read(&self, address: u16, value: u8) -> u8 {
+        if self.rom_patches.is_empty() {
+            value
+        } else {
+            cheats::patch_read(&self.rom_patches, address, value)
         }
     }
 
     fn cpu_write_u8(&mut self, address: u16, value: u8) {
-        let address = Self::cpu_unmirror_address(address);
+        let unmirrored = Self::cpu_unmirror_address(address);
+        self.watch(AddressSpace::Cpu, address, unmirrored, value, Access::Write);
+        let address = unmirrored;
 
         // if address < CARTRIDGE_SPACE {
         if address < IO_REGISTERS_START {
@@ -245,8 +326,11 @@ impl MemoryInt {
     }
 
     fn ppu_write(&mut self, address: u16, value: u8) {
-        let address = Self::ppu_unmirror_address_write(address);
+        let unmirrored = Self::ppu_unmirror_address_write(address);
+        self.watch(AddressSpace::Ppu, address, unmirrored, value, Access::Write);
+        let address = unmirrored;
         if address < 0x3F00 {
+            self.cartridge.ppu_address(address);
             self.cartridge
                 .ppu_write(address, value, &mut self.internal_vram);
         } else {
@@ -255,6 +339,45 @@ impl MemoryInt {
     }
 
     fn ppu_read(&mut self, address: u16) -> u8 {
+        let bus_address = address;
+        let address = Self::ppu_unmirror_address_read(address);
+        let value = if address < 0x3F00 {
+            self.cartridge.ppu_address(address);
+            self.cartridge.ppu_read(address, &self.internal_vram)
+        } else {
+            self.palette_ram_indexes[address as usize - 0x3F00]
+        };
+        self.watch(AddressSpace::Ppu, bus_address, address, value, Access::Read);
+        value
+    }
+
+    /// Records the access if a watchpoint covers either the address on the bus
+    /// or the one it mirrors
+    fn watch(
+        &mut self,
+        space: AddressSpace,
+        address: u16,
+        unmirrored: u16,
+        value: u8,
+        access: Access,
+    ) {
+        if self.watchpoints.is_empty() || self.watch_hit.is_some() {
+            return;
+        }
+        if self.watchpoints.iter().any(|w| {
+            w.matches(space, address, access) || w.matches(space, unmirrored, access)
+        }) {
+            self.watch_hit = Some(WatchHit {
+                space,
+                address: unmirrored,
+                value,
+                access,
+            });
+        }
+    }
+
+    /// Reads PPU memory without the mapper seeing the access
+    fn ppu_peek(&self, address: u16) -> u8 {
         let address = Self::ppu_unmirror_address_read(address);
         if address < 0x3F00 {
             self.cartridge.ppu_read(address, &self.internal_vram)
@@ -263,6 +386,18 @@ impl MemoryInt {
         }
     }
 
+    /// Writes PPU memory without the mapper seeing the access, pattern tables
+    /// backed by CHR ROM ignore the write
+    fn ppu_poke(&mut self, address: u16, value: u8) {
+        let address = Self::ppu_unmirror_address_write(address);
+        if address < 0x3F00 {
+            self.cartridge
+                .ppu_write(address, value, &mut self.internal_vram);
+        } else {
+            self.palette_ram_indexes[address as usize - 0x3F00] = value;
+        }
+    }
+
     fn ppu_oam_read(&mut self, address: usize) -> u8 {
         self.oam_memory[address]
     }
@@ -305,6 +440,38 @@ impl MemoryInt {
     }
 
     fn try_dma(&mut self) -> bool {
+        if self.dmc_stall == 0 {
+            if let Some(address) = self.dmc_request.take() {
+                self.dmc_address = address;
+                self.dmc_stall = if self.dma_cycle < 512 {
+                    // the fetch reuses the OAM DMA alignment cycles
+                    match self.dma_cycle {
+                        510 => 1,
+                        511 => 3,
+                        _ => 2,
+                    }
+                } else if self.cpu_last_write {
+                    // the CPU can only be halted on a read, the write counts as the first cycle
+                    3
+                } else {
+                    4
+                };
+            }
+        }
+
+        if self.dmc_stall > 0 {
+            self.dmc_stall -= 1;
+            if self.dmc_stall == 0 {
+                self.dmc_sample = Some(self.cpu_read_u8(self.dmc_address));
+            } else if self.dma_cycle >= 512
+                && (self.cpu_last_read == 0x4016 || self.cpu_last_read == 0x4017)
+            {
+                // the halted CPU keeps repeating its last read, clocking the controller again
+                let _ = self.cpu_read_u8(self.cpu_last_read);
+            }
+            return true;
+        }
+
         if self.dma_cycle < 512 {
             if self.dma_cycle % 2 == 0 {
                 self.dma_buffer = self.cpu_read_u8(self.dma_address + self.dma_offset as u16);
@@ -323,6 +490,78 @@ impl MemoryInt {
     fn set_controller1_input(&mut self, input_data: InputData) {
         self.controller1.set_input(input_data);
     }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.cpu_memory);
+        writer.write_bytes(&self.internal_vram);
+        writer.write_bytes(&self.palette_ram_indexes);
+        writer.write_u8(self.ppudata_buffer);
+        writer.write_bytes(&self.oam_memory);
+        writer.write_u8(self.oam_address_mirror);
+        writer.write_u16(self.dma_address);
+        writer.write_u8(self.dma_buffer);
+        writer.write_usize(self.dma_cycle);
+        writer.write_u8(self.dma_offset);
+        writer.write_bool(self.dmc_request.is_some());
+        writer.write_u16(self.dmc_request.unwrap_or(0));
+        writer.write_u16(self.dmc_address);
+        writer.write_u8(self.dmc_stall);
+        writer.write_bool(self.dmc_sample.is_some());
+        writer.write_u8(self.dmc_sample.unwrap_or(0));
+        writer.write_u16(self.cpu_last_read);
+        writer.write_bool(self.cpu_last_write);
+        writer.write_u8(self.ppu_io_registers.status);
+        writer.write_u8(self.ppu_io_registers.last_written);
+        writer.write_u16(self.ppu_v);
+        writer.write_u8(self.apu_status);
+        self.controller1.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.read_bytes_into(&mut self.cpu_memory)?;
+        reader.read_bytes_into(&mut self.internal_vram)?;
+        reader.read_bytes_into(&mut self.palette_ram_indexes)?;
+        self.ppudata_buffer = reader.read_u8()?;
+        reader.read_bytes_into(&mut self.oam_memory)?;
+        self.oam_address_mirror = reader.read_u8()?;
+        self.dma_address = reader.read_u16()?;
+        self.dma_buffer = reader.read_u8()?;
+        self.dma_cycle = reader.read_usize()?;
+        self.dma_offset = reader.read_u8()?;
+        if reader.version() >= 2 {
+            let has_request = reader.read_bool()?;
+            let request = reader.read_u16()?;
+            self.dmc_request = if has_request { Some(request) } else { None };
+            self.dmc_address = reader.read_u16()?;
+            self.dmc_stall = reader.read_u8()?;
+            let has_sample = reader.read_bool()?;
+            let sample = reader.read_u8()?;
+            self.dmc_sample = if has_sample { Some(sample) } else { None };
+            self.cpu_last_read = reader.read_u16()?;
+            self.cpu_last_write = reader.read_bool()?;
+        } else {
+            // version 1 had no DMC DMA and didn't track the last bus access
+            self.dmc_request = None;
+            self.dmc_address = 0;
+            self.dmc_stall = 0;
+            self.dmc_sample = None;
+            self.cpu_last_read = 0;
+            self.cpu_last_write = false;
+        }
+        self.ppu_io_registers.status = reader.read_u8()?;
+        self.ppu_io_registers.last_written = reader.read_u8()?;
+        self.ppu_v = reader.read_u16()?;
+        // version 1 didn't save the APU status, the APU refreshes it on its next tick
+        self.apu_status = if reader.version() >= 2 {
+            reader.read_u8()?
+        } else {
+            0
+        };
+        self.controller1.load_state(reader)?;
+        // bus actions are consumed within the same tick, there is never one pending
+        self.bus_action = BusAction::None;
+        Ok(())
+    }
 }
 
 pub struct CpuMemory(Rc<RefCell<MemoryInt>>);
@@ -332,11 +571,16 @@ impl CpuMemory {
     }
 
     pub(crate) fn read_u8(&mut self, address: u16) -> u8 {
-        self.0.as_ref().borrow_mut().cpu_read_u8(address)
+        let mut memory = self.0.as_ref().borrow_mut();
+        memory.cpu_last_read = address;
+        memory.cpu_last_write = false;
+        memory.cpu_read_u8(address)
     }
 
     pub(crate) fn write_u8(&mut self, address: u16, value: u8) {
-        self.0.as_ref().borrow_mut().cpu_write_u8(address, value)
+        let mut memory = self.0.as_ref().borrow_mut();
+        memory.cpu_last_write = true;
+        memory.cpu_write_u8(address, value)
     }
 
     pub(crate) fn read_u16(&mut self, address: u16) -> u16 {
@@ -378,6 +622,10 @@ impl PpuMemory {
         self.0.as_ref().borrow_mut().ppu_write(address, value);
     }
 
+    pub(crate) fn peek(&self, address: u16) -> u8 {
+        self.0.as_ref().borrow().ppu_peek(address)
+    }
+
     pub(crate) fn print_oam(&self) {
         println!("oam: {:?}", self.0.as_ref().borrow().oam_memory);
     }
@@ -414,15 +662,147 @@ impl MemoryHandle {
         self.0.as_ref().borrow().cartridge.get_save_data()
     }
 
+    pub(crate) fn has_battery(&self) -> bool {
+        self.0.as_ref().borrow().cartridge.has_battery()
+    }
+
+    pub(crate) fn game(&self) -> Option<&'static GameInfo> {
+        self.0.as_ref().borrow().cartridge.game()
+    }
+
     pub(crate) fn set_save_data(&mut self, data: Vec<u8>) {
         self.0.as_ref().borrow_mut().cartridge.set_save_data(data);
     }
+
+    pub(crate) fn disk_sides(&self) -> usize {
+        self.0.as_ref().borrow().cartridge.disk_sides()
+    }
+
+    pub(crate) fn disk_side(&self) -> Option<usize> {
+        self.0.as_ref().borrow().cartridge.disk_side()
+    }
+
+    pub(crate) fn insert_disk(&mut self, side: usize) -> bool {
+        self.0.as_ref().borrow_mut().cartridge.insert_disk(side)
+    }
+
+    pub(crate) fn eject_disk(&mut self) {
+        self.0.as_ref().borrow_mut().cartridge.eject_disk();
+    }
+
+    pub(crate) fn disk_image(&self) -> Option<Vec<u8>> {
+        self.0.as_ref().borrow().cartridge.disk_image()
+    }
+
+    pub(crate) fn load_disk_image(&mut self, image: &[u8]) -> bool {
+        self.0.as_ref().borrow_mut().cartridge.load_disk_image(image)
+    }
+
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        self.0.as_ref().borrow().save_state(writer);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.0.as_ref().borrow_mut().load_state(reader)
+    }
+
+    pub(crate) fn save_mapper_state(&self, writer: &mut StateWriter) {
+        self.0.as_ref().borrow().cartridge.save_state(writer);
+    }
+
+    pub(crate) fn load_mapper_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.0.as_ref().borrow_mut().cartridge.load_state(reader)
+    }
+
+    pub(crate) fn mapper_irq(&self) -> bool {
+        self.0.as_ref().borrow().cartridge.irq()
+    }
+
+    pub(crate) fn cartridge_fingerprint(&self) -> u32 {
+        self.0.as_ref().borrow().cartridge.fingerprint()
+    }
+
+    pub(crate) fn poke(&mut self, address: u16, value: u8) {
+        self.0.as_ref().borrow_mut().cpu_poke(address, value);
+    }
+
+    pub(crate) fn peek_ppu(&self, address: u16) -> u8 {
+        self.0.as_ref().borrow().ppu_peek(address)
+    }
+
+    pub(crate) fn poke_ppu(&mut self, address: u16, value: u8) {
+        self.0.as_ref().borrow_mut().ppu_poke(address, value);
+    }
+
+    pub(crate) fn peek_oam(&self, address: u8) -> u8 {
+        self.0.as_ref().borrow().oam_memory[address as usize]
+    }
+
+    pub(crate) fn poke_oam(&mut self, address: u8, value: u8) {
+        self.0.as_ref().borrow_mut().oam_memory[address as usize] = value;
+    }
+
+    pub(crate) fn prg_rom_size(&self) -> usize {
+        self.0.as_ref().borrow().cartridge.prg_rom_size()
+    }
+
+    pub(crate) fn peek_prg(&self, offset: usize) -> Option<u8> {
+        self.0.as_ref().borrow().cartridge.peek_prg(offset)
+    }
+
+    pub(crate) fn poke_prg(&mut self, offset: usize, value: u8) -> bool {
+        self.0.as_ref().borrow_mut().cartridge.poke_prg(offset, value)
+    }
+
+    pub(crate) fn chr_size(&self) -> usize {
+        self.0.as_ref().borrow().cartridge.chr_size()
+    }
+
+    pub(crate) fn peek_chr(&self, offset: usize) -> Option<u8> {
+        self.0.as_ref().borrow().cartridge.peek_chr(offset)
+    }
+
+    pub(crate) fn poke_chr(&mut self, offset: usize, value: u8) -> bool {
+        self.0.as_ref().borrow_mut().cartridge.poke_chr(offset, value)
+    }
+
+    pub(crate) fn set_rom_patches(&mut self, patches: Vec<GameGenieCode>) {
+        self.0.as_ref().borrow_mut().rom_patches = patches;
+    }
+
+    pub(crate) fn watchpoints(&self) -> Vec<Watchpoint> {
+        self.0.as_ref().borrow().watchpoints.clone()
+    }
+
+    pub(crate) fn set_watchpoints(&mut self, watchpoints: Vec<Watchpoint>) {
+        let mut memory = self.0.as_ref().borrow_mut();
+        memory.watchpoints = watchpoints;
+        memory.watch_hit = None;
+    }
+
+    pub(crate) fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.0.as_ref().borrow_mut().watch_hit.take()
+    }
 }
 
 pub struct ApuMemory(Rc<RefCell<MemoryInt>>);
 impl ApuMemory {
-    pub(crate) fn read_u8(&mut self, address: u16) -> u8 {
-        self.0.as_ref().borrow_mut().cpu_read_u8(address)
+    /// Asks for a sample byte, the CPU is stalled while it's fetched
+    pub(crate) fn request_dmc_fetch(&mut self, address: u16) {
+        self.0.as_ref().borrow_mut().dmc_request = Some(address);
+    }
+
+    pub(crate) fn take_dmc_sample(&mut self) -> Option<u8> {
+        self.0.as_ref().borrow_mut().dmc_sample.take()
+    }
+
+    pub(crate) fn set_status(&mut self, status: u8) {
+        self.0.as_ref().borrow_mut().apu_status = status;
+    }
+
+    /// Output of the cartridge sound chip
+    pub(crate) fn expansion_audio(&self) -> f32 {
+        self.0.as_ref().borrow().cartridge.audio_output()
     }
 }
 
@@ -0,0 +1,122 @@
+//! Comparison of the CPU trace with the nestest.log golden log
+
+/// nestest.nes runs every test without a display when started from $C000
+pub(crate) const START: u16 = 0xC000;
+/// nestest.log is recorded after the 7 cycles of the reset sequence
+pub(crate) const START_CYCLE: usize = 7;
+/// Scanline and dot of the PPU on the first line of nestest.log
+pub(crate) const START_PPU_POSITION: (usize, usize) = (0, 21);
+/// Lines shown before the diverging one
+pub(crate) const CONTEXT_LINES: usize = 5;
+/// Way more than nestest needs, stops a run stuck on a jammed CPU
+pub(crate) const MAX_CYCLES: usize = 100_000;
+
+#[derive(Debug, Clone)]
+pub struct NestestMismatch {
+    /// 1-based line number in the golden log
+    pub line: usize,
+    pub expected: String,
+    /// `None` when the CPU stopped executing instructions
+    pub found: Option<String>,
+    /// Matching lines before the mismatch, oldest first
+    pub context: Vec<String>,
+}
+
+impl NestestMismatch {
+    pub(crate) fn new(index: usize, expected: &[&str], found: Option<String>) -> Self {
+        let start = index.saturating_sub(CONTEXT_LINES);
+        Self {
+            line: index + 1,
+            expected: expected[index].to_string(),
+            found,
+            context: expected[start..index]
+                .iter()
+                .map(|l| l.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for NestestMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "nestest diverged at line {}", self.line)?;
+        for line in self.context.iter() {
+            writeln!(f, "           {}", line)?;
+        }
+        writeln!(f, "expected:  {}", self.expected)?;
+        match &self.found {
+            Some(found) => {
+                writeln!(f, "found:     {}", found)?;
+                // mark the first differing column
+                let column = self
+                    .expected
+                    .chars()
+                    .zip(found.chars())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                write!(f, "           {}^", " ".repeat(column))
+            }
+            None => write!(
+                f,
+                "found:     nothing, the CPU stopped executing instructions"
+            ),
+        }
+    }
+}
+
+/// Golden log lines, without the line endings and blank lines
+pub(crate) fn golden_lines(log: &str) -> Vec<&str> {
+    log.lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cartridge, Nes};
+
+    /// The first lines of nestest.log
+    const GOLDEN: &str = "\
+C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10
+C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 36 CYC:12
+C5F9  86 10     STX $10 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 45 CYC:15
+C5FB  86 11     STX $11 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 54 CYC:18
+C5FD  20 2D C7  JSR $C72D                       A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 63 CYC:21
+C72D  EA        NOP                             A:00 X:00 Y:00 P:26 SP:FB PPU:  0, 81 CYC:27
+C72E  38        SEC                             A:00 X:00 Y:00 P:26 SP:FB PPU:  0, 87 CYC:29
+C72F  B0 04     BCS $C735                       A:00 X:00 Y:00 P:27 SP:FB PPU:  0, 93 CYC:31
+C735  EA        NOP                             A:00 X:00 Y:00 P:27 SP:FB PPU:  0,102 CYC:34
+";
+
+    /// The code nestest.nes runs for the lines above
+    fn nestest_start() -> Nes {
+        let mut memory = vec![0xEA; 0x10000];
+        memory[0xC000..0xC003].copy_from_slice(&[0x4C, 0xF5, 0xC5]);
+        memory[0xC5F5..0xC600].copy_from_slice(&[
+            0xA2, 0x00, 0x86, 0x00, 0x86, 0x10, 0x86, 0x11, 0x20, 0x2D, 0xC7,
+        ]);
+        memory[0xC72D..0xC731].copy_from_slice(&[0xEA, 0x38, 0xB0, 0x04]);
+        memory[0xFFFC] = 0x00;
+        memory[0xFFFD] = 0xC0;
+        Nes::with_cartridge(Cartridge::from_vec(memory))
+    }
+
+    #[test]
+    fn trace_matches_nestest_log() {
+        let mut nes = nestest_start();
+        assert_eq!(nes.run_nestest(GOLDEN).unwrap(), 10);
+    }
+
+    #[test]
+    fn reports_first_diverging_line() {
+        let golden = GOLDEN.replace("STX $11 = 00", "STX $11 = 01");
+        let mut nes = nestest_start();
+        let mismatch = nes.run_nestest(&golden).unwrap_err();
+        assert_eq!(mismatch.line, 5);
+        assert!(mismatch.found.unwrap().contains("STX $11 = 00"));
+        assert_eq!(mismatch.context.len(), 4);
+        assert!(mismatch.context[0].starts_with("C000"));
+    }
+}
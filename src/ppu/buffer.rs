@@ -1,3 +1,5 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
 pub struct Buffer {
     width: usize,
     height: usize,
@@ -18,6 +20,14 @@ impl Buffer {
         &mut self.data[..]
     }
 
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.data);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.read_bytes_into(&mut self.data)
+    }
+
     pub fn set_pixel(&mut self, x: usize, y: usize, r: u8, g: u8, b: u8) {
         self.data[4 * (x + self.width * y)] = 255;
         self.data[4 * (x + self.width * y) + 1] = b;
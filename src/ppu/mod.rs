@@ -2,7 +2,11 @@ pub mod buffer;
 mod sprite_unit;
 
 use self::sprite_unit::SpriteUnit;
-use crate::{bus::PpuAction, memory::PpuMemory};
+use crate::{
+    bus::PpuAction,
+    memory::PpuMemory,
+    state::{StateError, StateReader, StateWriter},
+};
 use buffer::Buffer;
 
 const IMAGE_COLOR_PALETTE_ADDRESS: u16 = 0x3F00;
@@ -97,6 +101,42 @@ enum SpriteEvaluationState {
     Copy3(usize),
 }
 
+impl SpriteEvaluationState {
+    fn save_state(&self, writer: &mut StateWriter) {
+        let (tag, n) = match *self {
+            SpriteEvaluationState::Read0(n) => (0, n),
+            SpriteEvaluationState::Copy0(n) => (1, n),
+            SpriteEvaluationState::Read1(n) => (2, n),
+            SpriteEvaluationState::Copy1(n) => (3, n),
+            SpriteEvaluationState::Read2(n) => (4, n),
+            SpriteEvaluationState::Copy2(n) => (5, n),
+            SpriteEvaluationState::Read3(n) => (6, n),
+            SpriteEvaluationState::Copy3(n) => (7, n),
+        };
+        writer.write_u8(tag);
+        writer.write_usize(n);
+    }
+
+    fn load_state(reader: &mut StateReader) -> Result<Self, StateError> {
+        let tag = reader.read_u8()?;
+        let n = reader.read_usize()?;
+        if n >= 64 {
+            return Err(StateError::InvalidValue("sprite evaluation index"));
+        }
+        match tag {
+            0 => Ok(SpriteEvaluationState::Read0(n)),
+            1 => Ok(SpriteEvaluationState::Copy0(n)),
+            2 => Ok(SpriteEvaluationState::Read1(n)),
+            3 => Ok(SpriteEvaluationState::Copy1(n)),
+            4 => Ok(SpriteEvaluationState::Read2(n)),
+            5 => Ok(SpriteEvaluationState::Copy2(n)),
+            6 => Ok(SpriteEvaluationState::Read3(n)),
+            7 => Ok(SpriteEvaluationState::Copy3(n)),
+            _ => Err(StateError::InvalidValue("sprite evaluation state")),
+        }
+    }
+}
+
 impl Ppu {
     pub(crate) fn new(memory: PpuMemory) -> Self {
         Ppu {
@@ -207,6 +247,148 @@ impl Ppu {
         }
     }
 
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        // The frame being drawn is saved too, so a state taken mid-frame resumes the same image
+        self.current_frame.save_state(writer);
+        writer.write_bool(self.even_frame);
+        writer.write_usize(self.cycles);
+        writer.write_usize(self.x);
+        writer.write_usize(self.y);
+        writer.write_bool(self.nmi_enabled);
+        writer.write_bool(self.vblank_flag);
+        writer.write_bool(self.rendering_enabled);
+
+        writer.write_bool(self.sprite_zero_hit);
+
+        writer.write_u8(self.sprite_height);
+        writer.write_u16(self.sprite_pattern_table);
+        writer.write_u16(self.background_pattern_table);
+        writer.write_u16(self.sprites_pattern_table);
+        writer.write_u16(self.vram_address_increment);
+
+        writer.write_u16(self.reg_v);
+        writer.write_u16(self.reg_t);
+        writer.write_u8(self.reg_x);
+        writer.write_bool(self.reg_w);
+
+        writer.write_u8(self.fine_x);
+        writer.write_u8(self.x_increment_counter);
+
+        writer.write_bool(self.current_line_has_sprite_zero);
+        writer.write_bool(self.next_line_has_sprite_zero);
+        self.sprite_evaluation_state.save_state(writer);
+        writer.write_usize(self.oam_n);
+        writer.write_bytes(&self.secondary_oam);
+        writer.write_usize(self.secondary_oam_pointer);
+        writer.write_u8(self.oam_addr);
+        writer.write_u8(self.oam_buffer);
+        writer.write_bool(self.oam_initializing);
+
+        for unit in self.sprite_units.iter() {
+            unit.save_state(writer);
+        }
+        writer.write_u16(self.sprite_pattern_address);
+
+        writer.write_bool(self.grayscale);
+        writer.write_bool(self.show_backgroudn_leftmost);
+        writer.write_bool(self.show_sprites_leftmost);
+        writer.write_bool(self.show_background);
+        writer.write_bool(self.show_sprites);
+        writer.write_bool(self.emphasize_red);
+        writer.write_bool(self.emphasize_green);
+        writer.write_bool(self.emphasize_blue);
+
+        writer.write_u8(self.pattern_table_buffer1);
+        writer.write_u8(self.pattern_table_buffer2);
+        writer.write_u8(self.current_tile);
+        writer.write_u8(self.current_attribute_quadrant);
+        writer.write_u16(self.current_attribute_address);
+        writer.write_u16(self.pattern_table_reg1);
+        writer.write_u16(self.pattern_table_reg2);
+        writer.write_u8(self.palette_attribute_buffer1);
+        writer.write_u8(self.palette_attribute_buffer2);
+        writer.write_u16(self.palette_attriubutes_reg1);
+        writer.write_u16(self.palette_attriubutes_reg2);
+
+        writer.write_u8(self.io_latch);
+
+        writer.write_usize(self.pixels);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.current_frame.load_state(reader)?;
+        self.even_frame = reader.read_bool()?;
+        self.cycles = reader.read_usize()?;
+        self.x = reader.read_usize()?;
+        self.y = reader.read_usize()?;
+        if self.x > 340 || self.y > 261 {
+            return Err(StateError::InvalidValue("PPU position"));
+        }
+        self.nmi_enabled = reader.read_bool()?;
+        self.vblank_flag = reader.read_bool()?;
+        self.rendering_enabled = reader.read_bool()?;
+
+        self.sprite_zero_hit = reader.read_bool()?;
+
+        self.sprite_height = reader.read_u8()?;
+        self.sprite_pattern_table = reader.read_u16()?;
+        self.background_pattern_table = reader.read_u16()?;
+        self.sprites_pattern_table = reader.read_u16()?;
+        self.vram_address_increment = reader.read_u16()?;
+
+        self.reg_v = reader.read_u16()?;
+        self.reg_t = reader.read_u16()?;
+        self.reg_x = reader.read_u8()?;
+        self.reg_w = reader.read_bool()?;
+
+        self.fine_x = reader.read_u8()?;
+        self.x_increment_counter = reader.read_u8()?;
+
+        self.current_line_has_sprite_zero = reader.read_bool()?;
+        self.next_line_has_sprite_zero = reader.read_bool()?;
+        self.sprite_evaluation_state = SpriteEvaluationState::load_state(reader)?;
+        self.oam_n = reader.read_usize()?;
+        reader.read_bytes_into(&mut self.secondary_oam)?;
+        self.secondary_oam_pointer = reader.read_usize()?;
+        if self.secondary_oam_pointer > self.secondary_oam.len() {
+            return Err(StateError::InvalidValue("secondary OAM pointer"));
+        }
+        self.oam_addr = reader.read_u8()?;
+        self.oam_buffer = reader.read_u8()?;
+        self.oam_initializing = reader.read_bool()?;
+
+        for unit in self.sprite_units.iter_mut() {
+            unit.load_state(reader)?;
+        }
+        self.sprite_pattern_address = reader.read_u16()?;
+
+        self.grayscale = reader.read_bool()?;
+        self.show_backgroudn_leftmost = reader.read_bool()?;
+        self.show_sprites_leftmost = reader.read_bool()?;
+        self.show_background = reader.read_bool()?;
+        self.show_sprites = reader.read_bool()?;
+        self.emphasize_red = reader.read_bool()?;
+        self.emphasize_green = reader.read_bool()?;
+        self.emphasize_blue = reader.read_bool()?;
+
+        self.pattern_table_buffer1 = reader.read_u8()?;
+        self.pattern_table_buffer2 = reader.read_u8()?;
+        self.current_tile = reader.read_u8()?;
+        self.current_attribute_quadrant = reader.read_u8()?;
+        self.current_attribute_address = reader.read_u16()?;
+        self.pattern_table_reg1 = reader.read_u16()?;
+        self.pattern_table_reg2 = reader.read_u16()?;
+        self.palette_attribute_buffer1 = reader.read_u8()?;
+        self.palette_attribute_buffer2 = reader.read_u8()?;
+        self.palette_attriubutes_reg1 = reader.read_u16()?;
+        self.palette_attriubutes_reg2 = reader.read_u16()?;
+
+        self.io_latch = reader.read_u8()?;
+
+        self.pixels = reader.read_usize()?;
+        Ok(())
+    }
+
     fn rendering(&mut self) {
         if self.x == 0 {
             // Idle cycle
@@ -311,6 +493,11 @@ impl Ppu {
 
     fn sprite_evaluation(&mut self) {
         //if self.x == 0 || (self.y != 261 && self.y >= 240) {
+        if self.y == 261 && self.x >= 257 && self.x <= 320 {
+            // no sprites are evaluated for the pre-render line but the fetches still happen
+            self.empty_sprite_fetch();
+            return;
+        }
         if self.x == 0 || self.y >= 240 {
             return;
         }
@@ -434,10 +621,32 @@ impl Ppu {
                 }
             } else {
                 self.sprite_units[sprite_id].set_transparent();
+                self.empty_sprite_fetch();
             }
         }
     }
 
+    /// Empty slots still fetch tile $FF, mappers watching A12 (MMC3) rely on it
+    fn empty_sprite_fetch(&mut self) {
+        if !self.is_rendering_enabled() {
+            return;
+        }
+        let table = if self.sprite_height == 8 {
+            self.sprite_pattern_table
+        } else {
+            0x1000
+        };
+        match (self.x - 257) % 8 {
+            5 => {
+                let _ = self.memory.read(table | 0x0FF0);
+            }
+            7 => {
+                let _ = self.memory.read(table | 0x0FF8);
+            }
+            _ => {}
+        }
+    }
+
     fn get_sprite_color(&self) -> (u8, bool, bool) {
         for (i, sprite) in self.sprite_units.iter().enumerate() {
             let color = sprite.get_color();
@@ -568,6 +777,18 @@ impl Ppu {
         self.buffers.push(buffer);
     }
 
+    /// Scanline and dot of the next PPU cycle
+    pub fn position(&self) -> (usize, usize) {
+        (self.y, self.x)
+    }
+
+    /// The reset line clears PPUCTRL, PPUMASK and the write toggle
+    pub fn reset(&mut self) {
+        self.handle_bus_action(PpuAction::PpuCtrlWrite(0));
+        self.handle_bus_action(PpuAction::PpuMaskWrite(0));
+        self.reg_w = false;
+    }
+
     fn handle_bus_action(&mut self, action: PpuAction) {
         match action {
             PpuAction::PpuCtrlWrite(val) => {
@@ -811,10 +1032,10 @@ impl Ppu {
             for x in 0..32 {
                 let tile_offset = y * 32 + x;
                 let tile_addr_offset =
-                    self.memory.read(nametable_addr + tile_offset) as u16 * 16;
+                    self.memory.peek(nametable_addr + tile_offset) as u16 * 16;
 
                 let attribute_addr_offset = ((y & 0b11100) << 1) | ((x & 0b11100) >> 2);
-                let attribute_byte = self.memory.read(nametable_addr + 960 + attribute_addr_offset);
+                let attribute_byte = self.memory.peek(nametable_addr + 960 + attribute_addr_offset);
                 let attribute_idx = y & 0b10 | ((x & 0b10) >> 1);
                 
                 let palette = (attribute_byte >> (2 * attribute_idx)) & 0b11;
@@ -836,17 +1057,17 @@ impl Ppu {
     fn render_tile(&mut self, address: u16, palette_idx: usize) -> [[u8; 8]; 8] {
         let mut palette = [0u8; 4];
 
-        let bg_color = self.memory.read(IMAGE_COLOR_PALETTE_ADDRESS);
+        let bg_color = self.memory.peek(IMAGE_COLOR_PALETTE_ADDRESS);
         for i in 0..4 {
             palette[i] = self
                 .memory
-                .read(IMAGE_COLOR_PALETTE_ADDRESS + palette_idx as u16 * 4 + i as u16);
+                .peek(IMAGE_COLOR_PALETTE_ADDRESS + palette_idx as u16 * 4 + i as u16);
         }
 
         let mut out = [[0; 8]; 8];
         for y in 0..8 {
-            let low = self.memory.read(address + y);
-            let high = self.memory.read(address + y + 8);
+            let low = self.memory.peek(address + y);
+            let high = self.memory.peek(address + y + 8);
 
             for x in 0..8 {
                 let pixel = ((low >> (7 - x)) & 1) | (((high >> (7 - x)) & 1) << 1);
@@ -867,7 +1088,7 @@ impl Ppu {
         for y in 0..2 {
             for x in 0..16 {
                 let color_idx = y * 16 + x;
-                let color_idx = self.memory.read(color_idx + 0x3F00);
+                let color_idx = self.memory.peek(color_idx + 0x3F00);
 
                 let (r, g, b) = map_system_color_to_rgb(color_idx);
                 for y_fine in 0..16 {
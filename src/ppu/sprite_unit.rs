@@ -1,3 +1,5 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
 pub(crate) struct SpriteUnit {
     high_color: u8,
     low_color: u8,
@@ -32,6 +34,25 @@ impl SpriteUnit {
         ]
     }
 
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.high_color);
+        writer.write_u8(self.low_color);
+        writer.write_u8(self.attributes);
+        writer.write_u8(self.position);
+        writer.write_u8(self.tile_number);
+        writer.write_u8(self.y);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.high_color = reader.read_u8()?;
+        self.low_color = reader.read_u8()?;
+        self.attributes = reader.read_u8()?;
+        self.position = reader.read_u8()?;
+        self.tile_number = reader.read_u8()?;
+        self.y = reader.read_u8()?;
+        Ok(())
+    }
+
     pub(crate) fn set_transparent(&mut self) {
         self.high_color = 0;
         self.low_color = 0;
@@ -0,0 +1,311 @@
+//! RAM search: narrows down the work RAM and SRAM addresses holding a game value by how
+//! the value changes between snapshots
+
+use crate::Nes;
+
+pub const WORK_RAM_SIZE: usize = 0x0800;
+pub const SAVE_RAM_START: u16 = 0x6000;
+/// PRG RAM window at $6000-$7FFF, banked RAMs are searched through the bank mapped there
+pub const SAVE_RAM_SIZE: usize = 0x2000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueSize {
+    Byte,
+    /// Little endian, starting at the candidate address
+    Word,
+}
+
+/// Compares the latest snapshot with the one taken at the previous filter
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SearchFilter {
+    Unchanged,
+    Changed,
+    Increased,
+    Decreased,
+    /// Holds exactly this value now
+    Equal(i32),
+    /// Changed by exactly this amount
+    ChangedBy(i32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Candidate {
+    pub address: u16,
+    /// Value when the last filter ran
+    pub previous: i32,
+    pub value: i32,
+}
+
+/// Work RAM and battery RAM content
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RamSnapshot {
+    ram: Vec<u8>,
+    save_ram: Vec<u8>,
+}
+
+impl RamSnapshot {
+    pub fn take(nes: &Nes) -> Self {
+        let save_ram = if nes.save_ram().is_empty() {
+            vec![]
+        } else {
+            (0..SAVE_RAM_SIZE as u16)
+                .map(|offset| nes.peek(SAVE_RAM_START + offset))
+                .collect()
+        };
+        Self {
+            ram: (0..WORK_RAM_SIZE as u16).map(|a| nes.peek(a)).collect(),
+            save_ram,
+        }
+    }
+
+    pub fn get(&self, address: u16) -> Option<u8> {
+        if (address as usize) < WORK_RAM_SIZE {
+            return Some(self.ram[address as usize]);
+        }
+        let offset = address.checked_sub(SAVE_RAM_START)? as usize;
+        self.save_ram.get(offset).copied()
+    }
+
+    fn addresses(&self) -> impl Iterator<Item = u16> {
+        let save_ram_end = SAVE_RAM_START as usize + self.save_ram.len();
+        (0..WORK_RAM_SIZE as u16).chain(SAVE_RAM_START..save_ram_end as u16)
+    }
+}
+
+pub struct RamSearch {
+    size: ValueSize,
+    signed: bool,
+    previous: RamSnapshot,
+    current: RamSnapshot,
+    candidates: Vec<u16>,
+}
+
+impl RamSearch {
+    /// Starts a search with every address as a candidate
+    pub fn new(nes: &Nes, size: ValueSize, signed: bool) -> Self {
+        let snapshot = RamSnapshot::take(nes);
+        let mut search = Self {
+            size,
+            signed,
+            previous: snapshot.clone(),
+            current: snapshot,
+            candidates: vec![],
+        };
+        search.reset();
+        search
+    }
+
+    /// Takes a new snapshot, meant to be called once per frame
+    pub fn update(&mut self, nes: &Nes) {
+        self.current = RamSnapshot::take(nes);
+    }
+
+    /// Makes every address a candidate again and the latest snapshot the base for the
+    /// next filter
+    pub fn reset(&mut self) {
+        self.previous = self.current.clone();
+        self.candidates = self.current.addresses().collect();
+        self.drop_incomplete();
+    }
+
+    /// Changes how the bytes are read, the candidates are kept
+    pub fn set_view(&mut self, size: ValueSize, signed: bool) {
+        self.size = size;
+        self.signed = signed;
+        self.drop_incomplete();
+    }
+
+    /// Keeps the candidates passing `filter`, returns how many are left
+    pub fn filter(&mut self, filter: SearchFilter) -> usize {
+        let (previous, current) = (&self.previous, &self.current);
+        let (size, signed) = (self.size, self.signed);
+        self.candidates.retain(|&address| {
+            match (
+                value(previous, address, size, signed),
+                value(current, address, size, signed),
+            ) {
+                (Some(previous), Some(current)) => filter.matches(previous, current),
+                _ => false,
+            }
+        });
+        self.previous = self.current.clone();
+        self.candidates.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    pub fn candidates(&self) -> Vec<Candidate> {
+        self.candidates
+            .iter()
+            .filter_map(|&address| {
+                Some(Candidate {
+                    address,
+                    previous: value(&self.previous, address, self.size, self.signed)?,
+                    value: value(&self.current, address, self.size, self.signed)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Words need the following byte in the same memory
+    fn drop_incomplete(&mut self) {
+        let (current, size) = (&self.current, self.size);
+        self.candidates
+            .retain(|&address| value(current, address, size, false).is_some());
+    }
+}
+
+impl SearchFilter {
+    fn matches(self, previous: i32, current: i32) -> bool {
+        match self {
+            SearchFilter::Unchanged => current == previous,
+            SearchFilter::Changed => current != previous,
+            SearchFilter::Increased => current > previous,
+            SearchFilter::Decreased => current < previous,
+            SearchFilter::Equal(value) => current == value,
+            SearchFilter::ChangedBy(delta) => current - previous == delta,
+        }
+    }
+}
+
+fn value(snapshot: &RamSnapshot, address: u16, size: ValueSize, signed: bool) -> Option<i32> {
+    let low = snapshot.get(address)?;
+    Some(match (size, signed) {
+        (ValueSize::Byte, false) => low as i32,
+        (ValueSize::Byte, true) => low as i8 as i32,
+        (ValueSize::Word, signed) => {
+            let word = u16::from_le_bytes([low, snapshot.get(address.wrapping_add(1))?]);
+            if signed {
+                word as i16 as i32
+            } else {
+                word as i32
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses(search: &RamSearch) -> Vec<u16> {
+        search.candidates().iter().map(|c| c.address).collect()
+    }
+
+    #[test]
+    fn byte_filters() {
+        let mut nes = Nes::new();
+        nes.poke(0x0010, 5);
+        nes.poke(0x0011, 9);
+        let mut search = RamSearch::new(&nes, ValueSize::Byte, false);
+        assert_eq!(search.len(), WORK_RAM_SIZE);
+
+        nes.poke(0x0010, 6);
+        nes.poke(0x0011, 7);
+        search.update(&nes);
+        assert_eq!(search.filter(SearchFilter::Changed), 2);
+        assert_eq!(addresses(&search), vec![0x0010, 0x0011]);
+
+        nes.poke(0x0010, 8);
+        nes.poke(0x0011, 5);
+        search.update(&nes);
+        // previous is the value at the last filter
+        assert_eq!(
+            search.candidates(),
+            vec![
+                Candidate {
+                    address: 0x0010,
+                    previous: 6,
+                    value: 8,
+                },
+                Candidate {
+                    address: 0x0011,
+                    previous: 7,
+                    value: 5,
+                },
+            ]
+        );
+        assert_eq!(search.filter(SearchFilter::ChangedBy(-2)), 1);
+        assert_eq!(addresses(&search), vec![0x0011]);
+
+        search.update(&nes);
+        assert_eq!(search.filter(SearchFilter::Equal(5)), 1);
+        assert_eq!(search.filter(SearchFilter::Increased), 0);
+
+        search.reset();
+        assert_eq!(search.filter(SearchFilter::Unchanged), WORK_RAM_SIZE);
+    }
+
+    #[test]
+    fn words_and_signed_values() {
+        let mut nes = Nes::new();
+        nes.poke(0x0020, 0xFF);
+        nes.poke(0x0030, 0x7F);
+        let mut search = RamSearch::new(&nes, ValueSize::Word, false);
+        // $07FF has no second byte
+        assert_eq!(search.len(), WORK_RAM_SIZE - 1);
+
+        nes.poke(0x0020, 0x00);
+        nes.poke(0x0021, 0x01);
+        nes.poke(0x0030, 0x80);
+        search.update(&nes);
+        search.set_view(ValueSize::Byte, true);
+        // $20 went from -1 to 0, $30 from 127 to -128
+        search.filter(SearchFilter::Decreased);
+        assert_eq!(addresses(&search), vec![0x0030]);
+
+        search.reset();
+        search.set_view(ValueSize::Word, false);
+        nes.poke(0x0020, 0x01);
+        search.update(&nes);
+        search.filter(SearchFilter::Equal(0x0101));
+        assert_eq!(addresses(&search), vec![0x0020]);
+    }
+
+    #[test]
+    fn large_save_ram() {
+        // NES 2.0 NROM with 64K of PRG RAM
+        let mut rom = b"NES\x1A\x01\x01\x00\x08\x00\x00\x0A".to_vec();
+        rom.resize(16 + 0x4000 + 0x2000, 0);
+        let mut nes = Nes::with_cartridge(crate::Cartridge::from_bytes(&rom).unwrap());
+        assert_eq!(nes.save_ram().len(), 0x10000);
+        nes.poke(0x7ABC, 0x42);
+
+        let search = RamSearch::new(&nes, ValueSize::Byte, false);
+        assert_eq!(search.len(), WORK_RAM_SIZE + SAVE_RAM_SIZE);
+        assert_eq!(addresses(&search).last(), Some(&0x7FFF));
+        assert_eq!(RamSnapshot::take(&nes).get(0x7ABC), Some(0x42));
+    }
+
+    #[test]
+    fn small_save_ram_is_mirrored() {
+        // NES 2.0 MMC1 with 2K of PRG RAM, seen four times at $6000-$7FFF
+        let mut rom = b"NES  ".to_vec();
+        rom.resize(16 + 0x8000 + 0x2000, 0);
+        let mut nes = Nes::with_cartridge(crate::Cartridge::from_bytes(&rom).unwrap());
+        assert_eq!(nes.save_ram().len(), 0x800);
+        nes.poke(0x6001, 0x42);
+
+        let snapshot = RamSnapshot::take(&nes);
+        assert_eq!(snapshot.get(0x6001), Some(0x42));
+        assert_eq!(snapshot.get(0x7801), Some(0x42));
+        assert_eq!(snapshot.get(0x8000), None);
+    }
+
+    #[test]
+    fn no_save_ram() {
+        // NES 2.0 NROM without PRG RAM
+        let mut rom = b"NES ".to_vec();
+        rom.resize(16 + 0x4000 + 0x2000, 0);
+        let nes = Nes::with_cartridge(crate::Cartridge::from_bytes(&rom).unwrap());
+        assert_eq!(RamSnapshot::take(&nes).get(0x6000), None);
+        let search = RamSearch::new(&nes, ValueSize::Byte, false);
+        assert_eq!(search.len(), WORK_RAM_SIZE);
+    }
+}
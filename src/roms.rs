@@ -1,29 +1,69 @@
-use std::fs::File;
-use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use crate::cartridge::{database, disk, patch};
 use crate::Cartridge;
 
-pub fn read_rom(filename: &str) -> Result<Cartridge, String> {
-    let mut header = [0u8; 16];
-
-    let mut f = File::open(filename).map_err(|e| e.to_string())?;
-    f.read_exact(&mut header).map_err(|e| e.to_string())?;
+/// FDS BIOS file, looked for next to the disk image and in the working directory
+pub const FDS_BIOS: &str = "disksys.rom";
+/// Game database in the `games.txt` format, looked for in the working directory until it
+/// has been loaded
+pub const GAME_DATABASE: &str = "nescartdb.txt";
 
-    //let mapper_low = (header[6] & 248) >> 4;
-    //let mapper_high = header[7] & 248; // 4 highest bits
-    //let mapper = mapper_high | mapper_low;
+/// Loads an iNES, UNIF or FDS file, or a zip archive holding an iNES or UNIF one. A `.ips`
+/// or `.bps` patch with the same name next to it is applied.
+pub fn read_rom(filename: &str) -> Result<Cartridge, String> {
+    load_game_database();
+    let data = std::fs::read(filename).map_err(|e| e.to_string())?;
+    if disk::is_disk_image(&data) {
+        return read_disk(filename, data);
+    }
+    let cartridge = match find_patch(filename) {
+        Some(patch) => {
+            let patch_data = std::fs::read(&patch).map_err(|e| e.to_string())?;
+            Cartridge::from_bytes_patched(&data, &patch_data)
+                .map_err(|e| format!("{}: {}", patch.display(), e))?
+        }
+        None => Cartridge::from_bytes(&data).map_err(|e| e.to_string())?,
+    };
+    Ok(cartridge)
+}
 
-    let mut data = Vec::new();
-    f.read_to_end(&mut data).map_err(|e| e.to_string())?;
+fn read_disk(filename: &str, mut image: Vec<u8>) -> Result<Cartridge, String> {
+    let bios_path = vec![
+        Path::new(filename).with_file_name(FDS_BIOS),
+        PathBuf::from(FDS_BIOS),
+    ]
+    .into_iter()
+    .find(|path| path.is_file())
+    .ok_or_else(|| format!("{} is needed to run disk images", FDS_BIOS))?;
+    let bios = std::fs::read(&bios_path).map_err(|e| e.to_string())?;
+    if let Some(patch) = find_patch(filename) {
+        let patch_data = std::fs::read(&patch).map_err(|e| e.to_string())?;
+        image = patch::apply_patch(&image, &patch_data)
+            .map_err(|e| format!("{}: {}", patch.display(), e))?;
+    }
+    Cartridge::from_fds(&image, &bios).map_err(|e| e.to_string())
+}
 
-    Ok(Cartridge::new(header, data))
+/// A database that can't be read only costs the header fixes, the ROM still loads
+fn load_game_database() {
+    static LOADED: AtomicBool = AtomicBool::new(false);
+    if LOADED.load(Ordering::Relaxed) || !Path::new(GAME_DATABASE).is_file() {
+        return;
+    }
+    let res = std::fs::read_to_string(GAME_DATABASE)
+        .map_err(|e| e.to_string())
+        .and_then(|text| database::load(&text));
+    match res {
+        Ok(_) => LOADED.store(true, Ordering::Relaxed),
+        Err(e) => eprintln!("warning: ignoring {}: {}", GAME_DATABASE, e),
+    }
+}
 
-    //
-    //println!("mapper found: {}", mapper);
-    //println!("banks: {}", banks.len());
-    //match mapper {
-    //0 => Cartridge::new(Mapper::NROM(banks.len() == 1), banks),
-    //1 => Cartridge::new(Mapper::MMC1(0, banks.len() - 1), banks),
-    //_ => unimplemented!("Unimplemented mapper {}", mapper),
-    //}
+fn find_patch(rom: &str) -> Option<PathBuf> {
+    ["ips", "bps"]
+        .iter()
+        .map(|extension| Path::new(rom).with_extension(extension))
+        .find(|patch| patch.is_file())
 }
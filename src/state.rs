@@ -0,0 +1,617 @@
+/// Magic bytes at the start of every save state
+pub(crate) const STATE_MAGIC: [u8; 8] = *b"RNESSAVE";
+/// Version of the save state container, bumped when the layout of the header changes
+pub(crate) const STATE_FORMAT_VERSION: u16 = 1;
+
+pub(crate) type ChunkId = [u8; 4];
+
+/// A chunk of the save state and the version of its layout. The version is bumped when the
+/// component changes what it saves, loaders check `StateReader::version` to read older layouts.
+/// Version 1 is the layout the chunks had when save states were introduced.
+#[derive(Clone, Copy)]
+pub(crate) struct ChunkType {
+    pub(crate) id: ChunkId,
+    pub(crate) version: u16,
+}
+
+/// Version 2 added the IRQ line and interrupt polling
+pub(crate) const CPU_CHUNK: ChunkType = ChunkType {
+    id: *b"CPU ",
+    version: 2,
+};
+pub(crate) const PPU_CHUNK: ChunkType = ChunkType {
+    id: *b"PPU ",
+    version: 1,
+};
+/// Version 2 added the DMC fetch and the cycle-accurate frame counter
+pub(crate) const APU_CHUNK: ChunkType = ChunkType {
+    id: *b"APU ",
+    version: 2,
+};
+/// Version 2 added the DMC DMA, the last bus access and the APU status
+pub(crate) const MEMORY_CHUNK: ChunkType = ChunkType {
+    id: *b"MEM ",
+    version: 2,
+};
+/// Version 2 added the NROM PRG RAM and sized the MMC1 PRG RAM from the header,
+/// version 3 added the UxROM and CNROM PRG RAM
+pub(crate) const MAPPER_CHUNK: ChunkType = ChunkType {
+    id: *b"MAPR",
+    version: 3,
+};
+pub(crate) const NES_CHUNK: ChunkType = ChunkType {
+    id: *b"NES ",
+    version: 1,
+};
+
+/// Errors returned when restoring a save state
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateError {
+    /// The state ended before all the expected data was read
+    UnexpectedEof,
+    /// A field contained a value that can't be mapped back to the emulator state
+    InvalidValue(&'static str),
+    /// The data doesn't start with the save state magic bytes
+    BadMagic,
+    /// The state was written by a newer version of the emulator
+    UnsupportedVersion(u16),
+    /// The state was saved while running a different game
+    RomMismatch { expected: u32, found: u32 },
+    /// A chunk required to restore the machine isn't in the state
+    MissingChunk(String),
+    /// The state file couldn't be read or written
+    Io(String),
+}
+
+impl std::fmt::Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateError::UnexpectedEof => write!(f, "unexpected end of save state"),
+            StateError::InvalidValue(field) => write!(f, "invalid value for {}", field),
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            StateError::RomMismatch { expected, found } => write!(
+                f,
+                "save state belongs to ROM {:08X}, loaded ROM is {:08X}",
+                found, expected
+            ),
+            StateError::MissingChunk(id) => write!(f, "missing save state chunk '{}'", id),
+            StateError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+impl From<std::io::Error> for StateError {
+    fn from(e: std::io::Error) -> Self {
+        StateError::Io(e.to_string())
+    }
+}
+
+/// Builds a save state: header followed by a list of chunks.
+///
+/// Layout (little-endian):
+/// - magic (8 bytes), format version (u16), ROM fingerprint (u32)
+/// - for every chunk: id (4 bytes), chunk version (u16), length (u32), data
+pub(crate) struct StateFileWriter {
+    writer: StateWriter,
+}
+
+impl StateFileWriter {
+    pub(crate) fn new(fingerprint: u32) -> Self {
+        let mut writer = StateWriter::new();
+        writer.data.extend_from_slice(&STATE_MAGIC);
+        writer.write_u16(STATE_FORMAT_VERSION);
+        writer.write_u32(fingerprint);
+        Self { writer }
+    }
+
+    pub(crate) fn add_chunk<F>(&mut self, chunk_type: ChunkType, save: F)
+    where
+        F: FnOnce(&mut StateWriter),
+    {
+        let mut chunk = StateWriter::new();
+        save(&mut chunk);
+        self.writer.data.extend_from_slice(&chunk_type.id);
+        self.writer.write_u16(chunk_type.version);
+        self.writer.write_bytes(&chunk.data);
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.writer.into_inner()
+    }
+}
+
+struct Chunk<'a> {
+    id: ChunkId,
+    version: u16,
+    data: &'a [u8],
+}
+
+/// A parsed save state, gives access to its chunks by id
+pub(crate) struct StateFile<'a> {
+    pub(crate) fingerprint: u32,
+    chunks: Vec<Chunk<'a>>,
+}
+
+impl<'a> StateFile<'a> {
+    pub(crate) fn parse(data: &'a [u8]) -> Result<Self, StateError> {
+        let mut reader = StateReader::new(data);
+        if reader.take(STATE_MAGIC.len()).ok() != Some(&STATE_MAGIC[..]) {
+            return Err(StateError::BadMagic);
+        }
+        let version = reader.read_u16()?;
+        if version > STATE_FORMAT_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let fingerprint = reader.read_u32()?;
+
+        let mut chunks = Vec::new();
+        while !reader.is_empty() {
+            let mut id = [0u8; 4];
+            id.copy_from_slice(reader.take(4)?);
+            let version = reader.read_u16()?;
+            let data = reader.read_bytes()?;
+            chunks.push(Chunk { id, version, data });
+        }
+
+        Ok(Self {
+            fingerprint,
+            chunks,
+        })
+    }
+
+    /// Runs `load` over the data of the chunk, the whole chunk has to be consumed.
+    /// The reader carries the version the chunk was saved with, only newer versions
+    /// than `chunk_type.version` are rejected.
+    /// Chunks that are not requested are ignored, so states from newer builds with
+    /// extra chunks can still be loaded.
+    pub(crate) fn load_chunk<F>(&self, chunk_type: ChunkType, load: F) -> Result<(), StateError>
+    where
+        F: FnOnce(&mut StateReader) -> Result<(), StateError>,
+    {
+        let id = chunk_type.id;
+        let chunk =
+            self.chunks.iter().find(|c| c.id == id).ok_or_else(|| {
+                StateError::MissingChunk(String::from_utf8_lossy(&id).into_owned())
+            })?;
+        if chunk.version > chunk_type.version {
+            return Err(StateError::UnsupportedVersion(chunk.version));
+        }
+
+        let mut reader = StateReader::with_version(chunk.data, chunk.version);
+        load(&mut reader)?;
+        if !reader.is_empty() {
+            return Err(StateError::InvalidValue("trailing data"));
+        }
+        Ok(())
+    }
+}
+
+/// Serializes emulator state into a flat little-endian byte buffer
+pub(crate) struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub(crate) fn new() -> Self {
+        Self { data: Vec::new() }
+    }
+
+    pub(crate) fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub(crate) fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub(crate) fn write_i8(&mut self, value: i8) {
+        self.data.push(value as u8);
+    }
+
+    pub(crate) fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub(crate) fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn write_usize(&mut self, value: usize) {
+        self.write_u64(value as u64);
+    }
+
+    pub(crate) fn write_f64(&mut self, value: f64) {
+        self.write_u64(value.to_bits());
+    }
+
+    /// Writes a length-prefixed byte slice
+    pub(crate) fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+/// Reads back the data produced by a `StateWriter`
+pub(crate) struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    version: u16,
+}
+
+impl<'a> StateReader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self::with_version(data, 1)
+    }
+
+    pub(crate) fn with_version(data: &'a [u8], version: u16) -> Self {
+        Self {
+            data,
+            position: 0,
+            version,
+        }
+    }
+
+    /// Layout version of the chunk being read
+    pub(crate) fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() - self.position < len {
+            return Err(StateError::UnexpectedEof);
+        }
+        let out = &self.data[self.position..self.position + len];
+        self.position += len;
+        Ok(out)
+    }
+
+    pub(crate) fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn read_i8(&mut self) -> Result<i8, StateError> {
+        Ok(self.read_u8()? as i8)
+    }
+
+    pub(crate) fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::InvalidValue("bool")),
+        }
+    }
+
+    pub(crate) fn read_u16(&mut self) -> Result<u16, StateError> {
+        let mut bytes = [0u8; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn read_u32(&mut self) -> Result<u32, StateError> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn read_u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn read_usize(&mut self) -> Result<usize, StateError> {
+        Ok(self.read_u64()? as usize)
+    }
+
+    pub(crate) fn read_f64(&mut self) -> Result<f64, StateError> {
+        Ok(f64::from_bits(self.read_u64()?))
+    }
+
+    /// Reads a length-prefixed byte slice
+    pub(crate) fn read_bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    /// Reads a length-prefixed byte slice into `out`, the lengths must match
+    pub(crate) fn read_bytes_into(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        let bytes = self.read_bytes()?;
+        if bytes.len() != out.len() {
+            return Err(StateError::InvalidValue("byte array length"));
+        }
+        out.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cartridge, Nes};
+
+    /// NROM cart that draws a static nametable, scrolls it from NMI and DMAs the zero page into OAM
+    fn scrolling_cartridge() -> Cartridge {
+        scrolling_cartridge_with_chr(7)
+    }
+
+    fn scrolling_cartridge_with_chr(seed: usize) -> Cartridge {
+        #[rustfmt::skip]
+        let program = [
+            0x78,                   // SEI
+            0xD8,                   // CLD
+            0xA9, 0x00,             // LDA #$00
+            0x8D, 0x00, 0x20,       // STA $2000
+            0x8D, 0x01, 0x20,       // STA $2001
+            0xA9, 0x3F,             // LDA #$3F
+            0x8D, 0x06, 0x20,       // STA $2006
+            0xA9, 0x00,             // LDA #$00
+            0x8D, 0x06, 0x20,       // STA $2006
+            0xA2, 0x00,             // LDX #$00
+            0x8A,                   // palette: TXA
+            0x8D, 0x07, 0x20,       // STA $2007
+            0xE8,                   // INX
+            0xE0, 0x20,             // CPX #$20
+            0xD0, 0xF7,             // BNE palette
+            0xA9, 0x20,             // LDA #$20
+            0x8D, 0x06, 0x20,       // STA $2006
+            0xA9, 0x00,             // LDA #$00
+            0x8D, 0x06, 0x20,       // STA $2006
+            0xA0, 0x04,             // LDY #$04
+            0xA2, 0x00,             // page: LDX #$00
+            0x8A,                   // tile: TXA
+            0x8D, 0x07, 0x20,       // STA $2007
+            0xE8,                   // INX
+            0xD0, 0xF9,             // BNE tile
+            0x88,                   // DEY
+            0xD0, 0xF4,             // BNE page
+            0xA9, 0x00,             // LDA #$00
+            0x8D, 0x05, 0x20,       // STA $2005
+            0x8D, 0x05, 0x20,       // STA $2005
+            0xA9, 0x1E,             // LDA #$1E
+            0x8D, 0x01, 0x20,       // STA $2001
+            0xA9, 0x80,             // LDA #$80
+            0x8D, 0x00, 0x20,       // STA $2000
+            0xE6, 0x00,             // main: INC $00
+            0x4C, 0x49, 0x80,       // JMP main
+            0xE6, 0x01,             // nmi: INC $01
+            0xA9, 0x00,             // LDA #$00
+            0x8D, 0x14, 0x40,       // STA $4014
+            0xA5, 0x01,             // LDA $01
+            0x8D, 0x05, 0x20,       // STA $2005
+            0x8D, 0x05, 0x20,       // STA $2005
+            0x40,                   // RTI
+        ];
+        let chr: Vec<u8> = (0..0x2000).map(|i| (i * seed + i / 16) as u8).collect();
+        Cartridge::test_nrom(&program, [0x804E, 0x8000, 0x8000], &chr)
+    }
+
+    fn run_frames(nes: &mut Nes, frames: usize) -> Vec<Vec<u8>> {
+        (0..frames)
+            .map(|_| {
+                nes.run_until_frame();
+                let mut frame = nes.get_frame();
+                let data = frame.get_data().to_vec();
+                nes.return_frame(frame);
+                data
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip_mid_frame() {
+        let mut nes = Nes::with_cartridge(scrolling_cartridge());
+        run_frames(&mut nes, 3);
+        for _ in 0..12345 {
+            nes.tick();
+        }
+
+        let state = nes.save_state();
+        let expected = run_frames(&mut nes, 3);
+        let expected_cycles = nes.cycles();
+        // the NMI handler scrolls the screen, so every frame is different
+        assert!(expected[1] != expected[2]);
+
+        let mut restored = Nes::with_cartridge(scrolling_cartridge());
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        assert!(run_frames(&mut restored, 3) == expected);
+        assert_eq!(restored.cycles(), expected_cycles);
+
+        nes.load_state(&state).unwrap();
+        assert!(run_frames(&mut nes, 3) == expected);
+    }
+
+    #[test]
+    fn truncated_state_is_rejected() {
+        let mut nes = Nes::with_cartridge(scrolling_cartridge());
+        run_frames(&mut nes, 1);
+        let state = nes.save_state();
+        run_frames(&mut nes, 1);
+        let before = nes.save_state();
+
+        assert_eq!(
+            nes.load_state(&state[..state.len() - 1]),
+            Err(StateError::UnexpectedEof)
+        );
+        assert_eq!(nes.save_state(), before);
+
+        let mut padded = state.clone();
+        padded.push(0);
+        assert!(nes.load_state(&padded).is_err());
+        assert_eq!(nes.save_state(), before);
+    }
+
+    /// Rebuilds `state`, `rewrite` returns the new version and data of a chunk or `None` to keep it
+    fn rewrite_chunks<F>(state: &[u8], rewrite: F) -> Vec<u8>
+    where
+        F: Fn(&Chunk) -> Option<(u16, Vec<u8>)>,
+    {
+        let file = StateFile::parse(state).unwrap();
+        let mut writer = StateFileWriter::new(file.fingerprint);
+        for chunk in &file.chunks {
+            let (version, data) = rewrite(chunk).unwrap_or((chunk.version, chunk.data.to_vec()));
+            let chunk_type = ChunkType {
+                id: chunk.id,
+                version,
+            };
+            writer.add_chunk(chunk_type, |w| w.data.extend_from_slice(&data));
+        }
+        writer.finish()
+    }
+
+    #[test]
+    fn short_ram_is_rejected() {
+        let mut nes = Nes::with_cartridge(scrolling_cartridge());
+        let state = rewrite_chunks(&nes.save_state(), |chunk| {
+            if chunk.id != MEMORY_CHUNK.id {
+                return None;
+            }
+            // the work RAM comes first, keep half of it
+            let mut reader = StateReader::new(chunk.data);
+            let ram = reader.read_bytes().unwrap();
+            let mut w = StateWriter::new();
+            w.write_bytes(&ram[..ram.len() / 2]);
+            w.data.extend_from_slice(&chunk.data[reader.position..]);
+            Some((chunk.version, w.data))
+        });
+        assert_eq!(
+            nes.load_state(&state),
+            Err(StateError::InvalidValue("byte array length"))
+        );
+    }
+
+    #[test]
+    fn version_1_chunks_are_loaded() {
+        let mut nes = Nes::with_cartridge(scrolling_cartridge());
+        run_frames(&mut nes, 1);
+
+        // strip the fields version 2 added from the CPU, memory and NROM chunks
+        let state = rewrite_chunks(&nes.save_state(), |chunk| {
+            let mut data = chunk.data.to_vec();
+            let mut reader = StateReader::new(chunk.data);
+            if chunk.id == CPU_CHUNK.id {
+                // IRQ sources and polling
+                data.truncate(data.len() - 5);
+            } else if chunk.id == MEMORY_CHUNK.id {
+                for _ in 0..3 {
+                    reader.read_bytes().unwrap();
+                }
+                reader.read_u8().unwrap();
+                reader.read_bytes().unwrap();
+                reader.read_u8().unwrap();
+                reader.read_u16().unwrap();
+                reader.read_u8().unwrap();
+                reader.read_usize().unwrap();
+                reader.read_u8().unwrap();
+                // the APU status follows the PPU registers, then the DMC DMA and bus access
+                let dmc = reader.position;
+                data.remove(dmc + 11 + 4);
+                data.drain(dmc..dmc + 11);
+            } else if chunk.id == MAPPER_CHUNK.id {
+                reader.read_bytes().unwrap();
+                let prg_ram = reader.position;
+                reader.read_bytes().unwrap();
+                data.drain(prg_ram..reader.position);
+            } else {
+                return None;
+            }
+            Some((1, data))
+        });
+
+        let mut restored = Nes::with_cartridge(scrolling_cartridge());
+        restored.load_state(&state).unwrap();
+        assert_eq!(run_frames(&mut restored, 2), run_frames(&mut nes, 2));
+        assert_eq!(restored.save_state(), nes.save_state());
+    }
+
+    #[test]
+    fn state_from_another_rom_is_rejected() {
+        let mut other = Nes::with_cartridge(scrolling_cartridge_with_chr(3));
+        let state = other.save_state();
+        let mut nes = Nes::with_cartridge(scrolling_cartridge());
+        match nes.load_state(&state) {
+            Err(StateError::RomMismatch { expected, found }) => {
+                assert_eq!(found, other.memory.cartridge_fingerprint());
+                assert_eq!(expected, nes.memory.cartridge_fingerprint());
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        other.load_state(&state).unwrap();
+    }
+
+    #[test]
+    fn bad_header_is_rejected() {
+        let mut nes = Nes::with_cartridge(scrolling_cartridge());
+        let state = nes.save_state();
+
+        let mut bad_magic = state.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(nes.load_state(&bad_magic), Err(StateError::BadMagic));
+
+        let mut newer = state.clone();
+        newer[8..10].copy_from_slice(&(STATE_FORMAT_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            nes.load_state(&newer),
+            Err(StateError::UnsupportedVersion(STATE_FORMAT_VERSION + 1))
+        );
+
+        // the version of the first chunk follows the header and the chunk id
+        let mut newer_chunk = state.clone();
+        newer_chunk[18..20].copy_from_slice(&(CPU_CHUNK.version + 1).to_le_bytes());
+        assert_eq!(
+            nes.load_state(&newer_chunk),
+            Err(StateError::UnsupportedVersion(CPU_CHUNK.version + 1))
+        );
+    }
+
+    #[test]
+    fn unknown_chunks_are_skipped() {
+        let mut nes = Nes::with_cartridge(scrolling_cartridge());
+        run_frames(&mut nes, 1);
+        let state = nes.save_state();
+
+        let mut extended = state.clone();
+        extended.extend_from_slice(b"XTRA");
+        extended.extend_from_slice(&1u16.to_le_bytes());
+        extended.extend_from_slice(&3u32.to_le_bytes());
+        extended.extend_from_slice(&[1, 2, 3]);
+
+        let mut restored = Nes::with_cartridge(scrolling_cartridge());
+        restored.load_state(&extended).unwrap();
+        assert_eq!(restored.save_state(), state);
+    }
+
+    #[test]
+    fn state_file_round_trip() {
+        let path = std::env::temp_dir().join(format!("rnes_state_{}.sav", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut nes = Nes::with_cartridge(scrolling_cartridge());
+        run_frames(&mut nes, 2);
+        nes.save_state_file(path).unwrap();
+        let state = nes.save_state();
+
+        let mut restored = Nes::with_cartridge(scrolling_cartridge());
+        restored.load_state_file(path).unwrap();
+        assert_eq!(restored.save_state(), state);
+        std::fs::remove_file(path).unwrap();
+
+        assert!(matches!(restored.load_state_file(path), Err(StateError::Io(_))));
+    }
+}
@@ -0,0 +1,54 @@
+//! Result protocol used by blargg's test ROMs: a status byte at $6000,
+//! the DE B0 61 signature at $6001-$6003 and a NUL terminated message from $6004.
+
+pub(crate) const STATUS: u16 = 0x6000;
+pub(crate) const SIGNATURE_ADDRESS: u16 = 0x6001;
+pub(crate) const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+pub(crate) const MESSAGE: u16 = 0x6004;
+
+/// Status byte while the test is running
+pub(crate) const RUNNING: u8 = 0x80;
+/// Status byte asking for the reset button to be pressed
+pub(crate) const NEEDS_RESET: u8 = 0x81;
+/// Frames to wait before pressing reset, the ROMs want at least 100ms
+pub(crate) const RESET_DELAY_FRAMES: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestRomStatus {
+    Passed,
+    /// The result code written by the ROM, identifies the failing check
+    Failed(u8),
+    /// The ROM was still running when the frame limit was reached
+    Timeout,
+    /// The signature never showed up, the ROM doesn't use the protocol
+    MissingSignature,
+}
+
+#[derive(Debug, Clone)]
+pub struct TestRomResult {
+    pub status: TestRomStatus,
+    pub message: String,
+    pub frames: usize,
+}
+
+impl TestRomResult {
+    pub fn passed(&self) -> bool {
+        self.status == TestRomStatus::Passed
+    }
+}
+
+impl std::fmt::Display for TestRomResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.status {
+            TestRomStatus::Passed => write!(f, "passed")?,
+            TestRomStatus::Failed(code) => write!(f, "failed with code {}", code)?,
+            TestRomStatus::Timeout => write!(f, "timed out")?,
+            TestRomStatus::MissingSignature => write!(f, "no test signature")?,
+        }
+        write!(f, " after {} frames", self.frames)?;
+        if !self.message.is_empty() {
+            write!(f, ": {}", self.message.trim_end())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use rnes::test_rom::TestRomStatus;
+use rnes::Nes;
+
+use crate::utils::{nrom_cartridge, test_roms_dir, Program};
+
+/// Enough for the longest multi-test ROMs, about a minute of emulated time
+const MAX_FRAMES: usize = 60 * 60;
+
+/// Program with NMIs disabled, their vector points back to the start
+fn program() -> Program {
+    let mut program = Program::new();
+    program.store(0x2000, 0x00);
+    program
+}
+
+fn write_signature(program: &mut Program) -> &mut Program {
+    program
+        .store(0x6001, 0xDE)
+        .store(0x6002, 0xB0)
+        .store(0x6003, 0x61)
+}
+
+#[test]
+fn reports_pass() {
+    let mut program = program();
+    write_signature(&mut program)
+        .store(0x6000, 0x80)
+        .store_str(0x6004, "Passed\n")
+        .store(0x6000, 0x00)
+        .hang();
+    let mut nes = Nes::with_cartridge(nrom_cartridge(program.code()));
+
+    let result = nes.run_test_rom(10);
+    assert_eq!(result.status, TestRomStatus::Passed);
+    assert_eq!(result.message, "Passed\n");
+    assert_eq!(result.frames, 1);
+}
+
+#[test]
+fn reports_failure_code() {
+    let mut program = program();
+    write_signature(&mut program)
+        .store(0x6000, 0x80)
+        .store_str(0x6004, "Failed #3")
+        .store(0x6000, 0x03)
+        .hang();
+    let mut nes = Nes::with_cartridge(nrom_cartridge(program.code()));
+
+    let result = nes.run_test_rom(10);
+    assert_eq!(result.status, TestRomStatus::Failed(3));
+    assert_eq!(result.message, "Failed #3");
+    assert!(!result.passed());
+}
+
+#[test]
+fn presses_reset_when_asked() {
+    // the status byte survives the reset, the second run reports the result
+    let mut program = program();
+    program.lda_abs(0x6000).cmp_imm(0x81).beq(0);
+    let branch = program.code().len() - 1;
+    write_signature(&mut program)
+        .store(0x6000, 0x81)
+        .store_str(0x6004, "Press reset")
+        .hang();
+    let after_reset = program.code().len();
+    program.store_str(0x6004, "Done").store(0x6000, 0x00).hang();
+
+    let mut code = program.code().to_vec();
+    code[branch] = (after_reset - branch - 1) as u8;
+    let mut nes = Nes::with_cartridge(nrom_cartridge(&code));
+
+    let result = nes.run_test_rom(60);
+    assert_eq!(result.status, TestRomStatus::Passed);
+    assert_eq!(result.message, "Done");
+    assert!(result.frames > 6);
+}
+
+#[test]
+fn rom_without_signature() {
+    let mut program = program();
+    program.store(0x6000, 0x00).hang();
+    let mut nes = Nes::with_cartridge(nrom_cartridge(program.code()));
+
+    let result = nes.run_test_rom(5);
+    assert_eq!(result.status, TestRomStatus::MissingSignature);
+    assert_eq!(result.frames, 5);
+}
+
+#[test]
+fn times_out_while_running() {
+    let mut program = program();
+    write_signature(&mut program)
+        .store(0x6000, 0x80)
+        .store_str(0x6004, "Running")
+        .hang();
+    let mut nes = Nes::with_cartridge(nrom_cartridge(program.code()));
+
+    let result = nes.run_test_rom(5);
+    assert_eq!(result.status, TestRomStatus::Timeout);
+    assert_eq!(result.message, "Running");
+}
+
+/// Runs a ROM from the test ROM directory, skipped when the ROM isn't there
+fn run_rom(path: &str) {
+    let full_path = test_roms_dir().join(path);
+    if !full_path.exists() {
+        eprintln!("skipping {}: not found", full_path.display());
+        return;
+    }
+    let cartridge = rnes::roms::read_rom(full_path.to_str().unwrap()).unwrap();
+    let mut nes = Nes::with_cartridge(cartridge);
+    let result = nes.run_test_rom(MAX_FRAMES);
+    assert!(result.passed(), "{}: {}", path, result);
+}
+
+macro_rules! test_roms {
+    ($($name:ident => $path:expr,)*) => {
+        $(
+            #[test]
+            fn $name() {
+                run_rom($path);
+            }
+        )*
+    };
+}
+
+test_roms! {
+    instr_test => "instr_test-v3/all_instrs.nes",
+    instr_test_official => "instr_test-v3/official_only.nes",
+    cpu_interrupts => "cpu_interrupts_v2/cpu_interrupts.nes",
+    ppu_vbl_nmi => "ppu_vbl_nmi/ppu_vbl_nmi.nes",
+    apu_test => "apu_test/apu_test.nes",
+}
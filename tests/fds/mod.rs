@@ -0,0 +1,66 @@
+use rnes::cartridge::{disk, Cartridge};
+use rnes::Nes;
+
+/// Enables the disk registers, then keeps copying the drive status at $4032 to $6000
+fn bios() -> Vec<u8> {
+    let mut bios = vec![0xEA; disk::BIOS_SIZE];
+    let program = [
+        0xA9, 0x03, // LDA #$03
+        0x8D, 0x23, 0x40, // STA $4023
+        0xAD, 0x32, 0x40, // LDA $4032
+        0x8D, 0x00, 0x60, // STA $6000
+        0x4C, 0x05, 0xE0, // JMP $E005
+    ];
+    bios[..program.len()].copy_from_slice(&program);
+    // NMI, reset and IRQ vectors
+    bios[0x1FFA..].copy_from_slice(&[0x05, 0xE0, 0x00, 0xE0, 0x05, 0xE0]);
+    bios
+}
+
+fn side() -> Vec<u8> {
+    let mut side = b"\x01*NINTENDO-HVC*".to_vec();
+    side.resize(56, 0);
+    side.resize(disk::SIDE_SIZE, 0);
+    side
+}
+
+fn drive_status(nes: &mut Nes) -> u8 {
+    nes.run_until_frame();
+    nes.peek(0x6000) & 0x07
+}
+
+#[test]
+fn disk_drive() {
+    let mut image = b"FDS\x1A\x02".to_vec();
+    image.resize(16, 0);
+    image.extend(side());
+    image.extend(side());
+    let mut nes = Nes::with_cartridge(Cartridge::from_fds(&image, &bios()).unwrap());
+    assert_eq!(nes.disk_sides(), 2);
+    assert!(nes.has_battery());
+
+    // inserted, the motor is off
+    assert_eq!(drive_status(&mut nes), 0x02);
+    nes.eject_disk();
+    assert_eq!(nes.disk_side(), None);
+    assert_eq!(drive_status(&mut nes), 0x07);
+    assert!(!nes.insert_disk(2));
+    assert!(nes.insert_disk(1));
+    assert_eq!(nes.disk_side(), Some(1));
+    assert_eq!(drive_status(&mut nes), 0x02);
+
+    // the disk is saved in the .fds format
+    let path = std::env::temp_dir().join(format!("rnes-test-{}.sav", std::process::id()));
+    let path = path.to_str().unwrap();
+    nes.save_data(path);
+    let saved = std::fs::read(path).unwrap();
+    assert_eq!(saved, [side(), side()].concat());
+    std::fs::write(path, &saved[..disk::SIDE_SIZE]).unwrap();
+    // an image with the wrong number of sides is ignored
+    nes.try_load_data(path);
+    nes.save_data(path);
+    assert_eq!(std::fs::read(path).unwrap().len(), 2 * disk::SIDE_SIZE);
+    std::fs::remove_file(path).unwrap();
+
+    assert!(Cartridge::from_fds(&image, &[0; 100]).is_err());
+}
@@ -0,0 +1,103 @@
+use rnes::Nes;
+
+use crate::utils::{nrom_cartridge, Program};
+
+/// Writes $42 to the nametable at $2100 and hangs, with NMIs disabled
+fn nes() -> Nes {
+    let mut program = Program::new();
+    program
+        .store(0x2000, 0x00)
+        .store(0x2006, 0x21)
+        .store(0x2006, 0x00)
+        .store(0x2007, 0x42)
+        .hang();
+    let mut nes = Nes::with_cartridge(nrom_cartridge(program.code()));
+    nes.run_until_frame();
+    nes
+}
+
+#[test]
+fn peeking_leaves_the_state_untouched() {
+    let nes = nes();
+    let before = nes.save_state();
+
+    for address in 0..=0xFFFF {
+        nes.peek(address);
+    }
+    for address in 0..0x4000 {
+        nes.peek_ppu(address);
+    }
+    for address in 0..=0xFF {
+        nes.peek_oam(address);
+    }
+    for offset in 0..nes.prg_rom_size() {
+        nes.peek_prg(offset).unwrap();
+    }
+    for offset in 0..nes.chr_size() {
+        nes.peek_chr(offset).unwrap();
+    }
+
+    assert!(nes.save_state() == before);
+}
+
+#[test]
+fn cpu_space() {
+    let mut nes = nes();
+    // RAM through its mirrors
+    nes.poke(0x0812, 0x34);
+    assert_eq!(nes.peek(0x0012), 0x34);
+    assert_eq!(nes.peek(0x1812), 0x34);
+
+    // PRG RAM
+    nes.poke(0x6000, 0x56);
+    assert_eq!(nes.peek(0x6000), 0x56);
+
+    // PRG ROM, the 16K bank is mirrored at $8000 and $C000
+    nes.poke(0xC100, 0x66);
+    assert_eq!(nes.peek(0x8100), 0x66);
+    assert_eq!(nes.peek_prg(0x100), Some(0x66));
+
+    // registers are left alone
+    nes.poke(0x2000, 0x80);
+    assert_eq!(nes.peek(0x0000), 0x00);
+}
+
+#[test]
+fn ppu_space_and_oam() {
+    let mut nes = nes();
+    assert_eq!(nes.peek_ppu(0x2100), 0x42);
+    // horizontal mirroring
+    assert_eq!(nes.peek_ppu(0x2500), 0x42);
+
+    nes.poke_ppu(0x2C10, 0x24);
+    assert_eq!(nes.peek_ppu(0x2810), 0x24);
+
+    // $3F10 mirrors the backdrop color at $3F00
+    nes.poke_ppu(0x3F10, 0x0F);
+    assert_eq!(nes.peek_ppu(0x3F00), 0x0F);
+    nes.poke_ppu(0x3F05, 0x16);
+    assert_eq!(nes.peek_ppu(0x3F25), 0x16);
+
+    nes.poke_oam(0x80, 0x99);
+    assert_eq!(nes.peek_oam(0x80), 0x99);
+}
+
+#[test]
+fn prg_and_chr_banks() {
+    let mut nes = nes();
+    assert_eq!(nes.prg_rom_size(), 0x4000);
+    assert_eq!(nes.chr_size(), 0x2000);
+
+    // LDA #$00 at the start of the program
+    assert_eq!(nes.peek_prg(0), Some(0xA9));
+    assert_eq!(nes.peek_prg(0x4000), None);
+
+    assert!(nes.poke_prg(0x3000, 0x55));
+    assert_eq!(nes.peek(0xB000), 0x55);
+    assert_eq!(nes.peek(0xF000), 0x55);
+    assert!(!nes.poke_prg(0x4000, 0x55));
+
+    assert!(nes.poke_chr(0x1010, 0x77));
+    assert_eq!(nes.peek_ppu(0x1010), 0x77);
+    assert_eq!(nes.peek_chr(0x2000), None);
+}
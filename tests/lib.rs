@@ -1 +1,5 @@
+mod blargg;
+mod fds;
+mod inspection;
+mod nestest;
 mod utils;
@@ -0,0 +1,24 @@
+use rnes::Nes;
+
+use crate::utils::test_roms_dir;
+
+#[test]
+fn nestest() {
+    let rom = test_roms_dir().join("nestest").join("nestest.nes");
+    let log = test_roms_dir().join("nestest").join("nestest.log");
+    if !rom.exists() || !log.exists() {
+        eprintln!(
+            "skipping nestest: {} or {} not found",
+            rom.display(),
+            log.display()
+        );
+        return;
+    }
+    let cartridge = rnes::roms::read_rom(rom.to_str().unwrap()).unwrap();
+    let golden_log = std::fs::read_to_string(log).unwrap();
+
+    let mut nes = Nes::with_cartridge(cartridge);
+    if let Err(mismatch) = nes.run_nestest(&golden_log) {
+        panic!("{}", mismatch);
+    }
+}
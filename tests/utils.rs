@@ -0,0 +1,110 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rnes::cartridge::Cartridge;
+use rnes::roms;
+
+/// Where the accuracy test ROMs are looked for, `RNES_TEST_ROMS` overrides `tests/roms`
+pub fn test_roms_dir() -> PathBuf {
+    match std::env::var_os("RNES_TEST_ROMS") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("roms"),
+    }
+}
+
+/// Builds a 16K NROM image running `program` from $C000 and loads it through `roms::read_rom`
+pub fn nrom_cartridge(program: &[u8]) -> Cartridge {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let mut prg = vec![0xEA; 0x4000];
+    prg[..program.len()].copy_from_slice(program);
+    // NMI, reset and IRQ vectors
+    prg[0x3FFA..].copy_from_slice(&[0x00, 0xC0, 0x00, 0xC0, 0x00, 0xC0]);
+
+    let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    rom.extend(prg);
+    rom.extend(vec![0; 0x2000]);
+
+    let path = std::env::temp_dir().join(format!(
+        "rnes-test-{}-{}.nes",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    std::fs::write(&path, rom).unwrap();
+    let cartridge = roms::read_rom(path.to_str().unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    cartridge
+}
+
+/// Just enough of a 6502 assembler to write test programs, code starts at $C000
+#[derive(Default)]
+pub struct Program {
+    code: Vec<u8>,
+}
+
+impl Program {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn address(&self) -> u16 {
+        0xC000 + self.code.len() as u16
+    }
+
+    pub fn lda_imm(&mut self, value: u8) -> &mut Self {
+        self.code.extend(&[0xA9, value]);
+        self
+    }
+
+    pub fn lda_abs(&mut self, address: u16) -> &mut Self {
+        self.code
+            .extend(&[0xAD, address as u8, (address >> 8) as u8]);
+        self
+    }
+
+    pub fn sta_abs(&mut self, address: u16) -> &mut Self {
+        self.code
+            .extend(&[0x8D, address as u8, (address >> 8) as u8]);
+        self
+    }
+
+    pub fn cmp_imm(&mut self, value: u8) -> &mut Self {
+        self.code.extend(&[0xC9, value]);
+        self
+    }
+
+    pub fn beq(&mut self, offset: i8) -> &mut Self {
+        self.code.extend(&[0xF0, offset as u8]);
+        self
+    }
+
+    pub fn jmp(&mut self, address: u16) -> &mut Self {
+        self.code
+            .extend(&[0x4C, address as u8, (address >> 8) as u8]);
+        self
+    }
+
+    /// Loops forever on the current address
+    pub fn hang(&mut self) -> &mut Self {
+        let address = self.address();
+        self.jmp(address)
+    }
+
+    pub fn store(&mut self, address: u16, value: u8) -> &mut Self {
+        self.lda_imm(value).sta_abs(address)
+    }
+
+    /// Writes a NUL terminated string starting at `address`
+    pub fn store_str(&mut self, address: u16, s: &str) -> &mut Self {
+        for (i, b) in s.bytes().chain(std::iter::once(0)).enumerate() {
+            self.store(address + i as u16, b);
+        }
+        self
+    }
+
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+}